@@ -0,0 +1,764 @@
+use std::mem::size_of_val;
+use std::time::{Duration, Instant};
+
+use errors::Error;
+use secret::SecretBytes;
+use noise;
+
+use super::untrusted;
+use redact;
+use super::ring::{hkdf, hmac, aead, agreement, rand, digest};
+use super::ring::rand::SecureRandom;
+
+mod stream;
+
+pub use self::stream::{StreamSealer, StreamOpener, SealingWriter, OpeningReader, seal_stream, open_stream};
+pub use self::stream::{DEFAULT_CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_STREAM_KEY_LEN, STREAM_HEADER_LEN};
+
+/// Prefixed to every key schedule label. Any change to how session secrets
+/// are derived must bump this, so secrets from different schedules can never
+/// coincide.
+pub const KEY_SCHEDULE_VERSION: &'static [u8] = b"libcart key schedule v1";
+
+const CLIENT_TO_SERVER_SECRET: &'static [u8] = b"c2s traffic";
+const SERVER_TO_CLIENT_SECRET: &'static [u8] = b"s2c traffic";
+const EXPORTER_SECRET: &'static [u8] = b"exporter";
+const REKEY_SECRET: &'static [u8] = b"rekey";
+const TRAFFIC_KEY: &'static [u8] = b"key";
+const NEXT_TRAFFIC_SECRET: &'static [u8] = b"next traffic";
+const EXPORTED_KEY: &'static [u8] = b"export";
+const RATCHET_SECRET: &'static [u8] = b"ratchet";
+
+/// Length of the random value each peer contributes to the key schedule salt.
+pub const SESSION_RANDOM_LEN: usize = 32;
+
+const CLIENT_TO_SERVER_LABEL: &'static [u8] = b"libcart client->server";
+const SERVER_TO_CLIENT_LABEL: &'static [u8] = b"libcart server->client";
+
+pub type EphemeralKeyPair = (agreement::EphemeralPrivateKey, Vec<u8>);
+
+/// The secrets the key schedule derives for a session, one per direction and
+/// purpose.
+pub struct SessionSecrets {
+    pub client_to_server: SecretBytes,
+    pub server_to_client: SecretBytes,
+    /// Root of `EncryptionHandler::export`.
+    pub exporter: SecretBytes,
+    /// Mixed into every ratchet of the traffic secrets.
+    pub rekey: SecretBytes,
+}
+
+impl SessionSecrets {
+    /// Runs the key schedule: HKDF-Extract of `secret` with `salt`, then one
+    /// HKDF-Expand per purpose. Each expansion's info is
+    /// `KEY_SCHEDULE_VERSION || " " || label || 0x00 || context`, so every
+    /// output is bound to the schedule version, its purpose and `context`
+    /// (normally the handshake transcript hash).
+    pub fn derive(salt: &[u8], secret: &[u8], context: &[u8]) -> SessionSecrets {
+        let salt = hmac::SigningKey::new(&digest::SHA256, salt);
+        let prk = hkdf::extract(&salt, secret);
+
+        SessionSecrets {
+            client_to_server: expand_label(&prk, CLIENT_TO_SERVER_SECRET, context, digest::SHA256_OUTPUT_LEN),
+            server_to_client: expand_label(&prk, SERVER_TO_CLIENT_SECRET, context, digest::SHA256_OUTPUT_LEN),
+            exporter: expand_label(&prk, EXPORTER_SECRET, context, digest::SHA256_OUTPUT_LEN),
+            rekey: expand_label(&prk, REKEY_SECRET, context, digest::SHA256_OUTPUT_LEN),
+        }
+    }
+}
+
+/// The AEAD algorithms a session can be encrypted with. Key agreement is always
+/// X25519; the suite only selects the symmetric cipher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherSuite {
+    ChaCha20Poly1305,
+    Aes128Gcm,
+    Aes256Gcm,
+}
+
+impl CipherSuite {
+    /// Every supported suite, in the default order of preference.
+    pub fn all() -> Vec<CipherSuite> {
+        vec![CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305, CipherSuite::Aes128Gcm]
+    }
+
+    pub fn from_id(id: u8) -> Option<CipherSuite> {
+        match id {
+            1 => Some(CipherSuite::ChaCha20Poly1305),
+            2 => Some(CipherSuite::Aes128Gcm),
+            3 => Some(CipherSuite::Aes256Gcm),
+            _ => None,
+        }
+    }
+
+    /// The identifier sent on the wire during the handshake.
+    pub fn id(&self) -> u8 {
+        match *self {
+            CipherSuite::ChaCha20Poly1305 => 1,
+            CipherSuite::Aes128Gcm => 2,
+            CipherSuite::Aes256Gcm => 3,
+        }
+    }
+
+    pub fn algorithm(&self) -> &'static aead::Algorithm {
+        match *self {
+            CipherSuite::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+            CipherSuite::Aes128Gcm => &aead::AES_128_GCM,
+            CipherSuite::Aes256Gcm => &aead::AES_256_GCM,
+        }
+    }
+
+    /// Picks the first suite in `preferred` that the peer also `offered`.
+    /// Unknown identifiers in `offered` are ignored.
+    pub fn negotiate(preferred: &[CipherSuite], offered: &[u8]) -> Option<CipherSuite> {
+        preferred.iter()
+            .find(|suite| offered.contains(&suite.id()))
+            .cloned()
+    }
+}
+
+/// Which end of the connection a handler belongs to. Used to label each
+/// direction so a frame can't be reflected back to its sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    fn sending_label(&self) -> &'static [u8] {
+        match *self {
+            Role::Client => CLIENT_TO_SERVER_LABEL,
+            Role::Server => SERVER_TO_CLIENT_LABEL,
+        }
+    }
+
+    fn receiving_label(&self) -> &'static [u8] {
+        match *self {
+            Role::Client => SERVER_TO_CLIENT_LABEL,
+            Role::Server => CLIENT_TO_SERVER_LABEL,
+        }
+    }
+}
+
+/// Limits on how much a single key may be used before the sender retires it.
+/// A `None` limit is never reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    pub max_frames: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+impl RekeyPolicy {
+    pub fn never() -> RekeyPolicy {
+        RekeyPolicy {
+            max_frames: None,
+            max_bytes: None,
+            max_age: None,
+        }
+    }
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> RekeyPolicy {
+        RekeyPolicy {
+            max_frames: Some(1 << 20),
+            max_bytes: Some(1 << 30),
+            max_age: Some(Duration::from_secs(60 * 60)),
+        }
+    }
+}
+
+/// Picks the ratchet interval for a session from the one each peer asked for,
+/// where 0 means no ratchet: the ratchet runs if either peer wants it, as
+/// often as the stricter one wants.
+pub fn negotiate_ratchet_interval(ours: u32, theirs: u32) -> u32 {
+    match (ours, theirs) {
+        (0, other) | (other, 0) => other,
+        (ours, theirs) => ::std::cmp::min(ours, theirs),
+    }
+}
+
+/// Seals and opens frames for a single session.
+///
+/// Each direction keeps its own monotonically increasing sequence number, which
+/// is used as the AEAD nonce. The sender never reuses a sequence number and the
+/// receiver only accepts the exact next one it expects, so replayed, dropped or
+/// reordered frames are rejected with `Error::SequenceError`.
+///
+/// Each direction's key is derived from a traffic secret. Once the sealing key
+/// has been used past the `RekeyPolicy` limits, the sender ratchets its secret
+/// forward with HKDF, keyed by the session's rekey secret, and tells the peer
+/// to do the same; see `rekey_sealer` and `rekey_opener`.
+///
+/// With a ratchet interval set, each direction's traffic secret is also
+/// replaced by a one-way HKDF step after every that many frames, without any
+/// signalling: both peers step at the same sequence numbers. The previous
+/// secret is wiped, so a snapshot of the handler's memory can't open frames
+/// from earlier intervals; the rekey secret only leads forward from the
+/// current secret, so holding it doesn't help. Both peers must use the same
+/// interval, which is why it is negotiated in the handshake.
+///
+/// The caller-supplied frame header is authenticated as associated data along
+/// with the session identifier and a direction label, so a modified header or
+/// a frame from another session or direction fails to open.
+///
+/// A handler made by `from_noise_split` seals Noise transport messages
+/// instead; see there for how that differs.
+pub struct EncryptionHandler {
+    role: Role,
+    session_id: Vec<u8>,
+    suite: CipherSuite,
+    sealer: aead::SealingKey,
+    opener: aead::OpeningKey,
+    seal_secret: SecretBytes,
+    open_secret: SecretBytes,
+    exporter_secret: SecretBytes,
+    schedule: KeySchedule,
+    seal_seq: u64,
+    open_seq: u64,
+    policy: RekeyPolicy,
+    sealed_frames: u64,
+    sealed_bytes: u64,
+    sealed_since: Instant,
+    ratchet_interval: u64,
+}
+
+impl EncryptionHandler {
+    pub fn new(role: Role, session_id: Vec<u8>, suite: CipherSuite, secrets: SessionSecrets) -> Result<EncryptionHandler, Error> {
+        let (seal_secret, open_secret) = match role {
+            Role::Client => (secrets.client_to_server, secrets.server_to_client),
+            Role::Server => (secrets.server_to_client, secrets.client_to_server),
+        };
+        let schedule = KeySchedule::Libcart { rekey_secret: secrets.rekey };
+
+        EncryptionHandler::with_schedule(role, session_id, suite, schedule, seal_secret, open_secret, secrets.exporter)
+    }
+
+    /// Keys a handler with the two keys of a Noise `Split()`, for the
+    /// transport phase after a Noise handshake; `session_id` should be the
+    /// handshake hash.
+    ///
+    /// Frames are then sealed exactly as Noise transport messages: each split
+    /// key is used as-is, the nonce is the Noise encoding of the sequence
+    /// number, which always equals the Noise message counter, and the
+    /// associated data is empty, so the frame header is not authenticated.
+    /// Rekeying and the ratchet both apply the Noise `REKEY()` function. Only
+    /// `exporter`, which the Noise spec leaves to the application, is libcart's
+    /// own.
+    pub fn from_noise_split(role: Role, session_id: Vec<u8>, suite: CipherSuite, initiator_to_responder: SecretBytes, responder_to_initiator: SecretBytes, exporter: SecretBytes) -> Result<EncryptionHandler, Error> {
+        let (seal_secret, open_secret) = match role {
+            Role::Client => (initiator_to_responder, responder_to_initiator),
+            Role::Server => (responder_to_initiator, initiator_to_responder),
+        };
+
+        EncryptionHandler::with_schedule(role, session_id, suite, KeySchedule::Noise, seal_secret, open_secret, exporter)
+    }
+
+    fn with_schedule(role: Role, session_id: Vec<u8>, suite: CipherSuite, schedule: KeySchedule, seal_secret: SecretBytes, open_secret: SecretBytes, exporter: SecretBytes) -> Result<EncryptionHandler, Error> {
+        let sealer = aead::SealingKey::new(suite.algorithm(), &schedule.traffic_key(suite, &seal_secret))?;
+        let opener = aead::OpeningKey::new(suite.algorithm(), &schedule.traffic_key(suite, &open_secret))?;
+
+        Ok(EncryptionHandler {
+            role: role,
+            session_id: session_id,
+            suite: suite,
+            sealer: sealer,
+            opener: opener,
+            seal_secret: seal_secret,
+            open_secret: open_secret,
+            exporter_secret: exporter,
+            schedule: schedule,
+            seal_seq: 0,
+            open_seq: 0,
+            policy: RekeyPolicy::default(),
+            sealed_frames: 0,
+            sealed_bytes: 0,
+            sealed_since: Instant::now(),
+            ratchet_interval: 0,
+        })
+    }
+
+    /// Completes the key agreement. `salt` is the per-session randomness both
+    /// peers contributed. `transcript_hash` is the hash of the handshake both
+    /// peers verified; it becomes the session id and is mixed into the key
+    /// derivation, as is `psk` when the peers share one.
+    pub fn from_agreement(role: Role, suite: CipherSuite, private_key: agreement::EphemeralPrivateKey, peer_public_key: &[u8], salt: &[u8], transcript_hash: &[u8], psk: Option<&[u8]>) -> Result<EncryptionHandler, Error> {
+        let secrets = new_sym_key(private_key, peer_public_key, salt, transcript_hash, psk)?;
+        EncryptionHandler::new(role, transcript_hash.to_vec(), suite, secrets)
+    }
+
+    /// The number of bytes sealing adds to a payload.
+    pub fn tag_len(&self) -> usize {
+        self.suite.algorithm().tag_len()
+    }
+
+    pub fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+        self.policy = policy;
+    }
+
+    /// Ratchets each direction's key forward after every `frames` frames; 0
+    /// turns the ratchet off. Must be set before the first frame, to the
+    /// interval agreed with the peer.
+    pub fn set_ratchet_interval(&mut self, frames: u32) {
+        self.ratchet_interval = frames as u64;
+    }
+
+    /// Whether the sealing key has reached any of the `RekeyPolicy` limits.
+    pub fn needs_rekey(&self) -> bool {
+        let policy = &self.policy;
+
+        policy.max_frames.map_or(false, |max| self.sealed_frames >= max) ||
+            policy.max_bytes.map_or(false, |max| self.sealed_bytes >= max) ||
+            policy.max_age.map_or(false, |max| self.sealed_since.elapsed() >= max)
+    }
+
+    /// Ratchets the outgoing traffic secret and switches to the derived key.
+    /// The frame announcing the switch must already have been sealed.
+    pub fn rekey_sealer(&mut self) -> Result<(), Error> {
+        let secret = self.schedule.next_secret(self.suite, &self.seal_secret)?;
+        self.sealer = aead::SealingKey::new(self.suite.algorithm(), &self.schedule.traffic_key(self.suite, &secret))?;
+        self.seal_secret = secret;
+        self.sealed_frames = 0;
+        self.sealed_bytes = 0;
+        self.sealed_since = Instant::now();
+
+        Ok(())
+    }
+
+    /// Ratchets the incoming traffic secret, mirroring the peer's `rekey_sealer`.
+    pub fn rekey_opener(&mut self) -> Result<(), Error> {
+        let secret = self.schedule.next_secret(self.suite, &self.open_secret)?;
+        self.opener = aead::OpeningKey::new(self.suite.algorithm(), &self.schedule.traffic_key(self.suite, &secret))?;
+        self.open_secret = secret;
+
+        Ok(())
+    }
+
+    /// Seals `data` under the next outgoing sequence number, returning that
+    /// sequence number along with the ciphertext and tag. `header` is the
+    /// plaintext frame header and is authenticated but not encrypted.
+    ///
+    /// `data` is copied and sealed in one piece; payloads too large for that
+    /// should go through a `SealingWriter` keyed with `export` instead.
+    pub fn seal_data(&mut self, header: &[u8], data: &[u8]) -> Result<(u64, Vec<u8>), Error> {
+        let seq = self.seal_seq;
+        let next = match seq.checked_add(1) {
+            Some(next) => next,
+            None => return Err(Error::NonceExhausted),
+        };
+        let nonce = self.schedule.nonce(self.suite, seq);
+
+        let mut vec = data.to_vec();
+        let len = vec.len();
+        vec.resize(len + self.sealer.algorithm().tag_len(), 0);
+
+        let ad = self.associated_data(self.role.sending_label(), header);
+        aead::seal_in_place(&self.sealer, &nonce, &ad, &mut vec, self.sealer.algorithm().tag_len())?;
+        self.seal_seq = next;
+        self.sealed_frames += 1;
+        self.sealed_bytes += len as u64;
+
+        if self.ratchet_due(seq) {
+            let secret = self.schedule.ratchet_secret(self.suite, &self.seal_secret)?;
+            self.sealer = aead::SealingKey::new(self.suite.algorithm(), &self.schedule.traffic_key(self.suite, &secret))?;
+            self.seal_secret = secret;
+        }
+
+        Ok((seq, vec))
+    }
+
+    /// Opens a frame that was sealed under sequence number `seq`. Anything other
+    /// than the next expected sequence number is refused before decrypting.
+    pub fn open_data(&mut self, header: &[u8], seq: u64, mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if seq != self.open_seq {
+            return Err(Error::SequenceError(self.open_seq, seq));
+        }
+
+        let nonce = self.schedule.nonce(self.suite, seq);
+        let ad = self.associated_data(self.role.receiving_label(), header);
+        let out = match aead::open_in_place(&self.opener, &nonce, &ad, 0, &mut data) {
+            Ok(plaintext) => plaintext.to_vec(),
+            Err(_) => return Err(Error::DecryptFailed),
+        };
+        self.open_seq += 1;
+
+        if self.ratchet_due(seq) {
+            let secret = self.schedule.ratchet_secret(self.suite, &self.open_secret)?;
+            self.opener = aead::OpeningKey::new(self.suite.algorithm(), &self.schedule.traffic_key(self.suite, &secret))?;
+            self.open_secret = secret;
+        }
+
+        Ok(out)
+    }
+
+    /// Derives `len` bytes of keying material for use outside the channel.
+    /// Both peers get the same output for the same `label` and `context`, and
+    /// it reveals nothing about the traffic keys.
+    pub fn export(&self, label: &[u8], context: &[u8], len: usize) -> SecretBytes {
+        let prk = hmac::SigningKey::new(&digest::SHA256, &self.exporter_secret);
+        let mut info = Vec::with_capacity(4 + label.len() + context.len());
+        info.extend_from_slice(&[(label.len() >> 24) as u8, (label.len() >> 16) as u8, (label.len() >> 8) as u8, label.len() as u8]);
+        info.extend_from_slice(label);
+        info.extend_from_slice(context);
+
+        expand_label(&prk, EXPORTED_KEY, &info, len)
+    }
+
+    /// Whether the ratchet steps after the frame with sequence number `seq`.
+    fn ratchet_due(&self, seq: u64) -> bool {
+        self.ratchet_interval != 0 && (seq + 1) % self.ratchet_interval == 0
+    }
+
+    fn associated_data(&self, label: &[u8], header: &[u8]) -> Vec<u8> {
+        if let KeySchedule::Noise = self.schedule {
+            return Vec::new();
+        }
+
+        let mut ad = Vec::with_capacity(label.len() + self.session_id.len() + header.len());
+        ad.extend_from_slice(label);
+        ad.extend_from_slice(&self.session_id);
+        ad.extend_from_slice(header);
+
+        ad
+    }
+}
+
+/// How an `EncryptionHandler` turns its per-direction secrets into keys and
+/// nonces.
+enum KeySchedule {
+    /// libcart's own: HKDF traffic keys, `next_secret` and `ratchet_secret`
+    /// steps and big-endian sequence nonces.
+    Libcart { rekey_secret: SecretBytes },
+    /// Noise transport: the secrets are the keys, stepped with Noise `REKEY()`.
+    Noise,
+}
+
+impl KeySchedule {
+    fn traffic_key(&self, suite: CipherSuite, secret: &[u8]) -> SecretBytes {
+        match *self {
+            KeySchedule::Libcart { .. } => traffic_key(suite, secret),
+            KeySchedule::Noise => SecretBytes::from_slice(secret),
+        }
+    }
+
+    fn next_secret(&self, suite: CipherSuite, secret: &[u8]) -> Result<SecretBytes, Error> {
+        match *self {
+            KeySchedule::Libcart { ref rekey_secret } => Ok(next_secret(rekey_secret, secret)),
+            KeySchedule::Noise => noise::rekey(suite, secret),
+        }
+    }
+
+    fn ratchet_secret(&self, suite: CipherSuite, secret: &[u8]) -> Result<SecretBytes, Error> {
+        match *self {
+            KeySchedule::Libcart { .. } => Ok(ratchet_secret(secret)),
+            KeySchedule::Noise => noise::rekey(suite, secret),
+        }
+    }
+
+    fn nonce(&self, suite: CipherSuite, seq: u64) -> Vec<u8> {
+        match *self {
+            KeySchedule::Libcart { .. } => sequence_nonce(seq, suite.algorithm().nonce_len()),
+            KeySchedule::Noise => noise::nonce(suite, seq).to_vec(),
+        }
+    }
+}
+
+/// HKDF-Expand with a key schedule label; see `SessionSecrets::derive`.
+fn expand_label(prk: &hmac::SigningKey, label: &[u8], context: &[u8], len: usize) -> SecretBytes {
+    let mut info = Vec::with_capacity(KEY_SCHEDULE_VERSION.len() + label.len() + context.len() + 2);
+    info.extend_from_slice(KEY_SCHEDULE_VERSION);
+    info.push(b' ');
+    info.extend_from_slice(label);
+    info.push(0);
+    info.extend_from_slice(context);
+
+    let mut out = SecretBytes::zeroed(len);
+    hkdf::expand(prk, &info, &mut out);
+
+    out
+}
+
+fn traffic_key(suite: CipherSuite, secret: &[u8]) -> SecretBytes {
+    let prk = hmac::SigningKey::new(&digest::SHA256, secret);
+    expand_label(&prk, TRAFFIC_KEY, &[], suite.algorithm().key_len())
+}
+
+fn next_secret(rekey_secret: &[u8], secret: &[u8]) -> SecretBytes {
+    let salt = hmac::SigningKey::new(&digest::SHA256, rekey_secret);
+    let prk = hkdf::extract(&salt, secret);
+    expand_label(&prk, NEXT_TRAFFIC_SECRET, &[], digest::SHA256_OUTPUT_LEN)
+}
+
+/// One step of the per-direction ratchet. Unlike `next_secret` it depends on
+/// nothing but the current secret, so erasing that secret erases the chain.
+fn ratchet_secret(secret: &[u8]) -> SecretBytes {
+    let prk = hmac::SigningKey::new(&digest::SHA256, secret);
+    expand_label(&prk, RATCHET_SECRET, &[], digest::SHA256_OUTPUT_LEN)
+}
+
+/// Builds an AEAD nonce from a sequence number: big-endian, right-aligned and
+/// zero-padded on the left to the algorithm's nonce length.
+fn sequence_nonce(seq: u64, nonce_len: usize) -> Vec<u8> {
+    let mut nonce = vec![0u8; nonce_len];
+    for (i, byte) in nonce.iter_mut().rev().take(8).enumerate() {
+        *byte = (seq >> (i * 8)) as u8;
+    }
+
+    nonce
+}
+
+pub fn new_ephemeral_key() -> Result<EphemeralKeyPair, Error> {
+    let rng = rand::SystemRandom::new();
+    let private_key = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng).map_err(|_| Error::RandomFailure)?;
+
+    let mut public_key = [0u8; agreement::PUBLIC_KEY_MAX_LEN];
+    let public_key = &mut public_key[..private_key.public_key_len()];
+    private_key.compute_public_key(public_key)?;
+
+    Ok((private_key, public_key.to_vec()))
+}
+
+/// A fresh random value for one peer's share of the key schedule salt.
+pub fn new_session_random() -> Result<Vec<u8>, Error> {
+    let rng = rand::SystemRandom::new();
+    let mut random = vec![0u8; SESSION_RANDOM_LEN];
+    rng.fill(&mut random).map_err(|_| Error::RandomFailure)?;
+
+    Ok(random)
+}
+
+/// Runs the key schedule over the X25519 agreement with `peer_pub_key`.
+/// `salt` should be the concatenation of both peers' session randoms and
+/// `context` the handshake transcript hash.
+///
+/// A `psk` is appended to the X25519 output before extraction, so both the
+/// agreement and the pre-shared key are needed to arrive at the secrets.
+pub fn new_sym_key(private_key: agreement::EphemeralPrivateKey, peer_pub_key: &[u8], salt: &[u8], context: &[u8], psk: Option<&[u8]>) -> Result<SessionSecrets, Error> {
+    let pub_key_in = untrusted::Input::from(peer_pub_key);
+
+    let err = Error::InvalidKey("unable to agree on a key with the peer's public key".to_string());
+    agreement::agree_ephemeral(private_key, &agreement::X25519, pub_key_in, err, |key_data| {
+        let secret = SecretBytes::concat(key_data, psk.unwrap_or(&[]));
+
+        debug!("key data size: {} bits", size_of_val(key_data) * 8);
+        debug!("key data: {}", redact::bytes(key_data));
+
+        Ok(SessionSecrets::derive(salt, &secret, context))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::{digest, hkdf, hmac};
+
+    use errors::Error;
+    use super::{CipherSuite, EncryptionHandler, RekeyPolicy, Role, SessionSecrets};
+
+    fn handler(role: Role, salt: &[u8]) -> EncryptionHandler {
+        let secrets = SessionSecrets::derive(salt, b"shared secret", b"transcript");
+        EncryptionHandler::new(role, b"session".to_vec(), CipherSuite::ChaCha20Poly1305, secrets).unwrap()
+    }
+
+    fn session() -> (EncryptionHandler, EncryptionHandler) {
+        (handler(Role::Client, b"salt"), handler(Role::Server, b"salt"))
+    }
+
+    #[test]
+    fn refuses_replayed_and_reordered_frames() {
+        let (mut client, mut server) = session();
+        let (first_seq, first) = client.seal_data(b"header", b"first").unwrap();
+        let (second_seq, second) = client.seal_data(b"header", b"second").unwrap();
+        assert_eq!((first_seq, second_seq), (0, 1));
+
+        match server.open_data(b"header", second_seq, second.clone()) {
+            Err(Error::SequenceError(0, 1)) => (),
+            other => panic!("expected SequenceError, got {:?}", other),
+        }
+        assert_eq!(server.open_data(b"header", first_seq, first.clone()).unwrap(), b"first");
+        match server.open_data(b"header", first_seq, first) {
+            Err(Error::SequenceError(1, 0)) => (),
+            other => panic!("expected SequenceError, got {:?}", other),
+        }
+        assert_eq!(server.open_data(b"header", second_seq, second).unwrap(), b"second");
+    }
+
+    #[test]
+    fn failed_frames_do_not_advance_the_sequence() {
+        let (mut client, mut server) = session();
+        let (seq, frame) = client.seal_data(b"header", b"data").unwrap();
+
+        match server.open_data(b"other header", seq, frame.clone()) {
+            Err(Error::DecryptFailed) => (),
+            other => panic!("expected DecryptFailed, got {:?}", other),
+        }
+        assert_eq!(server.open_data(b"header", seq, frame).unwrap(), b"data");
+    }
+
+    /// Sends `data` from `sender` to `receiver`, rekeying both the way the
+    /// codec does once the sender's budget runs out. Returns whether it did.
+    fn send(sender: &mut EncryptionHandler, receiver: &mut EncryptionHandler, data: &[u8]) -> bool {
+        let (seq, frame) = sender.seal_data(b"", data).unwrap();
+        assert_eq!(receiver.open_data(b"", seq, frame).unwrap(), data);
+        if !sender.needs_rekey() {
+            return false;
+        }
+
+        let (seq, frame) = sender.seal_data(b"", b"").unwrap();
+        sender.rekey_sealer().unwrap();
+        assert!(receiver.open_data(b"", seq, frame).unwrap().is_empty());
+        receiver.rekey_opener().unwrap();
+
+        true
+    }
+
+    #[test]
+    fn rekeys_in_step_after_the_frame_budget() {
+        let (mut client, mut server) = session();
+        client.set_rekey_policy(RekeyPolicy { max_frames: Some(2), ..RekeyPolicy::never() });
+        server.set_rekey_policy(RekeyPolicy::never());
+
+        let rekeys: Vec<bool> = (0..5u8).map(|i| send(&mut client, &mut server, &[i])).collect();
+        assert_eq!(rekeys, vec![false, true, false, true, false]);
+
+        // only the client's direction was rekeyed
+        assert!(!send(&mut server, &mut client, b"reply"));
+    }
+
+    #[test]
+    fn rekeys_after_the_byte_budget() {
+        let (mut client, mut server) = session();
+        client.set_rekey_policy(RekeyPolicy { max_bytes: Some(100), ..RekeyPolicy::never() });
+
+        assert!(!send(&mut client, &mut server, &[0; 60]));
+        assert!(send(&mut client, &mut server, &[0; 60]));
+        assert!(!send(&mut client, &mut server, &[0; 60]));
+    }
+
+    #[test]
+    fn refuses_frames_under_a_key_the_peer_has_not_switched_to() {
+        let (mut client, mut server) = session();
+        client.rekey_sealer().unwrap();
+
+        let (seq, frame) = client.seal_data(b"", b"data").unwrap();
+        match server.open_data(b"", seq, frame) {
+            Err(Error::DecryptFailed) => (),
+            other => panic!("expected DecryptFailed, got {:?}", other),
+        }
+    }
+
+    fn expect_decrypt_failure(sender: &mut EncryptionHandler, receiver: &mut EncryptionHandler) {
+        let (seq, frame) = sender.seal_data(b"", b"data").unwrap();
+        match receiver.open_data(b"", seq, frame) {
+            Err(Error::DecryptFailed) => (),
+            other => panic!("expected DecryptFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn separates_sessions_by_salt() {
+        let mut client = handler(Role::Client, b"salt");
+        let mut server = handler(Role::Server, b"other salt");
+
+        expect_decrypt_failure(&mut client, &mut server);
+    }
+
+    #[test]
+    fn separates_sessions_by_id() {
+        let secrets = SessionSecrets::derive(b"salt", b"shared secret", b"transcript");
+        let mut client = handler(Role::Client, b"salt");
+        let mut server = EncryptionHandler::new(Role::Server, b"other session".to_vec(), CipherSuite::ChaCha20Poly1305, secrets).unwrap();
+
+        expect_decrypt_failure(&mut client, &mut server);
+    }
+
+    #[test]
+    fn separates_directions() {
+        let mut client = handler(Role::Client, b"salt");
+        let mut reflected = handler(Role::Client, b"salt");
+
+        expect_decrypt_failure(&mut client, &mut reflected);
+    }
+
+    #[test]
+    fn derives_distinct_secrets_per_label() {
+        let secrets = SessionSecrets::derive(b"salt", b"shared secret", b"transcript");
+        let all = [&secrets.client_to_server, &secrets.server_to_client, &secrets.exporter, &secrets.rekey];
+
+        for (i, a) in all.iter().enumerate() {
+            for b in &all[i + 1..] {
+                assert!(a[..] != b[..]);
+            }
+        }
+
+        let other = SessionSecrets::derive(b"salt", b"shared secret", b"other transcript");
+        assert!(secrets.client_to_server[..] != other.client_to_server[..]);
+    }
+
+    #[test]
+    fn binds_secrets_to_the_schedule_version() {
+        let secrets = SessionSecrets::derive(b"salt", b"shared secret", b"transcript");
+
+        let salt = hmac::SigningKey::new(&digest::SHA256, b"salt");
+        let prk = hkdf::extract(&salt, b"shared secret");
+        let mut expected = [0u8; digest::SHA256_OUTPUT_LEN];
+        hkdf::expand(&prk, b"libcart key schedule v1 c2s traffic\0transcript", &mut expected);
+
+        assert_eq!(&secrets.client_to_server[..], &expected[..]);
+    }
+
+    #[test]
+    fn exports_the_same_keys_on_both_sides() {
+        let (client, server) = session();
+
+        assert_eq!(&client.export(b"label", b"context", 32)[..], &server.export(b"label", b"context", 32)[..]);
+        assert!(client.export(b"label", b"context", 32)[..] != client.export(b"other label", b"context", 32)[..]);
+        assert!(client.export(b"label", b"context", 32)[..] != client.export(b"label", b"other context", 32)[..]);
+    }
+
+    #[test]
+    fn ratchets_both_directions_at_the_same_frames() {
+        let (mut client, mut server) = session();
+        client.set_ratchet_interval(3);
+        server.set_ratchet_interval(3);
+
+        let due: Vec<u64> = (0..7).filter(|&seq| client.ratchet_due(seq)).collect();
+        assert_eq!(due, vec![2, 5]);
+        assert!((0..7).all(|seq| client.ratchet_due(seq) == server.ratchet_due(seq)));
+
+        for i in 0..7u8 {
+            assert!(!send(&mut client, &mut server, &[i]));
+            assert!(!send(&mut server, &mut client, &[i]));
+        }
+        assert_eq!(&client.seal_secret[..], &server.open_secret[..]);
+        assert_eq!(&client.open_secret[..], &server.seal_secret[..]);
+    }
+
+    #[test]
+    fn never_ratchets_without_an_interval() {
+        let (client, _) = session();
+
+        assert!((0..100).all(|seq| !client.ratchet_due(seq)));
+    }
+
+    #[test]
+    fn old_chain_keys_cannot_open_later_frames() {
+        let (mut client, mut server) = session();
+        client.set_ratchet_interval(2);
+        server.set_ratchet_interval(2);
+
+        // a copy of the server taken before the ratchet steps, which still
+        // holds the first interval's key
+        let mut snapshot = handler(Role::Server, b"salt");
+        let first_secret = server.open_secret.to_vec();
+
+        for i in 0..2u8 {
+            send(&mut client, &mut server, &[i]);
+        }
+        assert!(server.open_secret[..] != first_secret[..]);
+
+        snapshot.open_seq = 2;
+        expect_decrypt_failure(&mut client, &mut snapshot);
+    }
+}
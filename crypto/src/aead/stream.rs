@@ -0,0 +1,595 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+
+use errors::Error;
+use secret::SecretBytes;
+use super::{CipherSuite, expand_label, sequence_nonce};
+use ::ring::{aead, digest, hkdf, hmac, rand};
+use ::ring::rand::SecureRandom;
+
+#[cfg(feature = "tokio")]
+use ::futures::Poll;
+#[cfg(feature = "tokio")]
+use ::tokio_io::{AsyncRead, AsyncWrite};
+
+/// Plaintext bytes per chunk unless the sender picks otherwise.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The largest chunk size an opener accepts, which bounds what a stream
+/// header can make it allocate.
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// The shortest key streams can be sealed under, in bytes.
+pub const MIN_STREAM_KEY_LEN: usize = 32;
+
+const STREAM_VERSION: u8 = 1;
+const SALT_LEN: usize = 32;
+
+/// Length of the header written before the first chunk: version, cipher
+/// suite, chunk size and salt.
+pub const STREAM_HEADER_LEN: usize = 1 + 1 + 4 + SALT_LEN;
+
+const STREAM_KEY: &'static [u8] = b"stream key";
+const LAST_CHUNK: u8 = 1;
+
+/// Seals a stream one fixed-size chunk at a time.
+///
+/// This is the STREAM construction: every chunk is sealed under its own
+/// nonce, made from a chunk counter and a flag marking the last chunk, with
+/// the stream header as associated data. Dropped, reordered or duplicated
+/// chunks fail to open because their counter doesn't match, and a stream cut
+/// short fails because no chunk carrying the flag arrives.
+///
+/// Each stream is sealed with a key derived from the caller's key and a
+/// random salt in the header, so one key can seal any number of streams. The
+/// key should be at least `MIN_STREAM_KEY_LEN` random bytes, e.g. from
+/// `EncryptionHandler::export`.
+///
+/// Every chunk but the last holds exactly `chunk_size` bytes of plaintext and
+/// the last holds fewer, possibly none, so the opener can tell the last
+/// chunk from its length without reading ahead.
+pub struct StreamSealer {
+    key: aead::SealingKey,
+    header: Vec<u8>,
+    chunk_size: usize,
+    counter: u64,
+    finished: bool,
+}
+
+impl StreamSealer {
+    pub fn new(suite: CipherSuite, key: &[u8], chunk_size: usize) -> Result<StreamSealer, Error> {
+        check_key(key)?;
+        check_chunk_size(chunk_size)?;
+
+        let rng = rand::SystemRandom::new();
+        let mut salt = [0u8; SALT_LEN];
+        rng.fill(&mut salt).map_err(|_| Error::RandomFailure)?;
+
+        let mut header = Vec::with_capacity(STREAM_HEADER_LEN);
+        header.push(STREAM_VERSION);
+        header.push(suite.id());
+        header.extend_from_slice(&[(chunk_size >> 24) as u8, (chunk_size >> 16) as u8, (chunk_size >> 8) as u8, chunk_size as u8]);
+        header.extend_from_slice(&salt);
+
+        let stream_key = stream_key(suite, key, &header);
+        debug!("sealing stream with {:?} in {} byte chunks", suite, chunk_size);
+
+        Ok(StreamSealer {
+            key: aead::SealingKey::new(suite.algorithm(), &stream_key)?,
+            header: header,
+            chunk_size: chunk_size,
+            counter: 0,
+            finished: false,
+        })
+    }
+
+    /// The header the opener needs, to be sent ahead of the first chunk.
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Whether the last chunk has been sealed.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Seals the next chunk. `chunk` must be exactly `chunk_size` bytes,
+    /// unless `last` is set, in which case it must be shorter.
+    pub fn seal_chunk(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, Error> {
+        if self.finished {
+            return Err(Error::InvalidStream("stream is already finished".to_string()));
+        }
+        if (last && chunk.len() >= self.chunk_size) || (!last && chunk.len() != self.chunk_size) {
+            return Err(Error::InvalidStream(format!("{} byte chunk doesn't fit a {} byte chunk size", chunk.len(), self.chunk_size)));
+        }
+
+        let next = match self.counter.checked_add(1) {
+            Some(next) => next,
+            None => return Err(Error::NonceExhausted),
+        };
+        let nonce = chunk_nonce(self.counter, last, self.key.algorithm().nonce_len());
+        let tag_len = self.key.algorithm().tag_len();
+
+        let mut out = Vec::with_capacity(chunk.len() + tag_len);
+        out.extend_from_slice(chunk);
+        out.resize(chunk.len() + tag_len, 0);
+        aead::seal_in_place(&self.key, &nonce, &self.header, &mut out, tag_len)?;
+
+        self.counter = next;
+        self.finished = last;
+
+        Ok(out)
+    }
+}
+
+/// Opens a stream sealed by a `StreamSealer`, one chunk at a time.
+pub struct StreamOpener {
+    key: aead::OpeningKey,
+    suite: CipherSuite,
+    header: Vec<u8>,
+    chunk_size: usize,
+    counter: u64,
+    finished: bool,
+}
+
+impl StreamOpener {
+    pub fn new(key: &[u8], header: &[u8]) -> Result<StreamOpener, Error> {
+        check_key(key)?;
+
+        if header.len() != STREAM_HEADER_LEN || header[0] != STREAM_VERSION {
+            return Err(Error::InvalidStream("unsupported stream header".to_string()));
+        }
+        let suite = match CipherSuite::from_id(header[1]) {
+            Some(suite) => suite,
+            None => return Err(Error::InvalidStream(format!("unknown cipher suite {}", header[1]))),
+        };
+        let chunk_size = ((header[2] as usize) << 24) | ((header[3] as usize) << 16) |
+            ((header[4] as usize) << 8) | (header[5] as usize);
+        check_chunk_size(chunk_size)?;
+
+        let stream_key = stream_key(suite, key, header);
+        debug!("opening stream with {:?} in {} byte chunks", suite, chunk_size);
+
+        Ok(StreamOpener {
+            key: aead::OpeningKey::new(suite.algorithm(), &stream_key)?,
+            suite: suite,
+            header: header.to_vec(),
+            chunk_size: chunk_size,
+            counter: 0,
+            finished: false,
+        })
+    }
+
+    pub fn suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// The length of every sealed chunk but the last.
+    pub fn sealed_chunk_size(&self) -> usize {
+        self.chunk_size + self.key.algorithm().tag_len()
+    }
+
+    /// Whether the last chunk has been opened.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Opens the next chunk. `last` says whether the caller reached the end
+    /// of its input while reading it.
+    pub fn open_chunk(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, Error> {
+        if self.finished {
+            return Err(Error::InvalidStream("data after the last chunk".to_string()));
+        }
+
+        let tag_len = self.key.algorithm().tag_len();
+        if last && chunk.len() < tag_len {
+            return Err(Error::TruncatedStream);
+        }
+        if (last && chunk.len() >= self.sealed_chunk_size()) || (!last && chunk.len() != self.sealed_chunk_size()) {
+            return Err(Error::InvalidStream(format!("{} byte chunk doesn't fit a {} byte chunk size", chunk.len(), self.chunk_size)));
+        }
+
+        let next = match self.counter.checked_add(1) {
+            Some(next) => next,
+            None => return Err(Error::NonceExhausted),
+        };
+        let nonce = chunk_nonce(self.counter, last, self.key.algorithm().nonce_len());
+
+        let mut data = chunk.to_vec();
+        let len = match aead::open_in_place(&self.key, &nonce, &self.header, 0, &mut data) {
+            Ok(plaintext) => plaintext.len(),
+            Err(_) => return Err(Error::InvalidStream(format!("chunk {} is corrupt, truncated or out of order", self.counter))),
+        };
+        data.truncate(len);
+
+        self.counter = next;
+        self.finished = last;
+
+        Ok(data)
+    }
+}
+
+/// Seals everything written to it onto `inner` as a stream.
+///
+/// At most one chunk of plaintext and one sealed chunk are buffered. `finish`
+/// must be called once all the data has been written; a stream that isn't
+/// finished is rejected by the opener as truncated.
+///
+/// A `WouldBlock` error from `inner` leaves the writer in a state where the
+/// call can simply be retried, so it works over non-blocking I/O.
+pub struct SealingWriter<W> {
+    inner: W,
+    sealer: StreamSealer,
+    plaintext: Vec<u8>,
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<W: Write> SealingWriter<W> {
+    pub fn new(suite: CipherSuite, key: &[u8], inner: W) -> Result<SealingWriter<W>, Error> {
+        SealingWriter::with_chunk_size(suite, key, DEFAULT_CHUNK_SIZE, inner)
+    }
+
+    pub fn with_chunk_size(suite: CipherSuite, key: &[u8], chunk_size: usize, inner: W) -> Result<SealingWriter<W>, Error> {
+        let sealer = StreamSealer::new(suite, key, chunk_size)?;
+        let header = sealer.header().to_vec();
+
+        Ok(SealingWriter {
+            inner: inner,
+            sealer: sealer,
+            plaintext: Vec::with_capacity(chunk_size),
+            pending: header,
+            written: 0,
+        })
+    }
+
+    /// Seals the buffered plaintext as the last chunk and flushes it.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        if !self.sealer.is_finished() {
+            self.pending = self.sealer.seal_chunk(&self.plaintext, true).map_err(io_error)?;
+            self.plaintext.clear();
+            self.write_pending()?;
+        }
+
+        self.inner.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        while self.written < self.pending.len() {
+            match self.inner.write(&self.pending[self.written..]) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "unable to write sealed chunk")),
+                Ok(n) => self.written += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => return Err(err),
+            }
+        }
+
+        self.pending.clear();
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for SealingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_pending()?;
+        if self.sealer.is_finished() {
+            return Err(io::Error::new(io::ErrorKind::Other, "stream is already finished"));
+        }
+
+        let n = cmp::min(buf.len(), self.sealer.chunk_size() - self.plaintext.len());
+        self.plaintext.extend_from_slice(&buf[..n]);
+        if self.plaintext.len() == self.sealer.chunk_size() {
+            self.pending = self.sealer.seal_chunk(&self.plaintext, false).map_err(io_error)?;
+            self.plaintext.clear();
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+/// Opens a stream read from `inner`, yielding its plaintext.
+///
+/// At most one sealed chunk and its plaintext are buffered. The last chunk
+/// is only recognised by `inner` running out, so the stream has to be the
+/// rest of its input: anything that follows is read as part of the last
+/// chunk and makes it fail to authenticate.
+///
+/// A stream that ends early or has been tampered with gives an `InvalidData`
+/// error wrapping the `crypto` error; plaintext already read from it must be
+/// discarded.
+///
+/// Like `SealingWriter`, a read that fails with `WouldBlock` can be retried.
+pub struct OpeningReader<R> {
+    inner: R,
+    key: Option<SecretBytes>,
+    opener: Option<StreamOpener>,
+    buf: Vec<u8>,
+    plaintext: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> OpeningReader<R> {
+    pub fn new(key: &[u8], inner: R) -> OpeningReader<R> {
+        OpeningReader {
+            inner: inner,
+            key: Some(SecretBytes::from_slice(key)),
+            opener: None,
+            buf: Vec::with_capacity(STREAM_HEADER_LEN),
+            plaintext: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads until `buf` holds `len` bytes, returning false if the input
+    /// ended first.
+    fn fill(&mut self, len: usize) -> io::Result<bool> {
+        while self.buf.len() < len {
+            let start = self.buf.len();
+            self.buf.resize(len, 0);
+            match self.inner.read(&mut self.buf[start..]) {
+                Ok(0) => {
+                    self.buf.truncate(start);
+                    return Ok(false);
+                },
+                Ok(n) => self.buf.truncate(start + n),
+                Err(err) => {
+                    self.buf.truncate(start);
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                },
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Reads and opens the next chunk, returning false after the last one.
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        if self.opener.is_none() {
+            if !self.fill(STREAM_HEADER_LEN)? {
+                return Err(io_error(Error::TruncatedStream));
+            }
+
+            let opener = match self.key.take() {
+                Some(key) => StreamOpener::new(&key, &self.buf).map_err(io_error)?,
+                None => return Err(io_error(Error::InvalidStream("stream header was rejected".to_string()))),
+            };
+            self.buf = Vec::with_capacity(opener.sealed_chunk_size());
+            self.opener = Some(opener);
+        }
+
+        let sealed_chunk_size = match self.opener {
+            Some(ref opener) if !opener.is_finished() => opener.sealed_chunk_size(),
+            _ => return Ok(false),
+        };
+        let full = self.fill(sealed_chunk_size)?;
+
+        let plaintext = match self.opener {
+            Some(ref mut opener) => opener.open_chunk(&self.buf, !full).map_err(io_error)?,
+            None => unreachable!(),
+        };
+        self.buf.clear();
+        self.plaintext = plaintext;
+        self.pos = 0;
+
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for OpeningReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+
+        while self.pos == self.plaintext.len() {
+            if !self.next_chunk()? {
+                return Ok(0);
+            }
+        }
+
+        let n = cmp::min(out.len(), self.plaintext.len() - self.pos);
+        out[..n].copy_from_slice(&self.plaintext[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R: AsyncRead> AsyncRead for OpeningReader<R> {}
+
+#[cfg(feature = "tokio")]
+impl<W: AsyncWrite> AsyncWrite for SealingWriter<W> {
+    /// Finishes the stream before shutting down the inner writer.
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.finish());
+        self.inner.shutdown()
+    }
+}
+
+/// Seals everything read from `reader` onto `writer` as a single stream,
+/// returning the number of plaintext bytes sealed.
+pub fn seal_stream<R: Read, W: Write>(suite: CipherSuite, key: &[u8], reader: &mut R, writer: W) -> Result<u64, Error> {
+    let mut sealer = SealingWriter::new(suite, key, writer)?;
+    let len = io::copy(reader, &mut sealer).map_err(from_io_error)?;
+    sealer.finish().map_err(from_io_error)?;
+
+    Ok(len)
+}
+
+/// Opens a stream read from `reader` onto `writer`, returning the number of
+/// plaintext bytes written. If this fails, whatever was written so far must
+/// be discarded.
+pub fn open_stream<R: Read, W: Write>(key: &[u8], reader: R, writer: &mut W) -> Result<u64, Error> {
+    let mut opener = OpeningReader::new(key, reader);
+    io::copy(&mut opener, writer).map_err(from_io_error)
+}
+
+fn check_key(key: &[u8]) -> Result<(), Error> {
+    if key.len() < MIN_STREAM_KEY_LEN {
+        return Err(Error::InvalidKey(format!("stream key is shorter than {} bytes", MIN_STREAM_KEY_LEN)));
+    }
+
+    Ok(())
+}
+
+fn check_chunk_size(chunk_size: usize) -> Result<(), Error> {
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(Error::InvalidStream(format!("chunk size {} is out of range", chunk_size)));
+    }
+
+    Ok(())
+}
+
+/// Derives the key for one stream, bound to the whole header.
+fn stream_key(suite: CipherSuite, key: &[u8], header: &[u8]) -> SecretBytes {
+    let salt = hmac::SigningKey::new(&digest::SHA256, &header[STREAM_HEADER_LEN - SALT_LEN..]);
+    let prk = hkdf::extract(&salt, key);
+
+    expand_label(&prk, STREAM_KEY, header, suite.algorithm().key_len())
+}
+
+/// The chunk counter, big-endian, followed by the last-chunk flag byte.
+fn chunk_nonce(counter: u64, last: bool, nonce_len: usize) -> Vec<u8> {
+    let mut nonce = sequence_nonce(counter, nonce_len - 1);
+    nonce.push(if last { LAST_CHUNK } else { 0 });
+
+    nonce
+}
+
+fn io_error(err: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Unwraps errors `io_error` wrapped, so callers of `seal_stream` and
+/// `open_stream` see the original `crypto` error.
+fn from_io_error(err: io::Error) -> Error {
+    let is_crypto = err.get_ref().map_or(false, |inner| inner.is::<Error>());
+    if !is_crypto {
+        return Error::IOError(err);
+    }
+
+    let kind = err.kind();
+    match err.into_inner().map(|inner| inner.downcast::<Error>()) {
+        Some(Ok(err)) => *err,
+        Some(Err(inner)) => Error::IOError(io::Error::new(kind, inner)),
+        None => Error::IOError(kind.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use errors::Error;
+    use super::{SealingWriter, STREAM_HEADER_LEN, open_stream};
+    use super::super::CipherSuite;
+
+    const KEY: [u8; 32] = [7; 32];
+    const CHUNK_SIZE: usize = 16;
+    // ChaCha20-Poly1305 tag
+    const SEALED_CHUNK_SIZE: usize = CHUNK_SIZE + 16;
+
+    fn seal(data: &[u8]) -> Vec<u8> {
+        let mut writer = SealingWriter::with_chunk_size(CipherSuite::ChaCha20Poly1305, &KEY, CHUNK_SIZE, Vec::new()).unwrap();
+        io::copy(&mut io::Cursor::new(data), &mut writer).unwrap();
+        writer.finish().unwrap();
+        writer.into_inner()
+    }
+
+    fn open(sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        open_stream(&KEY, io::Cursor::new(sealed), &mut out)?;
+        Ok(out)
+    }
+
+    /// The header and the sealed chunks of a stream of `chunks` full chunks,
+    /// the last of which is followed by an empty final chunk.
+    fn split(sealed: &[u8], chunks: usize) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let (header, body) = sealed.split_at(STREAM_HEADER_LEN);
+        let parts: Vec<Vec<u8>> = body.chunks(SEALED_CHUNK_SIZE).map(|chunk| chunk.to_vec()).collect();
+        assert_eq!(parts.len(), chunks + 1);
+
+        (header.to_vec(), parts)
+    }
+
+    #[test]
+    fn round_trip() {
+        for len in vec![0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            assert_eq!(open(&seal(&data)).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn truncated_at_chunk_boundary() {
+        let sealed = seal(&[1; 3 * CHUNK_SIZE]);
+        let truncated = &sealed[..STREAM_HEADER_LEN + 2 * SEALED_CHUNK_SIZE];
+
+        match open(truncated) {
+            Err(Error::TruncatedStream) => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("opened a truncated stream"),
+        }
+    }
+
+    #[test]
+    fn reordered_chunks() {
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| i as u8).collect();
+        let (header, mut chunks) = split(&seal(&data), 3);
+        chunks.swap(0, 1);
+
+        match open(&[header, chunks.concat()].concat()) {
+            Err(Error::InvalidStream(_)) => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("opened a reordered stream"),
+        }
+    }
+
+    #[test]
+    fn duplicated_chunk() {
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| i as u8).collect();
+        let (header, mut chunks) = split(&seal(&data), 3);
+        let first = chunks[0].clone();
+        chunks.insert(1, first);
+
+        match open(&[header, chunks.concat()].concat()) {
+            Err(Error::InvalidStream(_)) => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("opened a stream with a duplicated chunk"),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::prelude::*;
+
+use ::base64::decode;
+use errors::Error;
+use keys::PUBLIC_KEY_LEN;
+
+/// The set of client Ed25519 public keys a server will accept.
+///
+/// On disk this is one base64-encoded public key per line, optionally followed
+/// by whitespace and a free-form comment. Blank lines and lines starting with
+/// `#` are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct AuthorizedKeys {
+    keys: HashSet<Vec<u8>>,
+}
+
+impl AuthorizedKeys {
+    pub fn new() -> AuthorizedKeys {
+        AuthorizedKeys {
+            keys: HashSet::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<AuthorizedKeys, Error> {
+        debug!("Loading authorized keys: {}", path);
+        let mut f = File::open(path)?;
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)?;
+
+        AuthorizedKeys::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<AuthorizedKeys, Error> {
+        let mut authorized = AuthorizedKeys::new();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let encoded = line.split_whitespace().next().unwrap_or("");
+            let key = decode(encoded)?;
+            if key.len() != PUBLIC_KEY_LEN {
+                return Err(Error::InvalidKey(format!("invalid authorized key: {}", encoded)));
+            }
+
+            authorized.insert(key);
+        }
+
+        debug!("Loaded {} authorized keys", authorized.len());
+        Ok(authorized)
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>) -> bool {
+        self.keys.insert(key)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        self.keys.remove(key)
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.keys.contains(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
@@ -0,0 +1,319 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ::pem;
+use ::ring::signature::Ed25519KeyPair;
+
+use errors::Error;
+use encoding::{Writer, Reader};
+use keys::PUBLIC_KEY_LEN;
+use super::verify;
+
+const FORMAT_VERSION: u8 = 1;
+const SIGNATURE_LABEL: &'static [u8] = b"libcart certificate";
+const CERTIFICATE_TAG: &'static str = "LIBCART CERTIFICATE";
+
+/// What a certified key may be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyUsage {
+    ServerAuth,
+    ClientAuth,
+}
+
+impl KeyUsage {
+    fn id(&self) -> u8 {
+        match *self {
+            KeyUsage::ServerAuth => 1,
+            KeyUsage::ClientAuth => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<KeyUsage> {
+        match id {
+            1 => Some(KeyUsage::ServerAuth),
+            2 => Some(KeyUsage::ClientAuth),
+            _ => None,
+        }
+    }
+}
+
+/// A long-term Ed25519 key signed by an offline root key.
+///
+/// There are no intermediate issuers: a certificate is valid if the root key
+/// a peer trusts signed it directly. Times are seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    pub subject_key: Vec<u8>,
+    pub issuer_key: Vec<u8>,
+    pub not_before: u64,
+    pub not_after: u64,
+    /// Host names the key may serve. A leading `*.` matches exactly one label.
+    pub hostnames: Vec<String>,
+    pub usages: Vec<KeyUsage>,
+    pub signature: Vec<u8>,
+}
+
+impl Certificate {
+    /// Signs `subject_key` with `issuer`.
+    pub fn issue(issuer: &Ed25519KeyPair, subject_key: &[u8], not_before: u64, not_after: u64,
+                 hostnames: Vec<String>, usages: Vec<KeyUsage>) -> Result<Certificate, Error> {
+        if subject_key.len() != PUBLIC_KEY_LEN {
+            return Err(Error::InvalidCertificate("subject key has the wrong length".to_string()));
+        }
+        if not_after <= not_before {
+            return Err(Error::InvalidCertificate("validity period is empty".to_string()));
+        }
+
+        let mut cert = Certificate {
+            subject_key: subject_key.to_vec(),
+            issuer_key: issuer.public_key_bytes().to_vec(),
+            not_before: not_before,
+            not_after: not_after,
+            hostnames: hostnames,
+            usages: usages,
+            signature: Vec::new(),
+        };
+        let signed = issuer.sign(&cert.signed_bytes());
+        cert.signature = signed.as_ref().to_vec();
+
+        Ok(cert)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Certificate, Error> {
+        let mut rdr = Reader::new(bytes);
+        if rdr.get_u8()? != FORMAT_VERSION {
+            return Err(Error::InvalidCertificate("unsupported certificate version".to_string()));
+        }
+
+        let subject_key = rdr.get_bytes()?.to_vec();
+        let issuer_key = rdr.get_bytes()?.to_vec();
+        let not_before = rdr.get_u64()?;
+        let not_after = rdr.get_u64()?;
+
+        let mut hostnames = Vec::new();
+        for _ in 0..rdr.get_u32()? {
+            hostnames.push(rdr.get_str()?);
+        }
+
+        let mut usages = Vec::new();
+        for id in rdr.get_bytes()? {
+            match KeyUsage::from_id(*id) {
+                Some(usage) => usages.push(usage),
+                None => return Err(Error::InvalidCertificate(format!("unknown key usage {}", id))),
+            }
+        }
+
+        let signature = rdr.get_bytes()?.to_vec();
+        rdr.finish()?;
+
+        Ok(Certificate {
+            subject_key: subject_key,
+            issuer_key: issuer_key,
+            not_before: not_before,
+            not_after: not_after,
+            hostnames: hostnames,
+            usages: usages,
+            signature: signature,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        self.write_body(&mut w);
+        w.put_bytes(&self.signature);
+
+        w.into_vec()
+    }
+
+    pub fn load(path: &str) -> Result<Certificate, Error> {
+        let mut f = File::open(path)?;
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)?;
+
+        let p = match pem::parse(&contents) {
+            Ok(p) => p,
+            Err(_) => return Err(Error::InvalidCertificate(format!("{} is not valid PEM", path))),
+        };
+        if p.tag != CERTIFICATE_TAG {
+            return Err(Error::InvalidCertificate(format!("{} is not a certificate", path)));
+        }
+
+        Certificate::from_bytes(&p.contents)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        let p = pem::Pem {
+            tag: String::from(CERTIFICATE_TAG),
+            contents: self.to_bytes(),
+        };
+
+        let mut f = File::create(path)?;
+        f.write_all(pem::encode(&p).as_bytes())?;
+        f.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Checks that `root_key` issued this certificate, that it is valid now
+    /// and that it covers `hostname` and `usage`.
+    pub fn verify(&self, root_key: &[u8], hostname: &str, usage: KeyUsage) -> Result<(), Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+
+        self.verify_at(root_key, hostname, usage, now)
+    }
+
+    pub fn verify_at(&self, root_key: &[u8], hostname: &str, usage: KeyUsage, now: u64) -> Result<(), Error> {
+        if &self.issuer_key[..] != root_key {
+            return Err(Error::InvalidCertificate("not issued by a trusted root".to_string()));
+        }
+        if let Err(_) = verify(root_key, &self.signed_bytes(), &self.signature) {
+            return Err(Error::InvalidCertificate("bad issuer signature".to_string()));
+        }
+        if now < self.not_before {
+            return Err(Error::InvalidCertificate("certificate is not yet valid".to_string()));
+        }
+        if now >= self.not_after {
+            return Err(Error::InvalidCertificate("certificate has expired".to_string()));
+        }
+        if !self.usages.contains(&usage) {
+            return Err(Error::InvalidCertificate(format!("certificate is not valid for {:?}", usage)));
+        }
+        if !self.hostnames.iter().any(|pattern| hostname_matches(pattern, hostname)) {
+            return Err(Error::InvalidCertificate(format!("certificate is not valid for {}", hostname)));
+        }
+
+        Ok(())
+    }
+
+    fn write_body(&self, w: &mut Writer) {
+        w.put_u8(FORMAT_VERSION)
+            .put_bytes(&self.subject_key)
+            .put_bytes(&self.issuer_key)
+            .put_u64(self.not_before)
+            .put_u64(self.not_after)
+            .put_u32(self.hostnames.len() as u32);
+        for hostname in &self.hostnames {
+            w.put_str(hostname);
+        }
+
+        let usages: Vec<u8> = self.usages.iter().map(|usage| usage.id()).collect();
+        w.put_bytes(&usages);
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.put_raw(SIGNATURE_LABEL);
+        self.write_body(&mut w);
+
+        w.into_vec()
+    }
+}
+
+fn hostname_matches(pattern: &str, hostname: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let hostname = hostname.to_lowercase();
+
+    if pattern.starts_with("*.") {
+        let suffix = &pattern[1..];
+        match hostname.find('.') {
+            Some(dot) => dot > 0 && &hostname[dot..] == suffix,
+            None => false,
+        }
+    } else {
+        pattern == hostname
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::ring::rand::SystemRandom;
+    use ::ring::signature::Ed25519KeyPair;
+    use ::untrusted;
+
+    use errors::Error;
+    use super::{Certificate, KeyUsage};
+
+    const SUBJECT_KEY: [u8; 32] = [5; 32];
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8[..])).unwrap()
+    }
+
+    /// A server certificate for `hostname`, valid from 100 until 200.
+    fn issue(root: &Ed25519KeyPair, hostname: &str) -> Certificate {
+        Certificate::issue(root, &SUBJECT_KEY, 100, 200, vec![hostname.to_string()], vec![KeyUsage::ServerAuth]).unwrap()
+    }
+
+    fn assert_refused(result: Result<(), Error>, reason: &str) {
+        match result {
+            Err(Error::InvalidCertificate(ref message)) if message.contains(reason) => {},
+            Err(err) => panic!("expected \"{}\", got: {}", reason, err),
+            Ok(()) => panic!("accepted a certificate that should fail with \"{}\"", reason),
+        }
+    }
+
+    #[test]
+    fn wildcard_matches_one_label() {
+        let root = key_pair();
+        let cert = issue(&root, "*.a.com");
+        let root_key = root.public_key_bytes();
+
+        cert.verify_at(root_key, "x.a.com", KeyUsage::ServerAuth, 150).unwrap();
+        cert.verify_at(root_key, "X.A.com", KeyUsage::ServerAuth, 150).unwrap();
+        assert_refused(cert.verify_at(root_key, "a.com", KeyUsage::ServerAuth, 150), "not valid for a.com");
+        assert_refused(cert.verify_at(root_key, "x.y.a.com", KeyUsage::ServerAuth, 150), "not valid for x.y.a.com");
+        assert_refused(cert.verify_at(root_key, ".a.com", KeyUsage::ServerAuth, 150), "not valid for .a.com");
+    }
+
+    #[test]
+    fn checks_validity_period_boundaries() {
+        let root = key_pair();
+        let cert = issue(&root, "a.com");
+        let root_key = root.public_key_bytes();
+
+        assert_refused(cert.verify_at(root_key, "a.com", KeyUsage::ServerAuth, 99), "not yet valid");
+        cert.verify_at(root_key, "a.com", KeyUsage::ServerAuth, 100).unwrap();
+        cert.verify_at(root_key, "a.com", KeyUsage::ServerAuth, 199).unwrap();
+        assert_refused(cert.verify_at(root_key, "a.com", KeyUsage::ServerAuth, 200), "expired");
+    }
+
+    #[test]
+    fn refuses_other_usages() {
+        let root = key_pair();
+        let cert = issue(&root, "a.com");
+
+        assert_refused(cert.verify_at(root.public_key_bytes(), "a.com", KeyUsage::ClientAuth, 150), "not valid for ClientAuth");
+    }
+
+    #[test]
+    fn refuses_other_issuers() {
+        let cert = issue(&key_pair(), "a.com");
+
+        assert_refused(cert.verify_at(key_pair().public_key_bytes(), "a.com", KeyUsage::ServerAuth, 150), "trusted root");
+    }
+
+    #[test]
+    fn refuses_tampered_certificates() {
+        let root = key_pair();
+        let root_key = root.public_key_bytes();
+
+        let mut cert = issue(&root, "a.com");
+        cert.hostnames.push("evil.com".to_string());
+        assert_refused(cert.verify_at(root_key, "evil.com", KeyUsage::ServerAuth, 150), "bad issuer signature");
+
+        let mut cert = issue(&root, "a.com");
+        cert.signature[0] ^= 1;
+        assert_refused(cert.verify_at(root_key, "a.com", KeyUsage::ServerAuth, 150), "bad issuer signature");
+
+        // and the encoding round trips without losing anything signed
+        let cert = issue(&root, "a.com");
+        let decoded = Certificate::from_bytes(&cert.to_bytes()).unwrap();
+        assert_eq!(decoded, cert);
+        decoded.verify_at(root_key, "a.com", KeyUsage::ServerAuth, 150).unwrap();
+    }
+}
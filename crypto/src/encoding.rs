@@ -0,0 +1,127 @@
+//! Minimal big-endian, length-prefixed binary encoding shared by the
+//! certificate, revocation list and other on-disk or on-wire formats.
+
+use errors::Error;
+
+#[derive(Debug, Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer { buf: Vec::new() }
+    }
+
+    pub fn put_u8(&mut self, val: u8) -> &mut Writer {
+        self.buf.push(val);
+        self
+    }
+
+    pub fn put_u32(&mut self, val: u32) -> &mut Writer {
+        for shift in [24, 16, 8, 0].iter() {
+            self.buf.push((val >> *shift) as u8);
+        }
+        self
+    }
+
+    pub fn put_u64(&mut self, val: u64) -> &mut Writer {
+        self.put_u32((val >> 32) as u32).put_u32(val as u32)
+    }
+
+    /// Writes `data` prefixed with its u32 length.
+    pub fn put_bytes(&mut self, data: &[u8]) -> &mut Writer {
+        self.put_u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    /// Writes `data` as-is, without a length prefix.
+    pub fn put_raw(&mut self, data: &[u8]) -> &mut Writer {
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    pub fn put_str(&mut self, val: &str) -> &mut Writer {
+        self.put_bytes(val.as_bytes())
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads what `Writer` wrote. Every method fails with `Error::DecodeError`
+/// rather than panicking when the input runs out.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf: buf, pos: 0 }
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.take(4)?;
+        Ok(bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u32))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, Error> {
+        let high = self.get_u32()? as u64;
+        let low = self.get_u32()? as u64;
+        Ok((high << 32) | low)
+    }
+
+    pub fn get_bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.get_u32()? as usize;
+        self.take(len)
+    }
+
+    /// Reads exactly `len` bytes with no length prefix.
+    pub fn get_raw(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        self.take(len)
+    }
+
+    pub fn get_str(&mut self) -> Result<String, Error> {
+        match String::from_utf8(self.get_bytes()?.to_vec()) {
+            Ok(val) => Ok(val),
+            Err(_) => Err(Error::DecodeError("invalid UTF-8 string".to_string())),
+        }
+    }
+
+    /// Everything not yet read.
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        rest
+    }
+
+    /// Fails unless the whole input has been consumed.
+    pub fn finish(&self) -> Result<(), Error> {
+        if self.pos == self.buf.len() {
+            Ok(())
+        } else {
+            Err(Error::DecodeError("trailing data".to_string()))
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() - self.pos < len {
+            return Err(Error::DecodeError("unexpected end of input".to_string()));
+        }
+
+        let out = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(out)
+    }
+}
@@ -3,20 +3,105 @@ use std::io;
 use std::error;
 use ::base64;
 use ::ring::error::Unspecified;
+use aead::CipherSuite;
+use key_file::KeyKind;
 
 #[derive(Debug)]
 pub enum Error {
     CryptoError(String),
+    BadSignature,
+    InvalidKey(String),
+    RandomFailure,
+    DecryptFailed,
     IOError(io::Error),
     Base64DecodeError(base64::DecodeError),
+    SequenceError(u64, u64),
+    NonceExhausted,
+    WrongPassphrase,
+    PassphraseUnavailable(String),
+    InvalidKeyFile(String),
+    InsecureKeyPermissions(String, u32),
+    KeyWriteFailed(String, io::Error),
+    UnexpectedKeyKind(KeyKind, KeyKind),
+    /// A key store had no key to load; holds the store's description.
+    KeyNotFound(String),
+    /// A private key doesn't belong to the public key it was checked against.
+    KeyMismatch(String, String),
+    KeyDerivationFailed,
+    HostKeyMismatch(String),
+    UnknownHost(String),
+    DecodeError(String),
+    InvalidCertificate(String),
+    InvalidRevocationList(String),
+    KeyRevoked(String),
+    UnknownPsk(String),
+    PskMismatch(String),
+    InvalidStream(String),
+    TruncatedStream,
+    InvalidTicket(String),
+    TicketExpired,
+    TicketReused,
+    PairingExpired,
+    PairingAttemptsExhausted,
+    PairingFailed,
+    /// The cipher suite can't be used with this kind of handshake.
+    UnsupportedCipherSuite(CipherSuite),
+    /// A key the handshake pattern needs wasn't supplied or received.
+    MissingStaticKey(String),
+    HandshakeOutOfTurn,
+    HandshakeIncomplete,
+    TruncatedHandshake,
+    /// A thread panicked while holding the named lock.
+    LockPoisoned(&'static str),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::CryptoError(ref err) => write!(f, "Crypto Error: {}", err),
+            Error::BadSignature => write!(f, "Signature Error: signature does not verify"),
+            Error::InvalidKey(ref err) => write!(f, "Key Error: {}", err),
+            Error::RandomFailure => write!(f, "Random Error: unable to read from the system random number generator"),
+            Error::DecryptFailed => write!(f, "Decrypt Error: ciphertext failed to authenticate"),
             Error::IOError(ref err) => write!(f, "IO Error: {}", err),
             Error::Base64DecodeError(ref err) => write!(f, "Base64 Error: {}", err),
+            Error::SequenceError(expected, got) =>
+                write!(f, "Sequence Error: expected frame {}, got {}", expected, got),
+            Error::NonceExhausted => write!(f, "Nonce Error: sequence numbers exhausted"),
+            Error::WrongPassphrase => write!(f, "Passphrase Error: wrong passphrase for encrypted key"),
+            Error::PassphraseUnavailable(ref err) => write!(f, "Passphrase Error: {}", err),
+            Error::InvalidKeyFile(ref err) => write!(f, "Key File Error: {}", err),
+            Error::InsecureKeyPermissions(ref path, mode) =>
+                write!(f, "Key File Error: {} is accessible by other users (mode {:o}), run `chmod 600` on it", path, mode),
+            Error::KeyWriteFailed(ref path, ref err) => write!(f, "Key File Error: unable to write {}: {}", path, err),
+            Error::UnexpectedKeyKind(expected, found) =>
+                write!(f, "Key File Error: expected {}, found {}", expected, found),
+            Error::KeyNotFound(ref store) => write!(f, "Key Store Error: no key in {}", store),
+            Error::KeyMismatch(ref private, ref public) => write!(f, "Key Error: {} does not match {}", private, public),
+            Error::KeyDerivationFailed => write!(f, "Passphrase Error: unable to derive a key from the passphrase"),
+            Error::HostKeyMismatch(ref host) =>
+                write!(f, "Host Key Error: key for {} does not match known hosts, possible impersonation", host),
+            Error::UnknownHost(ref host) => write!(f, "Host Key Error: {} is not in known hosts", host),
+            Error::DecodeError(ref err) => write!(f, "Decode Error: {}", err),
+            Error::InvalidCertificate(ref err) => write!(f, "Certificate Error: {}", err),
+            Error::InvalidRevocationList(ref err) => write!(f, "Revocation List Error: {}", err),
+            Error::KeyRevoked(ref fp) => write!(f, "Revoked Key: {} has been revoked", fp),
+            Error::UnknownPsk(ref identity) => write!(f, "PSK Error: unknown identity {}", identity),
+            Error::PskMismatch(ref identity) => write!(f, "PSK Error: peer does not hold the key for {}", identity),
+            Error::InvalidStream(ref err) => write!(f, "Stream Error: {}", err),
+            Error::TruncatedStream => write!(f, "Stream Error: stream ended before its last chunk"),
+            Error::InvalidTicket(ref err) => write!(f, "Ticket Error: {}", err),
+            Error::TicketExpired => write!(f, "Ticket Error: session ticket has expired"),
+            Error::TicketReused => write!(f, "Ticket Error: session ticket was already redeemed"),
+            Error::PairingExpired => write!(f, "Pairing Error: pairing code has expired or was already used"),
+            Error::PairingAttemptsExhausted => write!(f, "Pairing Error: too many attempts with this pairing code"),
+            Error::PairingFailed => write!(f, "Pairing Error: peer did not use the same pairing code"),
+            Error::UnsupportedCipherSuite(suite) => write!(f, "Handshake Error: {:?} is not supported by this handshake", suite),
+            Error::MissingStaticKey(ref key) => write!(f, "Handshake Error: missing {}", key),
+            Error::HandshakeOutOfTurn => write!(f, "Handshake Error: handshake message sent or received out of turn"),
+            Error::HandshakeIncomplete => write!(f, "Handshake Error: handshake is not finished"),
+            Error::TruncatedHandshake => write!(f, "Handshake Error: handshake message is truncated"),
+            Error::LockPoisoned(name) => write!(f, "Internal Error: {} lock poisoned by a panic", name),
         }
     }
 }
@@ -25,16 +110,90 @@ impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
             Error::CryptoError(ref err) => &err,
+            Error::BadSignature => "signature does not verify",
+            Error::InvalidKey(ref err) => &err,
+            Error::RandomFailure => "unable to read from the system random number generator",
+            Error::DecryptFailed => "ciphertext failed to authenticate",
             Error::IOError(ref err) => err.description(),
             Error::Base64DecodeError(ref err) => err.description(),
+            Error::SequenceError(..) => "replayed, dropped or out-of-order frame",
+            Error::NonceExhausted => "sequence numbers exhausted",
+            Error::WrongPassphrase => "wrong passphrase for encrypted key",
+            Error::PassphraseUnavailable(ref err) => &err,
+            Error::InvalidKeyFile(ref err) => &err,
+            Error::InsecureKeyPermissions(..) => "private key file is accessible by other users",
+            Error::KeyWriteFailed(_, ref err) => err.description(),
+            Error::UnexpectedKeyKind(..) => "unexpected kind of key",
+            Error::KeyNotFound(_) => "no key in key store",
+            Error::KeyMismatch(..) => "private key does not match public key",
+            Error::KeyDerivationFailed => "unable to derive a key from the passphrase",
+            Error::HostKeyMismatch(_) => "server key does not match known hosts",
+            Error::UnknownHost(_) => "server is not in known hosts",
+            Error::DecodeError(ref err) => &err,
+            Error::InvalidCertificate(ref err) => &err,
+            Error::InvalidRevocationList(ref err) => &err,
+            Error::KeyRevoked(_) => "key has been revoked",
+            Error::UnknownPsk(_) => "unknown pre-shared key identity",
+            Error::PskMismatch(_) => "pre-shared key does not match",
+            Error::InvalidStream(ref err) => &err,
+            Error::TruncatedStream => "stream ended before its last chunk",
+            Error::InvalidTicket(ref err) => &err,
+            Error::TicketExpired => "session ticket has expired",
+            Error::TicketReused => "session ticket was already redeemed",
+            Error::PairingExpired => "pairing code has expired or was already used",
+            Error::PairingAttemptsExhausted => "too many attempts with this pairing code",
+            Error::PairingFailed => "peer did not use the same pairing code",
+            Error::UnsupportedCipherSuite(_) => "cipher suite is not supported by this handshake",
+            Error::MissingStaticKey(_) => "missing static key",
+            Error::HandshakeOutOfTurn => "handshake message sent or received out of turn",
+            Error::HandshakeIncomplete => "handshake is not finished",
+            Error::TruncatedHandshake => "handshake message is truncated",
+            Error::LockPoisoned(_) => "lock poisoned by a panic",
         }
     }
 
     fn cause(&self) -> Option<&error::Error> {
         match *self {
             Error::CryptoError(_) => None,
+            Error::BadSignature => None,
+            Error::InvalidKey(_) => None,
+            Error::RandomFailure => None,
+            Error::DecryptFailed => None,
             Error::IOError(ref err) => Some(err),
             Error::Base64DecodeError(ref err) => Some(err),
+            Error::SequenceError(..) => None,
+            Error::NonceExhausted => None,
+            Error::WrongPassphrase => None,
+            Error::PassphraseUnavailable(_) => None,
+            Error::InvalidKeyFile(_) => None,
+            Error::InsecureKeyPermissions(..) => None,
+            Error::KeyWriteFailed(_, ref err) => Some(err),
+            Error::UnexpectedKeyKind(..) => None,
+            Error::KeyNotFound(_) => None,
+            Error::KeyMismatch(..) => None,
+            Error::KeyDerivationFailed => None,
+            Error::HostKeyMismatch(_) => None,
+            Error::UnknownHost(_) => None,
+            Error::DecodeError(_) => None,
+            Error::InvalidCertificate(_) => None,
+            Error::InvalidRevocationList(_) => None,
+            Error::KeyRevoked(_) => None,
+            Error::UnknownPsk(_) => None,
+            Error::PskMismatch(_) => None,
+            Error::InvalidStream(_) => None,
+            Error::TruncatedStream => None,
+            Error::InvalidTicket(_) => None,
+            Error::TicketExpired => None,
+            Error::TicketReused => None,
+            Error::PairingExpired => None,
+            Error::PairingAttemptsExhausted => None,
+            Error::PairingFailed => None,
+            Error::UnsupportedCipherSuite(_) => None,
+            Error::MissingStaticKey(_) => None,
+            Error::HandshakeOutOfTurn => None,
+            Error::HandshakeIncomplete => None,
+            Error::TruncatedHandshake => None,
+            Error::LockPoisoned(_) => None,
         }
     }
 }
@@ -0,0 +1,467 @@
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ::pem;
+use ::base64::{encode, decode};
+
+use errors::Error;
+use keys::{PUBLIC_KEY_LEN, PKCS8_LEN};
+use secret::{SecretBytes, zeroize};
+
+/// Set this environment variable to load private keys that other users can
+/// read, as if every load passed `allow_insecure` to `KeyFile::load_with`.
+/// Meant for filesystems that don't support Unix permissions.
+pub const ALLOW_INSECURE_PERMISSIONS_VAR: &'static str = "LIBCART_ALLOW_INSECURE_KEY_PERMISSIONS";
+
+/// First line of every key file in the current format.
+const MAGIC: &'static str = "libcart-key-v1";
+
+const PRIVATE_KEY_TAG: &'static str = "PRIVATE KEY";
+const ENCRYPTED_PRIVATE_KEY_TAG: &'static str = "ENCRYPTED PRIVATE KEY";
+const PUBLIC_KEY_TAG: &'static str = "PUBLIC KEY";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyKind {
+    /// A PKCS#8 Ed25519 private key.
+    PrivateKey,
+    /// A PKCS#8 Ed25519 private key sealed by `passphrase::seal_private_key`.
+    EncryptedPrivateKey,
+    /// A raw 32-byte Ed25519 public key.
+    PublicKey,
+}
+
+impl KeyKind {
+    fn name(&self) -> &'static str {
+        match *self {
+            KeyKind::PrivateKey => "private",
+            KeyKind::EncryptedPrivateKey => "encrypted-private",
+            KeyKind::PublicKey => "public",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<KeyKind> {
+        match name {
+            "private" => Some(KeyKind::PrivateKey),
+            "encrypted-private" => Some(KeyKind::EncryptedPrivateKey),
+            "public" => Some(KeyKind::PublicKey),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for KeyKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} key", self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    Ed25519,
+}
+
+impl KeyAlgorithm {
+    fn name(&self) -> &'static str {
+        match *self {
+            KeyAlgorithm::Ed25519 => "ed25519",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<KeyAlgorithm> {
+        match name {
+            "ed25519" => Some(KeyAlgorithm::Ed25519),
+            _ => None,
+        }
+    }
+}
+
+/// The encodings a `KeyFile` can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// The `libcart-key-v1` format, the only one that keeps the metadata.
+    Current,
+    /// Legacy PEM.
+    Pem,
+    /// Legacy bare base64. Encrypted keys can't be written this way, since
+    /// the kind of key is inferred from the length.
+    Base64,
+}
+
+impl KeyFormat {
+    pub fn from_name(name: &str) -> Option<KeyFormat> {
+        match name {
+            "current" => Some(KeyFormat::Current),
+            "pem" => Some(KeyFormat::Pem),
+            "base64" => Some(KeyFormat::Base64),
+            _ => None,
+        }
+    }
+}
+
+/// A key together with the metadata needed to use it.
+///
+/// Files are written as a `libcart-key-v1` line followed by `field: value`
+/// lines:
+///
+/// ```text
+/// libcart-key-v1
+/// kind: private
+/// algorithm: ed25519
+/// created: 1508371200
+/// comment: build server
+/// data: MFMCAQEwBQYDK2Vw...
+/// ```
+///
+/// Unknown fields are ignored. `parse` also accepts the legacy PEM files
+/// written by `create_signing_keypair` and the bare base64 files written by
+/// `load_or_create_key`; those have no creation time or comment.
+#[derive(Debug, Clone)]
+pub struct KeyFile {
+    pub kind: KeyKind,
+    pub algorithm: KeyAlgorithm,
+    /// Seconds since the Unix epoch, or 0 if unknown.
+    pub created: u64,
+    pub comment: String,
+    /// Wiped on drop, since for private keys this is the key itself.
+    pub data: SecretBytes,
+}
+
+impl KeyFile {
+    /// A new Ed25519 key file stamped with the current time.
+    pub fn new(kind: KeyKind, data: Vec<u8>, comment: &str) -> KeyFile {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+
+        KeyFile {
+            kind: kind,
+            algorithm: KeyAlgorithm::Ed25519,
+            created: created,
+            comment: comment.replace('\n', " "),
+            data: SecretBytes::new(data),
+        }
+    }
+
+    /// Loads a key file. Private keys whose file is readable or writable by
+    /// group or others are refused with `Error::InsecureKeyPermissions`,
+    /// unless `ALLOW_INSECURE_PERMISSIONS_VAR` is set.
+    pub fn load(path: &str) -> Result<KeyFile, Error> {
+        KeyFile::load_with(path, false)
+    }
+
+    /// Like `load`, but with `allow_insecure` private keys are loaded whatever
+    /// their permissions, with a warning.
+    pub fn load_with(path: &str, allow_insecure: bool) -> Result<KeyFile, Error> {
+        let mut f = File::open(path)?;
+        let mut buf = Vec::new();
+        f.read_to_end(&mut buf)?;
+
+        let key = KeyFile::parse(&buf);
+        zeroize(&mut buf);
+
+        let key = key?;
+        if key.kind != KeyKind::PublicKey {
+            check_permissions(path, &f, allow_insecure)?;
+        }
+
+        Ok(key)
+    }
+
+    /// Parses any supported key file format, detecting which one it is.
+    pub fn parse(contents: &[u8]) -> Result<KeyFile, Error> {
+        let text = match ::std::str::from_utf8(contents) {
+            Ok(text) => text.trim(),
+            Err(_) => return Err(Error::InvalidKeyFile("key file is not valid UTF-8".to_string())),
+        };
+
+        let key = if text.starts_with(MAGIC) {
+            parse_current(text)?
+        } else if text.starts_with("-----BEGIN") {
+            parse_pem(text)?
+        } else {
+            parse_base64(text)?
+        };
+
+        key.validate()?;
+        Ok(key)
+    }
+
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        out.push_str(MAGIC);
+        out.push('\n');
+        out.push_str(&format!("kind: {}\n", self.kind.name()));
+        out.push_str(&format!("algorithm: {}\n", self.algorithm.name()));
+        out.push_str(&format!("created: {}\n", self.created));
+        if !self.comment.is_empty() {
+            out.push_str(&format!("comment: {}\n", self.comment));
+        }
+        out.push_str(&format!("data: {}\n", encode(&self.data)));
+
+        out
+    }
+
+    /// Encodes the key in `format`. The legacy formats drop the creation
+    /// time and comment.
+    pub fn encode_as(&self, format: KeyFormat) -> Result<String, Error> {
+        match format {
+            KeyFormat::Current => Ok(self.encode()),
+            KeyFormat::Pem => {
+                let tag = match self.kind {
+                    KeyKind::PrivateKey => PRIVATE_KEY_TAG,
+                    KeyKind::EncryptedPrivateKey => ENCRYPTED_PRIVATE_KEY_TAG,
+                    KeyKind::PublicKey => PUBLIC_KEY_TAG,
+                };
+                let mut p = pem::Pem {
+                    tag: tag.to_string(),
+                    contents: self.data.to_vec(),
+                };
+                let encoded = pem::encode(&p);
+                zeroize(&mut p.contents);
+
+                Ok(encoded)
+            },
+            KeyFormat::Base64 => match self.kind {
+                KeyKind::EncryptedPrivateKey =>
+                    Err(Error::InvalidKeyFile("encrypted keys have no legacy base64 encoding".to_string())),
+                _ => Ok(encode(&self.data)),
+            },
+        }
+    }
+
+    /// Returns an error unless this is a key of the given kind.
+    pub fn expect_kind(&self, kind: KeyKind) -> Result<(), Error> {
+        if self.kind == kind {
+            Ok(())
+        } else {
+            Err(Error::UnexpectedKeyKind(kind, self.kind))
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let valid = match self.kind {
+            KeyKind::PrivateKey => self.data.len() == PKCS8_LEN,
+            KeyKind::PublicKey => self.data.len() == PUBLIC_KEY_LEN,
+            KeyKind::EncryptedPrivateKey => !self.data.is_empty(),
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(Error::InvalidKeyFile(format!("{} has the wrong length ({} bytes)", self.kind, self.data.len())))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &str, f: &File, allow_insecure: bool) -> Result<(), Error> {
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = f.metadata()?.permissions().mode() & 0o777;
+    if mode & 0o077 == 0 {
+        return Ok(());
+    }
+
+    if allow_insecure || env::var_os(ALLOW_INSECURE_PERMISSIONS_VAR).is_some() {
+        warn!("loading {} despite insecure permissions {:o}", path, mode);
+        return Ok(());
+    }
+
+    Err(Error::InsecureKeyPermissions(path.to_string(), mode))
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_: &str, _: &File, _: bool) -> Result<(), Error> {
+    Ok(())
+}
+
+fn parse_current(text: &str) -> Result<KeyFile, Error> {
+    let mut kind = None;
+    let mut algorithm = None;
+    let mut created = 0;
+    let mut comment = String::new();
+    let mut data = None;
+
+    for line in text.lines().skip(1) {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = match parts.next() {
+            Some(value) => value.trim(),
+            None => return Err(Error::InvalidKeyFile(format!("malformed line: {}", line))),
+        };
+
+        match name {
+            "kind" => kind = KeyKind::from_name(value),
+            "algorithm" => algorithm = KeyAlgorithm::from_name(value),
+            "created" => created = match value.parse() {
+                Ok(created) => created,
+                Err(_) => return Err(Error::InvalidKeyFile(format!("invalid creation time: {}", value))),
+            },
+            "comment" => comment = value.to_string(),
+            "data" => data = Some(decode(value)?),
+            _ => debug!("ignoring unknown key file field: {}", name),
+        }
+    }
+
+    let kind = match kind {
+        Some(kind) => kind,
+        None => return Err(Error::InvalidKeyFile("missing or unknown key kind".to_string())),
+    };
+    let algorithm = match algorithm {
+        Some(algorithm) => algorithm,
+        None => return Err(Error::InvalidKeyFile("missing or unsupported algorithm".to_string())),
+    };
+    let data = match data {
+        Some(data) => data,
+        None => return Err(Error::InvalidKeyFile("missing key data".to_string())),
+    };
+
+    Ok(KeyFile {
+        kind: kind,
+        algorithm: algorithm,
+        created: created,
+        comment: comment,
+        data: SecretBytes::new(data),
+    })
+}
+
+fn parse_pem(text: &str) -> Result<KeyFile, Error> {
+    let p = match pem::parse(text) {
+        Ok(p) => p,
+        Err(_) => return Err(Error::InvalidKeyFile("malformed PEM".to_string())),
+    };
+
+    let kind = match &p.tag[..] {
+        PRIVATE_KEY_TAG => KeyKind::PrivateKey,
+        ENCRYPTED_PRIVATE_KEY_TAG => KeyKind::EncryptedPrivateKey,
+        PUBLIC_KEY_TAG => KeyKind::PublicKey,
+        tag => return Err(Error::InvalidKeyFile(format!("unsupported PEM type: {}", tag))),
+    };
+
+    Ok(legacy_key(kind, p.contents))
+}
+
+fn parse_base64(text: &str) -> Result<KeyFile, Error> {
+    let data = decode(text)?;
+    let kind = match data.len() {
+        PKCS8_LEN => KeyKind::PrivateKey,
+        PUBLIC_KEY_LEN => KeyKind::PublicKey,
+        len => return Err(Error::InvalidKeyFile(format!("unrecognized {} byte key", len))),
+    };
+
+    Ok(legacy_key(kind, data))
+}
+
+fn legacy_key(kind: KeyKind, data: Vec<u8>) -> KeyFile {
+    KeyFile {
+        kind: kind,
+        algorithm: KeyAlgorithm::Ed25519,
+        created: 0,
+        comment: String::new(),
+        data: SecretBytes::new(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::process;
+
+    use errors::Error;
+    use keys::{open_unencrypted_key_pair, PKCS8_LEN, PUBLIC_KEY_LEN};
+    use super::{KeyFile, KeyFormat, KeyKind};
+
+    const PUBLIC_KEY_BASE64: &'static str = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+
+    fn temp_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("libcart-key-file-{}-{}", process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_exposed_private_keys() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path("exposed.key");
+        let key = KeyFile::new(KeyKind::PrivateKey, vec![1; PKCS8_LEN], "");
+        File::create(&path).unwrap().write_all(key.encode().as_bytes()).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let result = KeyFile::load(&path);
+        let allowed = KeyFile::load_with(&path, true);
+        fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(Error::InsecureKeyPermissions(_, 0o644)) => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("loaded a private key other users can read"),
+        }
+        assert_eq!(allowed.unwrap().kind, KeyKind::PrivateKey);
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        for &kind in &[KeyKind::PrivateKey, KeyKind::EncryptedPrivateKey, KeyKind::PublicKey] {
+            let len = if kind == KeyKind::PublicKey { PUBLIC_KEY_LEN } else { PKCS8_LEN };
+            let key = KeyFile::new(kind, vec![7; len], "build server");
+
+            for &format in &[KeyFormat::Current, KeyFormat::Pem, KeyFormat::Base64] {
+                let encoded = match key.encode_as(format) {
+                    Ok(encoded) => encoded,
+                    Err(Error::InvalidKeyFile(_)) if kind == KeyKind::EncryptedPrivateKey && format == KeyFormat::Base64 => continue,
+                    Err(err) => panic!("unexpected error encoding {} as {:?}: {}", kind, format, err),
+                };
+                let parsed = KeyFile::parse(encoded.as_bytes()).unwrap();
+
+                assert_eq!(parsed.kind, kind);
+                assert_eq!(&parsed.data[..], &key.data[..]);
+                if format == KeyFormat::Current {
+                    assert_eq!(parsed.created, key.created);
+                    assert_eq!(parsed.comment, "build server");
+                } else {
+                    assert_eq!(parsed.created, 0);
+                    assert_eq!(parsed.comment, "");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn detects_legacy_formats() {
+        let key = KeyFile::parse(PUBLIC_KEY_BASE64.as_bytes()).unwrap();
+        assert_eq!(key.kind, KeyKind::PublicKey);
+        assert_eq!(&key.data[..], &[7; PUBLIC_KEY_LEN][..]);
+
+        let pem = format!("-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n", PUBLIC_KEY_BASE64);
+        let key = KeyFile::parse(pem.as_bytes()).unwrap();
+        assert_eq!(key.kind, KeyKind::PublicKey);
+        assert_eq!(&key.data[..], &[7; PUBLIC_KEY_LEN][..]);
+
+        let private = KeyFile::new(KeyKind::PrivateKey, vec![1; PKCS8_LEN], "");
+        let key = KeyFile::parse(private.encode_as(KeyFormat::Base64).unwrap().as_bytes()).unwrap();
+        assert_eq!(key.kind, KeyKind::PrivateKey);
+    }
+
+    #[test]
+    fn refuses_public_key_as_private() {
+        let key = KeyFile::parse(PUBLIC_KEY_BASE64.as_bytes()).unwrap();
+
+        match open_unencrypted_key_pair(&key) {
+            Err(Error::UnexpectedKeyKind(KeyKind::PrivateKey, KeyKind::PublicKey)) => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("used a public key as a private key"),
+        }
+    }
+}
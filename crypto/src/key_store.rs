@@ -0,0 +1,281 @@
+use std::env;
+use std::io;
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
+use std::io::prelude::*;
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::io::{RawFd, FromRawFd};
+
+use ::ring::signature::Ed25519KeyPair;
+
+use errors::Error;
+use key_file::KeyFile;
+use keys::{load_encrypted_key, load_or_create_encrypted_key, load_key, load_or_create_key, open_key_pair, open_unencrypted_key_pair};
+use passphrase::PassphraseSource;
+use secret::zeroize;
+
+/// The errno for a closed or invalid file descriptor, which is the same on
+/// Linux, macOS and the BSDs.
+#[cfg(unix)]
+const EBADF: i32 = 9;
+
+/// Somewhere a long-term Ed25519 identity key can be loaded from.
+///
+/// Keys may be in any format `KeyFile::parse` accepts. Encrypted keys need a
+/// passphrase source; without one they fail to load with
+/// `Error::PassphraseUnavailable`. A store that has no key at all, such as a
+/// missing file or an unset variable, fails with `Error::KeyNotFound`.
+pub trait KeyStore {
+    fn load(&self) -> Result<Ed25519KeyPair, Error>;
+
+    /// Where the key comes from, for logs. Never includes key material.
+    fn describe(&self) -> String;
+}
+
+/// A key file on disk.
+pub struct FileKeyStore {
+    path: String,
+    passphrase: Option<PassphraseSource>,
+    create: bool,
+}
+
+impl FileKeyStore {
+    pub fn new(path: &str) -> FileKeyStore {
+        FileKeyStore {
+            path: path.to_string(),
+            passphrase: None,
+            create: false,
+        }
+    }
+
+    pub fn with_passphrase(mut self, source: PassphraseSource) -> FileKeyStore {
+        self.passphrase = Some(source);
+        self
+    }
+
+    /// Generates and saves a new key if the file doesn't exist, encrypted
+    /// if a passphrase source is set. Off by default, so a mistyped path
+    /// fails rather than silently giving the server a new identity.
+    pub fn create_if_missing(mut self) -> FileKeyStore {
+        self.create = true;
+        self
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn load(&self) -> Result<Ed25519KeyPair, Error> {
+        let result = match (&self.passphrase, self.create) {
+            (&Some(ref source), true) => load_or_create_encrypted_key(&self.path, source),
+            (&Some(ref source), false) => load_encrypted_key(&self.path, source),
+            (&None, true) => load_or_create_key(&self.path),
+            (&None, false) => load_key(&self.path),
+        };
+
+        match result {
+            Err(Error::IOError(ref err)) if err.kind() == io::ErrorKind::NotFound => Err(Error::KeyNotFound(self.describe())),
+            result => result,
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("file {}", self.path)
+    }
+}
+
+/// A key already held in memory, e.g. fetched from a secrets service.
+pub struct MemoryKeyStore {
+    key: KeyFile,
+    passphrase: Option<PassphraseSource>,
+}
+
+impl MemoryKeyStore {
+    pub fn new(key: KeyFile) -> MemoryKeyStore {
+        MemoryKeyStore {
+            key: key,
+            passphrase: None,
+        }
+    }
+
+    /// Parses the contents of a key file.
+    pub fn parse(contents: &[u8]) -> Result<MemoryKeyStore, Error> {
+        Ok(MemoryKeyStore::new(KeyFile::parse(contents)?))
+    }
+
+    pub fn with_passphrase(mut self, source: PassphraseSource) -> MemoryKeyStore {
+        self.passphrase = Some(source);
+        self
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn load(&self) -> Result<Ed25519KeyPair, Error> {
+        open(&self.key, &self.passphrase)
+    }
+
+    fn describe(&self) -> String {
+        "memory".to_string()
+    }
+}
+
+/// A key file's contents held in an environment variable. The legacy base64
+/// format fits on one line, which makes it the easiest to pass this way.
+///
+/// The variable is removed once it has been read, so child processes don't
+/// inherit the key; it can only be loaded once.
+pub struct EnvKeyStore {
+    name: String,
+    passphrase: Option<PassphraseSource>,
+}
+
+impl EnvKeyStore {
+    pub fn new(name: &str) -> EnvKeyStore {
+        EnvKeyStore {
+            name: name.to_string(),
+            passphrase: None,
+        }
+    }
+
+    pub fn with_passphrase(mut self, source: PassphraseSource) -> EnvKeyStore {
+        self.passphrase = Some(source);
+        self
+    }
+}
+
+impl KeyStore for EnvKeyStore {
+    fn load(&self) -> Result<Ed25519KeyPair, Error> {
+        let mut contents = match env::var(&self.name) {
+            Ok(value) => value.into_bytes(),
+            Err(_) => return Err(Error::KeyNotFound(self.describe())),
+        };
+        env::remove_var(&self.name);
+        let key = KeyFile::parse(&contents);
+        zeroize(&mut contents);
+
+        open(&key?, &self.passphrase)
+    }
+
+    fn describe(&self) -> String {
+        format!("environment variable {}", self.name)
+    }
+}
+
+/// A key file's contents read from an inherited file descriptor, such as a
+/// pipe set up by a supervisor. The descriptor is read to the end and left
+/// open, so the key can only be loaded once.
+#[cfg(unix)]
+pub struct FdKeyStore {
+    fd: RawFd,
+    passphrase: Option<PassphraseSource>,
+}
+
+#[cfg(unix)]
+impl FdKeyStore {
+    pub fn new(fd: RawFd) -> FdKeyStore {
+        FdKeyStore {
+            fd: fd,
+            passphrase: None,
+        }
+    }
+
+    pub fn with_passphrase(mut self, source: PassphraseSource) -> FdKeyStore {
+        self.passphrase = Some(source);
+        self
+    }
+}
+
+#[cfg(unix)]
+impl KeyStore for FdKeyStore {
+    fn load(&self) -> Result<Ed25519KeyPair, Error> {
+        let mut f = unsafe { File::from_raw_fd(self.fd) };
+        let mut contents = Vec::new();
+        let result = f.read_to_end(&mut contents);
+        // the descriptor belongs to whoever handed it to us
+        mem::forget(f);
+
+        let key = match result {
+            Ok(0) => Err(Error::KeyNotFound(self.describe())),
+            Ok(_) => KeyFile::parse(&contents),
+            Err(ref err) if err.raw_os_error() == Some(EBADF) => Err(Error::KeyNotFound(self.describe())),
+            Err(err) => Err(Error::from(err)),
+        };
+        zeroize(&mut contents);
+
+        open(&key?, &self.passphrase)
+    }
+
+    fn describe(&self) -> String {
+        format!("file descriptor {}", self.fd)
+    }
+}
+
+fn open(key: &KeyFile, passphrase: &Option<PassphraseSource>) -> Result<Ed25519KeyPair, Error> {
+    match *passphrase {
+        Some(ref source) => open_key_pair(key, source),
+        None => open_unencrypted_key_pair(key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+
+    use ::base64;
+    use ::ring::rand::SystemRandom;
+    use ::ring::signature::Ed25519KeyPair;
+
+    use errors::Error;
+    use super::{EnvKeyStore, FileKeyStore, KeyStore};
+
+    fn assert_not_found(result: Result<Ed25519KeyPair, Error>) {
+        match result {
+            Err(Error::KeyNotFound(_)) => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("loaded a key that isn't there"),
+        }
+    }
+
+    #[test]
+    fn env_store_removes_the_variable() {
+        let name = format!("LIBCART_TEST_KEY_{}", process::id());
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        env::set_var(&name, base64::encode(&pkcs8[..]));
+
+        let store = EnvKeyStore::new(&name);
+        store.load().unwrap();
+        assert!(env::var_os(&name).is_none());
+        assert_not_found(store.load());
+    }
+
+    #[test]
+    fn file_store_only_creates_when_asked() {
+        let dir = env::temp_dir().join(format!("libcart-key-store-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.key");
+        let path = path.to_str().unwrap();
+
+        let missing = FileKeyStore::new(path).load();
+        let created_without_asking = Path::new(path).exists();
+        let created = FileKeyStore::new(path).create_if_missing().load().map(|key| key.public_key_bytes().to_vec());
+        let reloaded = FileKeyStore::new(path).load().map(|key| key.public_key_bytes().to_vec());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_not_found(missing);
+        assert!(!created_without_asking);
+        assert_eq!(created.unwrap(), reloaded.unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fd_store_reports_a_closed_descriptor() {
+        use super::FdKeyStore;
+
+        // far above any descriptor the test process has open
+        assert_not_found(FdKeyStore::new(999_999).load());
+    }
+}
@@ -1,83 +1,444 @@
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
+use std::io;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 
-use ::pem;
-use ::ring::{rand, signature};
-use ::base64::{encode, decode};
+use ::ring::{digest, hkdf, hmac, rand, signature};
+use ::ring::rand::SecureRandom;
 use ::untrusted;
+use ::base64::encode;
 
 use errors::Error;
+use key_file::{KeyFile, KeyFormat, KeyKind};
+use passphrase::{PassphraseSource, seal_private_key, open_private_key};
+use secret::{SecretBytes, zeroize};
+use redact;
+use sealed_box;
+use x25519;
+
+/// Length in bytes of an Ed25519 public key.
+pub const PUBLIC_KEY_LEN: usize = 32;
+/// Length in bytes of the PKCS#8 encoding of an Ed25519 private key.
+pub const PKCS8_LEN: usize = 85;
+
+const SEALING_KEY_SALT: &'static [u8] = b"libcart sealing key";
 
 pub fn create_signing_keypair(filename: &str) -> Result<(), Error> {
-    let key = gen_key_bytes()?;
-    let p = pem::Pem {
-        tag: String::from("PRIVATE KEY"),
-        contents: key.to_vec()
-    };
+    generate_keypair(filename, "", None).map(|_| ())
+}
+
+/// Like `create_signing_keypair`, but the private key is encrypted under
+/// `passphrase` before it is written.
+pub fn create_encrypted_signing_keypair(filename: &str, passphrase: &[u8]) -> Result<(), Error> {
+    generate_keypair(filename, "", Some(passphrase)).map(|_| ())
+}
 
-    let encoded = pem::encode(&p);
-    write_key_file(&encoded, &format!("{}.key", filename))?;
+/// Writes a new key pair to `<filename>.key` and `<filename>.pub`, both
+/// tagged with `comment`, and returns the public key. The private key is
+/// encrypted if a `passphrase` is given.
+///
+/// Servers and clients use the same kind of key, so this makes either.
+/// Existing files are replaced; see `generate_keypair_with`.
+pub fn generate_keypair(filename: &str, comment: &str, passphrase: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+    generate_keypair_with(filename, comment, passphrase, true)
+}
 
-    let key = signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&key))?;
-    let pubkey = key.public_key_bytes();
-    let p = pem::Pem {
-        tag: String::from("PUBLIC KEY"),
-        contents: pubkey.to_vec()
+/// Like `generate_keypair`, but unless `replace` is set, an existing
+/// `<filename>.key` or `<filename>.pub` fails the call with
+/// `Error::KeyWriteFailed` and is left untouched (see `save_new_key_file`).
+pub fn generate_keypair_with(filename: &str, comment: &str, passphrase: Option<&[u8]>, replace: bool) -> Result<Vec<u8>, Error> {
+    let key = gen_key_bytes()?;
+    let private = match passphrase {
+        Some(passphrase) => KeyFile::new(KeyKind::EncryptedPrivateKey, seal_private_key(&key, passphrase)?, comment),
+        None => KeyFile::new(KeyKind::PrivateKey, key.to_vec(), comment),
     };
-    let encoded = pem::encode(&p);
-    write_key_file(&encoded, &format!("{}.pub", filename))?;
+    let private_path = format!("{}.key", filename);
+    save_key_file_with(&private, &private_path, KeyFormat::Current, replace)?;
 
-    Ok(())
+    let public_key = from_pkcs8(&key)?.public_key_bytes().to_vec();
+    let public = KeyFile::new(KeyKind::PublicKey, public_key.clone(), comment);
+    if let Err(err) = save_key_file_with(&public, &format!("{}.pub", filename), KeyFormat::Current, replace) {
+        // a private key without its public half is no use, and this call
+        // created it, so it can't be anyone else's
+        if !replace {
+            let _ = fs::remove_file(&private_path);
+        }
+        return Err(err);
+    }
+
+    Ok(public_key)
 }
 
 pub fn load_or_create_key(path: &str) -> Result<signature::Ed25519KeyPair, Error> {
     let pair = load_key(path);
 
     match pair {
-        Ok(key) => Ok(key),
-        Err(Error::IOError(ref err)) => {
+        Err(Error::IOError(ref err)) if err.kind() == io::ErrorKind::NotFound => {
             debug!("Got IOError: {}, generating new key", err);
             let key = gen_key_bytes()?;
-            let encoded = encode(&key[..]);
-            write_key_file(&encoded, path)?;
-
-            let key = signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&key))?;
+            write_key_file(&KeyFile::new(KeyKind::PrivateKey, key.to_vec(), ""), path)?;
 
-            Ok(key)
+            from_pkcs8(&key)
         }
-        Err(error) => panic!(error),
+        result => result,
     }
 }
 
-fn gen_key_bytes() -> Result<[u8; 85], Error> {
+/// Loads an unencrypted private key in any supported key file format.
+/// Encrypted keys are refused with `Error::PassphraseUnavailable`.
+pub fn load_key(path: &str) -> Result<signature::Ed25519KeyPair, Error> {
+    debug!("Attempting to load key: {}", path);
+    let key = KeyFile::load(path)?;
+
+    let pair = open_unencrypted_key_pair(&key)?;
+    debug!("Got key: {}", redact::key(pair.public_key_bytes()));
+
+    Ok(pair)
+}
+
+/// The key pair for the unencrypted private key in `key`, for callers with
+/// no passphrase source. Encrypted keys are refused with
+/// `Error::PassphraseUnavailable`.
+pub fn open_unencrypted_key_pair(key: &KeyFile) -> Result<signature::Ed25519KeyPair, Error> {
+    if key.kind == KeyKind::EncryptedPrivateKey {
+        return Err(Error::PassphraseUnavailable("key is encrypted but no passphrase source was given".to_string()));
+    }
+    key.expect_kind(KeyKind::PrivateKey)?;
+
+    from_pkcs8(&key.data)
+}
+
+/// Loads a private key, asking `source` for the passphrase if it's encrypted.
+/// Unencrypted keys are loaded without reading the passphrase, with a
+/// warning, since whoever supplied one expects the key to be protected.
+pub fn load_encrypted_key(path: &str, source: &PassphraseSource) -> Result<signature::Ed25519KeyPair, Error> {
+    debug!("Attempting to load encrypted key: {}", path);
+    let key = KeyFile::load(path)?;
+
+    open_key_pair(&key, source)
+}
+
+/// The key pair for the private key in `key`, asking `source` for the
+/// passphrase if it's encrypted. An unencrypted key is logged as a warning.
+pub fn open_key_pair(key: &KeyFile, source: &PassphraseSource) -> Result<signature::Ed25519KeyPair, Error> {
+    if key.kind == KeyKind::PrivateKey {
+        warn!("a passphrase source was given, but the private key is not encrypted");
+    }
+
+    from_pkcs8(&open_key_file(key, source)?)
+}
+
+/// The PKCS#8 private key in `key`, asking `source` for the passphrase if
+/// it's encrypted.
+pub fn open_key_file(key: &KeyFile, source: &PassphraseSource) -> Result<SecretBytes, Error> {
+    match key.kind {
+        KeyKind::EncryptedPrivateKey => {
+            let passphrase = source.read()?;
+            open_private_key(&key.data, &passphrase)
+        },
+        KeyKind::PrivateKey => Ok(key.data.clone()),
+        kind => Err(Error::UnexpectedKeyKind(KeyKind::EncryptedPrivateKey, kind)),
+    }
+}
+
+/// The public key in `key`, or the one belonging to the private key in it.
+/// `source` is only asked for a passphrase if the private key is encrypted.
+pub fn public_key_of(key: &KeyFile, source: &PassphraseSource) -> Result<Vec<u8>, Error> {
+    match key.kind {
+        KeyKind::PublicKey => Ok(key.data.to_vec()),
+        _ => Ok(from_pkcs8(&open_key_file(key, source)?)?.public_key_bytes().to_vec()),
+    }
+}
+
+/// The X25519 key pair that sealed boxes for the identity in `key` are
+/// sealed to (see `sealed_box`).
+///
+/// Ed25519 keys can't be used for key agreement directly, so this key is
+/// derived from the private key and always comes out the same for it. Its
+/// public half can't be computed from the Ed25519 public key, so senders
+/// are given it signed by the identity; see `signed_sealing_key`.
+pub fn open_sealing_key(key: &KeyFile, source: &PassphraseSource) -> Result<x25519::KeyPair, Error> {
+    let pkcs8 = open_key_file(key, source)?;
+    from_pkcs8(&pkcs8)?;
+
+    sealing_key_from_pkcs8(&pkcs8)
+}
+
+/// The public half of `open_sealing_key`, signed by the identity in `key`
+/// (see `sealed_box::verify_sealing_key`). Senders need this and the
+/// identity's Ed25519 public key to seal boxes to it, e.g. from
+/// `cart-keygen sealing-key`.
+pub fn signed_sealing_key(key: &KeyFile, source: &PassphraseSource) -> Result<Vec<u8>, Error> {
+    let pkcs8 = open_key_file(key, source)?;
+    let identity = from_pkcs8(&pkcs8)?;
+    let pair = sealing_key_from_pkcs8(&pkcs8)?;
+
+    Ok(sealed_box::sign_sealing_key(&identity, pair.public_key_bytes()))
+}
+
+fn sealing_key_from_pkcs8(pkcs8: &[u8]) -> Result<x25519::KeyPair, Error> {
+    let salt = hmac::SigningKey::new(&digest::SHA256, SEALING_KEY_SALT);
+    let mut private_key = [0u8; x25519::KEY_LEN];
+    hkdf::extract_and_expand(&salt, pkcs8, b"x25519", &mut private_key);
+    let pair = x25519::KeyPair::from_private_key(&private_key);
+    zeroize(&mut private_key);
+
+    pair
+}
+
+/// Re-encrypts the private key at `path` under `passphrase`, or stores it
+/// unencrypted if `passphrase` is `None`. `source` supplies the current
+/// passphrase if there is one. The creation time and comment are kept.
+///
+/// `allow_insecure` is passed to `KeyFile::load_with`; the rewritten file is
+/// owner-only either way.
+pub fn set_passphrase(path: &str, source: &PassphraseSource, passphrase: Option<&[u8]>, allow_insecure: bool) -> Result<(), Error> {
+    let mut key = KeyFile::load_with(path, allow_insecure)?;
+    let pkcs8 = open_key_file(&key, source)?;
+    from_pkcs8(&pkcs8)?;
+
+    match passphrase {
+        Some(passphrase) => {
+            key.kind = KeyKind::EncryptedPrivateKey;
+            key.data = SecretBytes::new(seal_private_key(&pkcs8, passphrase)?);
+        },
+        None => {
+            key.kind = KeyKind::PrivateKey;
+            key.data = pkcs8;
+        },
+    }
+
+    write_key_file(&key, path)
+}
+
+/// Writes `key` to `filename` in the given format.
+///
+/// The key goes to a temporary file in the same directory, which is synced
+/// and then renamed over `filename`, so a crash leaves either the old file or
+/// the complete new one. Private keys are created readable by their owner
+/// only. Failures are reported as `Error::KeyWriteFailed`.
+pub fn save_key_file(key: &KeyFile, filename: &str, format: KeyFormat) -> Result<(), Error> {
+    save_key_file_with(key, filename, format, true)
+}
+
+/// Like `save_key_file`, but never replaces an existing file: if `filename`
+/// exists, this fails with an `io::ErrorKind::AlreadyExists` error inside
+/// `Error::KeyWriteFailed`. The temporary file is hard-linked into place
+/// rather than renamed, so the check and the write are a single step.
+pub fn save_new_key_file(key: &KeyFile, filename: &str, format: KeyFormat) -> Result<(), Error> {
+    save_key_file_with(key, filename, format, false)
+}
+
+fn save_key_file_with(key: &KeyFile, filename: &str, format: KeyFormat, replace: bool) -> Result<(), Error> {
+    let mut encoded = key.encode_as(format)?.into_bytes();
+    let mode = match key.kind {
+        KeyKind::PublicKey => 0o644,
+        _ => 0o600,
+    };
+
+    let result = write_atomically(Path::new(filename), &encoded, mode, replace);
+    zeroize(&mut encoded);
+
+    result.map_err(|err| Error::KeyWriteFailed(filename.to_string(), err))
+}
+
+/// Loads an encrypted private key, generating and encrypting a new one if
+/// `path` doesn't exist yet.
+pub fn load_or_create_encrypted_key(path: &str, source: &PassphraseSource) -> Result<signature::Ed25519KeyPair, Error> {
+    match load_encrypted_key(path, source) {
+        Err(Error::IOError(ref err)) if err.kind() == io::ErrorKind::NotFound => {
+            debug!("{} not found, generating new encrypted key", path);
+            let passphrase = source.read()?;
+            create_encrypted_key(path, &passphrase)
+        },
+        result => result,
+    }
+}
+
+/// Loads an Ed25519 public key in any supported key file format, ready to be
+/// passed to `Client::connect`.
+pub fn load_public_key(path: &str) -> Result<Vec<u8>, Error> {
+    debug!("Attempting to load public key: {}", path);
+    let key = KeyFile::load(path)?;
+    key.expect_kind(KeyKind::PublicKey)?;
+
+    Ok(key.data.to_vec())
+}
+
+/// A printable SHA-256 fingerprint of a public key, e.g. `SHA256:n4bQgYhM...`.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let hash = digest::digest(&digest::SHA256, public_key);
+    format!("SHA256:{}", encode(hash.as_ref()))
+}
+
+fn create_encrypted_key(path: &str, passphrase: &[u8]) -> Result<signature::Ed25519KeyPair, Error> {
+    let key = gen_key_bytes()?;
+    let sealed = seal_private_key(&key, passphrase)?;
+    write_key_file(&KeyFile::new(KeyKind::EncryptedPrivateKey, sealed, ""), path)?;
+
+    from_pkcs8(&key)
+}
+
+fn from_pkcs8(pkcs8: &[u8]) -> Result<signature::Ed25519KeyPair, Error> {
+    match signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(pkcs8)) {
+        Ok(pair) => Ok(pair),
+        Err(_) => Err(Error::InvalidKeyFile("not a valid Ed25519 PKCS#8 private key".to_string())),
+    }
+}
+
+fn gen_key_bytes() -> Result<SecretBytes, Error> {
     let rng = rand::SystemRandom::new();
-    let pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng)?;
+    let mut pkcs8_bytes = signature::Ed25519KeyPair::generate_pkcs8(&rng).map_err(|_| Error::RandomFailure)?;
 
-    Ok(pkcs8_bytes)
+    Ok(SecretBytes::take(&mut pkcs8_bytes))
 }
 
-fn write_key_file(key: &str, filename: &str) -> Result<(), Error> {
-    let mut f = File::create(filename)?;
-    f.write_all(key.as_bytes())?;
-    f.sync_all()?;
+fn write_key_file(key: &KeyFile, filename: &str) -> Result<(), Error> {
+    save_key_file(key, filename, KeyFormat::Current)
+}
 
-    Ok(())
+fn write_atomically(path: &Path, data: &[u8], mode: u32, replace: bool) -> io::Result<()> {
+    let tmp = temp_path(path)?;
+    let result = create_new(&tmp, mode)
+        .and_then(|mut f| f.write_all(data).and_then(|()| f.sync_all()))
+        .and_then(|()| if replace { fs::rename(&tmp, path) } else { fs::hard_link(&tmp, path) });
+
+    // a hard link leaves the temporary name behind as well
+    if result.is_err() || !replace {
+        let _ = fs::remove_file(&tmp);
+    }
+    result?;
+
+    sync_parent(path)
 }
 
-fn load_key(path: &str) -> Result<signature::Ed25519KeyPair, Error> {
-    debug!("Attempting to load server key: {}", path);
-    let mut f = File::open(path)?;
-    let mut buf = Vec::new();
+/// A name for the temporary file next to `path`, randomized so concurrent
+/// writers don't collide.
+fn temp_path(path: &Path) -> io::Result<PathBuf> {
+    let rng = rand::SystemRandom::new();
+    let mut suffix = [0u8; 8];
+    if rng.fill(&mut suffix).is_err() {
+        return Err(io::Error::new(io::ErrorKind::Other, "unable to generate temporary file name"));
+    }
 
-    f.read_to_end(&mut buf)?;
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "key path has no file name")),
+    };
+    let suffix: Vec<String> = suffix.iter().map(|byte| format!("{:02x}", byte)).collect();
 
-    let decoded = decode(&buf)?;
-    let mut der_bytes: [u8; 85] = [0; 85];
-    der_bytes.clone_from_slice(&decoded);
+    Ok(path.with_file_name(format!(".{}.{}.tmp", name, suffix.concat())))
+}
 
-    let pair = signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&der_bytes))?;
-    debug!("Got server key: {:?}", pair.public_key_bytes());
+#[cfg(unix)]
+fn create_new(path: &Path, mode: u32) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
 
-    Ok(pair)
+    OpenOptions::new().write(true).create_new(true).mode(mode).open(path)
+}
+
+#[cfg(not(unix))]
+fn create_new(path: &Path, _: u32) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+/// Syncs the directory holding `path`, so the rename itself survives a crash.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    File::open(parent)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_: &Path) -> io::Result<()> {
+    Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::process;
+
+    use errors::Error;
+
+    use key_file::{KeyFile, KeyFormat, KeyKind};
+    use super::{generate_keypair_with, save_key_file, save_new_key_file, PKCS8_LEN};
+
+    #[cfg(unix)]
+    #[test]
+    fn saves_private_keys_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("libcart-keys-{}-save", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.key");
+        let path = path.to_str().unwrap();
+
+        // an existing file is replaced through the temporary file, not
+        // rewritten in place, so its old permissions don't carry over
+        fs::write(path, b"old key").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let key = KeyFile::new(KeyKind::PrivateKey, vec![1; PKCS8_LEN], "");
+        save_key_file(&key, path, KeyFormat::Current).unwrap();
+
+        let mode = fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let contents = fs::read(path).unwrap();
+        let entries = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(mode, 0o600);
+        assert_eq!(contents, key.encode().into_bytes());
+        assert_eq!(entries, 1, "temporary file left behind");
+    }
+
+    #[test]
+    fn refuses_to_replace_existing_keys() {
+        let dir = env::temp_dir().join(format!("libcart-keys-{}-new", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.key");
+        let path = path.to_str().unwrap();
+        fs::write(path, b"old key").unwrap();
+
+        let key = KeyFile::new(KeyKind::PrivateKey, vec![1; PKCS8_LEN], "");
+        let result = save_new_key_file(&key, path, KeyFormat::Current);
+
+        let contents = fs::read(path).unwrap();
+        let entries = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+
+        match result {
+            Err(Error::KeyWriteFailed(_, ref err)) => assert_eq!(err.kind(), io::ErrorKind::AlreadyExists),
+            other => panic!("expected KeyWriteFailed, got {:?}", other),
+        }
+        assert_eq!(contents, b"old key");
+        assert_eq!(entries, 1, "temporary file left behind");
+    }
+
+    #[test]
+    fn generates_key_pairs_only_once_unless_replacing() {
+        let dir = env::temp_dir().join(format!("libcart-keys-{}-generate", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("server");
+        let name = name.to_str().unwrap();
+
+        let first = generate_keypair_with(name, "", None, false).unwrap();
+        let second = generate_keypair_with(name, "", None, false);
+        let kept = KeyFile::load(&format!("{}.pub", name)).unwrap().data.to_vec();
+        let third = generate_keypair_with(name, "", None, true).unwrap();
+        let replaced = KeyFile::load(&format!("{}.pub", name)).unwrap().data.to_vec();
+        let entries = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(second.is_err());
+        assert_eq!(kept, first);
+        assert_eq!(replaced, third);
+        assert_eq!(entries, 2);
+    }
+}
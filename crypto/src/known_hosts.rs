@@ -0,0 +1,154 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+
+use errors::Error;
+use keys::fingerprint;
+
+/// How to treat a host that has no entry in the known hosts file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostKeyPolicy {
+    /// Refuse hosts that aren't already listed.
+    Strict,
+    /// Record the key of an unlisted host and accept it (trust on first use).
+    TrustOnFirstUse,
+}
+
+/// A file of `host fingerprint` lines recording the long-term key each server
+/// presented on first contact. Blank lines and lines starting with `#` are
+/// ignored.
+#[derive(Debug, Clone)]
+pub struct KnownHosts {
+    path: String,
+}
+
+impl KnownHosts {
+    pub fn new(path: &str) -> KnownHosts {
+        KnownHosts {
+            path: path.to_string(),
+        }
+    }
+
+    /// The recorded fingerprint for `host`, if any.
+    pub fn lookup(&self, host: &str) -> Result<Option<String>, Error> {
+        let mut contents = String::new();
+        match File::open(&self.path) {
+            Ok(mut f) => { f.read_to_string(&mut contents)?; },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(Error::from(err)),
+        }
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            if fields.next() == Some(host) {
+                return Ok(fields.next().map(|fp| fp.to_string()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Checks `public_key` against the entry for `host`. Fails with
+    /// `Error::HostKeyMismatch` if a different key was recorded, and with
+    /// `Error::UnknownHost` for an unlisted host under `HostKeyPolicy::Strict`.
+    pub fn verify(&self, host: &str, public_key: &[u8], policy: HostKeyPolicy) -> Result<(), Error> {
+        let presented = fingerprint(public_key);
+
+        match self.lookup(host)? {
+            Some(ref recorded) if *recorded == presented => Ok(()),
+            Some(_) => {
+                error!("Host key for {} has changed! Presented key: {}", host, presented);
+                Err(Error::HostKeyMismatch(host.to_string()))
+            },
+            None => match policy {
+                HostKeyPolicy::Strict => Err(Error::UnknownHost(host.to_string())),
+                HostKeyPolicy::TrustOnFirstUse => {
+                    warn!("Permanently added {} ({}) to known hosts", host, presented);
+                    self.add(host, public_key)
+                },
+            },
+        }
+    }
+
+    /// Appends an entry for `host`. Doesn't check for an existing one.
+    pub fn add(&self, host: &str, public_key: &[u8]) -> Result<(), Error> {
+        let mut f = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        f.write_all(format!("{} {}\n", host, fingerprint(public_key)).as_bytes())?;
+        f.sync_all()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use errors::Error;
+    use keys::fingerprint;
+    use super::{HostKeyPolicy, KnownHosts};
+
+    const KEY: [u8; 32] = [1; 32];
+    const OTHER_KEY: [u8; 32] = [2; 32];
+
+    fn known_hosts(name: &str) -> (KnownHosts, String) {
+        let path = env::temp_dir().join(format!("libcart-known-hosts-{}-{}", process::id(), name));
+        let path = path.to_string_lossy().into_owned();
+        let _ = fs::remove_file(&path);
+
+        (KnownHosts::new(&path), path)
+    }
+
+    #[test]
+    fn records_new_hosts_on_first_use() {
+        let (hosts, path) = known_hosts("tofu");
+
+        hosts.verify("a.com", &KEY, HostKeyPolicy::TrustOnFirstUse).unwrap();
+        let recorded = hosts.lookup("a.com").unwrap();
+        hosts.verify("a.com", &KEY, HostKeyPolicy::Strict).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(recorded, Some(fingerprint(&KEY)));
+    }
+
+    #[test]
+    fn refuses_unknown_hosts_when_strict() {
+        let (hosts, path) = known_hosts("strict");
+        hosts.add("a.com", &KEY).unwrap();
+
+        let result = hosts.verify("b.com", &KEY, HostKeyPolicy::Strict);
+        let recorded = hosts.lookup("b.com").unwrap();
+        fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(Error::UnknownHost(ref host)) if host == "b.com" => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(()) => panic!("accepted an unknown host"),
+        }
+        assert_eq!(recorded, None);
+    }
+
+    #[test]
+    fn refuses_changed_keys() {
+        let (hosts, path) = known_hosts("changed");
+        hosts.add("a.com", &KEY).unwrap();
+
+        let result = hosts.verify("a.com", &OTHER_KEY, HostKeyPolicy::TrustOnFirstUse);
+        let recorded = hosts.lookup("a.com").unwrap();
+        fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(Error::HostKeyMismatch(ref host)) if host == "a.com" => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(()) => panic!("accepted a changed host key"),
+        }
+        assert_eq!(recorded, Some(fingerprint(&KEY)));
+    }
+}
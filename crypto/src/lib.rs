@@ -4,10 +4,37 @@ extern crate base64;
 extern crate ring;
 extern crate untrusted;
 extern crate pem;
+extern crate scrypt;
+extern crate x25519_dalek;
+extern crate spake2;
+#[cfg(feature = "mlock")]
+extern crate libc;
+#[cfg(feature = "tokio")]
+#[macro_use]
+extern crate tokio_io;
+#[cfg(feature = "tokio")]
+extern crate futures;
 
 pub mod errors;
+pub mod secret;
 pub mod keys;
+pub mod key_file;
+pub mod key_store;
 pub mod aead;
+pub mod authorized_keys;
+pub mod transcript;
+pub mod passphrase;
+pub mod known_hosts;
+pub mod encoding;
+pub mod certificate;
+pub mod revocation;
+pub mod x25519;
+pub mod noise;
+pub mod psk;
+pub mod ticket;
+pub mod sealed_box;
+pub mod pairing;
+pub mod redact;
 
 use self::base64::{encode, decode};
 
@@ -15,8 +42,8 @@ pub fn encode_base64(input: &[u8]) -> String {
     encode(input)
 }
 
-pub fn decode_base64(input: &[u8]) -> Vec<u8> {
-    decode(input).unwrap()
+pub fn decode_base64(input: &[u8]) -> Result<Vec<u8>, errors::Error> {
+    Ok(decode(input)?)
 }
 
 pub fn verify(pub_key: &[u8], msg: &[u8], sig: &[u8]) -> Result<(), errors::Error> {
@@ -33,7 +60,7 @@ pub fn verify(pub_key: &[u8], msg: &[u8], sig: &[u8]) -> Result<(), errors::Erro
 
     match result {
         Ok(()) => Ok(()),
-        Err(_) => Err(errors::Error::CryptoError("bad signature".to_string()))
+        Err(_) => Err(errors::Error::BadSignature)
     }
 }
 
@@ -0,0 +1,730 @@
+use std::sync::Arc;
+
+use ::ring::{digest, hmac};
+use ::ring::aead as ring_aead;
+
+use errors::Error;
+use secret::SecretBytes;
+use aead::{CipherSuite, EncryptionHandler, Role};
+use x25519::{self, KeyPair};
+
+const HASH_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+
+/// Input to one more HKDF of the final chaining key, giving the exporter
+/// secret. The Noise spec leaves exporters to the application.
+const EXPORTER_INPUT: &'static [u8] = b"libcart noise exporter";
+
+/// The Noise handshake patterns libcart can run. Each one names how the
+/// initiator (client) and responder (server) authenticate:
+///
+/// * `NK`: the client knows the server's static key in advance and stays
+///   anonymous itself.
+/// * `XX`: both sides send their static keys during the handshake, for mutual
+///   authentication without either knowing the other beforehand.
+/// * `IK`: the client knows the server's static key and sends its own in the
+///   first message, finishing in a single round trip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoisePattern {
+    NK,
+    XX,
+    IK,
+}
+
+impl NoisePattern {
+    pub fn from_id(id: u8) -> Option<NoisePattern> {
+        match id {
+            1 => Some(NoisePattern::NK),
+            2 => Some(NoisePattern::XX),
+            3 => Some(NoisePattern::IK),
+            _ => None,
+        }
+    }
+
+    /// The identifier sent on the wire during the handshake.
+    pub fn id(&self) -> u8 {
+        match *self {
+            NoisePattern::NK => 1,
+            NoisePattern::XX => 2,
+            NoisePattern::IK => 3,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            NoisePattern::NK => "NK",
+            NoisePattern::XX => "XX",
+            NoisePattern::IK => "IK",
+        }
+    }
+
+    /// Whether the initiator must know the responder's static key beforehand.
+    pub fn needs_remote_static(&self) -> bool {
+        *self != NoisePattern::XX
+    }
+
+    /// Whether the initiator authenticates with a static key of its own.
+    pub fn authenticates_initiator(&self) -> bool {
+        *self != NoisePattern::NK
+    }
+
+    /// The number of handshake messages, counting both directions.
+    pub fn message_count(&self) -> usize {
+        self.messages().len()
+    }
+
+    fn messages(&self) -> &'static [&'static [Token]] {
+        use self::Token::*;
+
+        match *self {
+            NoisePattern::NK => &[&[E, ES], &[E, EE]],
+            NoisePattern::XX => &[&[E], &[E, EE, S, ES], &[S, SE]],
+            NoisePattern::IK => &[&[E, ES, S, SS], &[E, EE, SE]],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    E,
+    S,
+    EE,
+    ES,
+    SE,
+    SS,
+}
+
+/// The Noise protocol name for a pattern and suite, e.g.
+/// `Noise_XX_25519_ChaChaPoly_SHA256`. Only ChaCha20-Poly1305 and AES-256-GCM
+/// have Noise names; other suites return `None`.
+pub fn protocol_name(pattern: NoisePattern, suite: CipherSuite) -> Option<String> {
+    let cipher = match suite {
+        CipherSuite::ChaCha20Poly1305 => "ChaChaPoly",
+        CipherSuite::Aes256Gcm => "AESGCM",
+        _ => return None,
+    };
+
+    Some(format!("Noise_{}_25519_{}_SHA256", pattern.name(), cipher))
+}
+
+/// The Noise nonce for counter `n`: four zero bytes followed by the counter,
+/// little-endian for ChaChaPoly and big-endian for AESGCM.
+pub fn nonce(suite: CipherSuite, n: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    for i in 0..8 {
+        let byte = (n >> (i * 8)) as u8;
+        match suite {
+            CipherSuite::ChaCha20Poly1305 => nonce[4 + i] = byte,
+            _ => nonce[11 - i] = byte,
+        }
+    }
+
+    nonce
+}
+
+/// Noise's `REKEY(k)`: the first 32 bytes of encrypting 32 zero bytes under
+/// `key` with the reserved nonce 2^64 - 1.
+pub fn rekey(suite: CipherSuite, key: &[u8]) -> Result<SecretBytes, Error> {
+    let sealing_key = ring_aead::SealingKey::new(suite.algorithm(), key)?;
+    let key_len = suite.algorithm().key_len();
+    let mut out = SecretBytes::zeroed(key_len + TAG_LEN);
+    ring_aead::seal_in_place(&sealing_key, &nonce(suite, u64::max_value()), &[], &mut out, TAG_LEN)?;
+
+    Ok(SecretBytes::from_slice(&out[..key_len]))
+}
+
+/// Noise's CipherState: an optional key and a message counter used as the
+/// nonce.
+struct CipherState {
+    suite: CipherSuite,
+    key: Option<SecretBytes>,
+    n: u64,
+}
+
+impl CipherState {
+    fn new(suite: CipherSuite) -> CipherState {
+        CipherState {
+            suite: suite,
+            key: None,
+            n: 0,
+        }
+    }
+
+    fn initialize_key(&mut self, key: &[u8]) {
+        self.key = Some(SecretBytes::from_slice(key));
+        self.n = 0;
+    }
+
+    fn has_key(&self) -> bool {
+        self.key.is_some()
+    }
+
+    fn encrypt_with_ad(&mut self, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let out = match self.key {
+            Some(ref key) => {
+                let key = ring_aead::SealingKey::new(self.suite.algorithm(), key)?;
+                let mut out = plaintext.to_vec();
+                out.resize(plaintext.len() + TAG_LEN, 0);
+                ring_aead::seal_in_place(&key, &nonce(self.suite, self.n), ad, &mut out, TAG_LEN)?;
+                out
+            },
+            None => return Ok(plaintext.to_vec()),
+        };
+        self.n += 1;
+
+        Ok(out)
+    }
+
+    fn decrypt_with_ad(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        let out = match self.key {
+            Some(ref key) => {
+                let key = ring_aead::OpeningKey::new(self.suite.algorithm(), key)?;
+                let mut data = ciphertext.to_vec();
+                match ring_aead::open_in_place(&key, &nonce(self.suite, self.n), ad, 0, &mut data) {
+                    Ok(plaintext) => plaintext.to_vec(),
+                    Err(_) => return Err(Error::DecryptFailed),
+                }
+            },
+            None => return Ok(ciphertext.to_vec()),
+        };
+        self.n += 1;
+
+        Ok(out)
+    }
+}
+
+/// Noise's SymmetricState: the chaining key and handshake hash.
+struct SymmetricState {
+    cipher: CipherState,
+    ck: SecretBytes,
+    h: Vec<u8>,
+}
+
+impl SymmetricState {
+    fn new(protocol_name: &str, suite: CipherSuite) -> SymmetricState {
+        let name = protocol_name.as_bytes();
+        let h = if name.len() <= HASH_LEN {
+            let mut h = name.to_vec();
+            h.resize(HASH_LEN, 0);
+            h
+        } else {
+            digest::digest(&digest::SHA256, name).as_ref().to_vec()
+        };
+
+        SymmetricState {
+            cipher: CipherState::new(suite),
+            ck: SecretBytes::from_slice(&h),
+            h: h,
+        }
+    }
+
+    fn mix_key(&mut self, input_key_material: &[u8]) {
+        let (ck, temp_k) = noise_hkdf(&self.ck, input_key_material);
+        self.ck = ck;
+        self.cipher.initialize_key(&temp_k);
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(&self.h);
+        ctx.update(data);
+        self.h = ctx.finish().as_ref().to_vec();
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let h = self.h.clone();
+        let ciphertext = self.cipher.encrypt_with_ad(&h, plaintext)?;
+        self.mix_hash(&ciphertext);
+
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        let h = self.h.clone();
+        let plaintext = self.cipher.decrypt_with_ad(&h, ciphertext)?;
+        self.mix_hash(ciphertext);
+
+        Ok(plaintext)
+    }
+
+    /// The initiator-to-responder and responder-to-initiator secrets.
+    fn split(&self) -> (SecretBytes, SecretBytes) {
+        noise_hkdf(&self.ck, &[])
+    }
+}
+
+/// Noise's two-output HKDF over HMAC-SHA256.
+fn noise_hkdf(chaining_key: &[u8], input_key_material: &[u8]) -> (SecretBytes, SecretBytes) {
+    let temp_key = hmac::sign(&hmac::SigningKey::new(&digest::SHA256, chaining_key), input_key_material);
+    let temp_key = hmac::SigningKey::new(&digest::SHA256, temp_key.as_ref());
+
+    let output1 = SecretBytes::from_slice(hmac::sign(&temp_key, &[1]).as_ref());
+    let input2 = SecretBytes::concat(&output1, &[2]);
+    let output2 = SecretBytes::from_slice(hmac::sign(&temp_key, &input2).as_ref());
+
+    (output1, output2)
+}
+
+/// A Noise handshake in progress, for either side.
+///
+/// Messages are produced and consumed strictly in pattern order with
+/// `write_message` and `read_message`. Once `is_finished` is true,
+/// `into_handler` turns the result into an `EncryptionHandler` that seals
+/// Noise transport messages with the split keys.
+///
+/// Static keys are X25519 keys (see `x25519::KeyPair`); with Noise they, not
+/// Ed25519 keys, identify the peers. They are shared rather than copied, so
+/// the private key exists only once however many handshakes use it.
+pub struct HandshakeState {
+    pattern: NoisePattern,
+    suite: CipherSuite,
+    initiator: bool,
+    symmetric: SymmetricState,
+    s: Option<Arc<KeyPair>>,
+    e: Option<KeyPair>,
+    rs: Option<Vec<u8>>,
+    re: Option<Vec<u8>>,
+    message: usize,
+}
+
+impl HandshakeState {
+    /// Starts the client side. `remote_static` is the server's static key and
+    /// is required by `NK` and `IK`; `static_key` is required by `XX` and `IK`.
+    pub fn initiator(pattern: NoisePattern, suite: CipherSuite, prologue: &[u8], static_key: Option<Arc<KeyPair>>, remote_static: Option<Vec<u8>>) -> Result<HandshakeState, Error> {
+        if pattern.authenticates_initiator() && static_key.is_none() {
+            return Err(Error::MissingStaticKey(format!("client static key for Noise {}", pattern.name())));
+        }
+        if pattern.needs_remote_static() && remote_static.is_none() {
+            return Err(Error::MissingStaticKey(format!("server static key for Noise {}", pattern.name())));
+        }
+
+        HandshakeState::new(pattern, suite, prologue, true, static_key, remote_static)
+    }
+
+    /// Starts the server side, which always has a static key.
+    pub fn responder(pattern: NoisePattern, suite: CipherSuite, prologue: &[u8], static_key: Arc<KeyPair>) -> Result<HandshakeState, Error> {
+        HandshakeState::new(pattern, suite, prologue, false, Some(static_key), None)
+    }
+
+    fn new(pattern: NoisePattern, suite: CipherSuite, prologue: &[u8], initiator: bool, s: Option<Arc<KeyPair>>, rs: Option<Vec<u8>>) -> Result<HandshakeState, Error> {
+        let name = match protocol_name(pattern, suite) {
+            Some(name) => name,
+            None => return Err(Error::UnsupportedCipherSuite(suite)),
+        };
+
+        let mut symmetric = SymmetricState::new(&name, suite);
+        symmetric.mix_hash(prologue);
+
+        // NK and IK both pre-share the responder's static key
+        if pattern.needs_remote_static() {
+            let responder_static = if initiator {
+                rs.clone()
+            } else {
+                s.as_ref().map(|s| s.public_key_bytes().to_vec())
+            };
+            match responder_static {
+                Some(ref key) if key.len() == x25519::KEY_LEN => symmetric.mix_hash(key),
+                _ => return Err(Error::InvalidKey("invalid Noise responder static key".to_string())),
+            }
+        }
+
+        Ok(HandshakeState {
+            pattern: pattern,
+            suite: suite,
+            initiator: initiator,
+            symmetric: symmetric,
+            s: s,
+            e: None,
+            rs: rs,
+            re: None,
+            message: 0,
+        })
+    }
+
+    /// Whether the next handshake message is ours to write.
+    pub fn is_my_turn(&self) -> bool {
+        (self.message % 2 == 0) == self.initiator
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.message >= self.pattern.message_count()
+    }
+
+    /// The peer's static key, once the handshake has revealed or confirmed it.
+    pub fn remote_static(&self) -> Option<&[u8]> {
+        self.rs.as_ref().map(|rs| &rs[..])
+    }
+
+    pub fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.is_finished() || !self.is_my_turn() {
+            return Err(Error::HandshakeOutOfTurn);
+        }
+
+        let mut out = Vec::new();
+        for token in self.pattern.messages()[self.message] {
+            match *token {
+                Token::E => {
+                    // Only the test vectors ever preset the ephemeral key
+                    let e = match self.e.take() {
+                        Some(e) => e,
+                        None => KeyPair::generate()?,
+                    };
+                    out.extend_from_slice(e.public_key_bytes());
+                    self.symmetric.mix_hash(e.public_key_bytes());
+                    self.e = Some(e);
+                },
+                Token::S => {
+                    let s = match self.s {
+                        Some(ref s) => s.public_key_bytes().to_vec(),
+                        None => return Err(Error::MissingStaticKey("local static key".to_string())),
+                    };
+                    let encrypted = self.symmetric.encrypt_and_hash(&s)?;
+                    out.extend_from_slice(&encrypted);
+                },
+                token => self.mix_dh(token)?,
+            }
+        }
+
+        let encrypted = self.symmetric.encrypt_and_hash(payload)?;
+        out.extend_from_slice(&encrypted);
+        self.message += 1;
+
+        Ok(out)
+    }
+
+    /// Processes the peer's next message and returns its payload.
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.is_finished() || self.is_my_turn() {
+            return Err(Error::HandshakeOutOfTurn);
+        }
+
+        let mut rest = message;
+        for token in self.pattern.messages()[self.message] {
+            match *token {
+                Token::E => {
+                    let re = take(&mut rest, x25519::KEY_LEN)?;
+                    self.symmetric.mix_hash(&re);
+                    self.re = Some(re);
+                },
+                Token::S => {
+                    let len = if self.symmetric.cipher.has_key() { x25519::KEY_LEN + TAG_LEN } else { x25519::KEY_LEN };
+                    let encrypted = take(&mut rest, len)?;
+                    self.rs = Some(self.symmetric.decrypt_and_hash(&encrypted)?);
+                },
+                token => self.mix_dh(token)?,
+            }
+        }
+
+        let payload = self.symmetric.decrypt_and_hash(rest)?;
+        self.message += 1;
+
+        Ok(payload)
+    }
+
+    /// Performs the DH a token calls for. Tokens name the initiator's key
+    /// first, so the responder swaps the local and remote halves.
+    fn mix_dh(&mut self, token: Token) -> Result<(), Error> {
+        let (local_ephemeral, remote_ephemeral) = match token {
+            Token::EE => (true, true),
+            Token::ES => (self.initiator, !self.initiator),
+            Token::SE => (!self.initiator, self.initiator),
+            Token::SS => (false, false),
+            Token::E | Token::S => unreachable!(),
+        };
+
+        let local = if local_ephemeral { self.e.as_ref() } else { self.s.as_ref().map(|s| &**s) };
+        let remote = if remote_ephemeral { self.re.as_ref() } else { self.rs.as_ref() };
+        let shared = match (local, remote) {
+            (Some(local), Some(remote)) => local.agree(remote)?,
+            _ => return Err(Error::MissingStaticKey(format!("key for Noise {:?}", token))),
+        };
+        self.symmetric.mix_key(&shared);
+
+        Ok(())
+    }
+
+    /// The handshake hash, which both sides share once the handshake is done.
+    pub fn handshake_hash(&self) -> &[u8] {
+        &self.symmetric.h
+    }
+
+    /// Finishes the handshake, keying an `EncryptionHandler` for `role`
+    /// directly with the two split keys (see
+    /// `EncryptionHandler::from_noise_split`). The handshake hash becomes the
+    /// session id.
+    pub fn into_handler(self, role: Role) -> Result<EncryptionHandler, Error> {
+        if !self.is_finished() {
+            return Err(Error::HandshakeIncomplete);
+        }
+
+        let (initiator_to_responder, responder_to_initiator) = self.symmetric.split();
+        let (exporter, _) = noise_hkdf(&self.symmetric.ck, EXPORTER_INPUT);
+
+        EncryptionHandler::from_noise_split(role, self.symmetric.h.clone(), self.suite, initiator_to_responder, responder_to_initiator, exporter)
+    }
+}
+
+fn take(rest: &mut &[u8], len: usize) -> Result<Vec<u8>, Error> {
+    if rest.len() < len {
+        return Err(Error::TruncatedHandshake);
+    }
+
+    let remaining: &[u8] = *rest;
+    let (head, tail) = remaining.split_at(len);
+    *rest = tail;
+
+    Ok(head.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ring::{digest, hkdf, hmac};
+
+    use aead::{CipherSuite, Role};
+    use errors::Error;
+    use x25519::KeyPair;
+    use super::{HandshakeState, NoisePattern, nonce, noise_hkdf};
+
+    // Keys and payloads shared by the cacophony test vectors
+    const PROLOGUE: &'static str = "4a6f686e2047616c74";
+    const INIT_STATIC: &'static str = "e61ef9919cde45dd5f82166404bd08e38bceb5dfdfded0a34c8df7ed542214d1";
+    const INIT_EPHEMERAL: &'static str = "893e28b9dc6ca8d611ab664754b8ceb7bac5117349a4439a6b0569da977c464a";
+    const RESP_STATIC: &'static str = "4a3acbfdb163dec651dfa3194dece676d437029c62a408b4c5ea9114246e4893";
+    const RESP_STATIC_PUBLIC: &'static str = "31e0303fd6418d2f8c0e78b91f22e8caed0fbe48656dcf4767e4834f701b8f62";
+    const RESP_EPHEMERAL: &'static str = "bbdb4cdbd309f1a1f2e1456967fe288cadd6f712d65dc7b7793d5e63da6b375b";
+    const PAYLOADS: [&'static str; 6] = [
+        "4c756477696720766f6e204d69736573",
+        "4d757272617920526f746862617264",
+        "462e20412e20486179656b",
+        "4361726c204d656e676572",
+        "4a65616e2d426170746973746520536179",
+        "457567656e2042f6686d20766f6e2042617765726b",
+    ];
+
+    fn hex(s: &str) -> Vec<u8> {
+        s.as_bytes()
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(::std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn key_pair(private_key: &str) -> KeyPair {
+        KeyPair::from_private_key(&hex(private_key)).unwrap()
+    }
+
+    fn handshake(pattern: NoisePattern, suite: CipherSuite, prologue: &[u8], initiator_static: Option<Arc<KeyPair>>, responder_static: Arc<KeyPair>) -> (HandshakeState, HandshakeState) {
+        let remote_static = if pattern.needs_remote_static() {
+            Some(responder_static.public_key_bytes().to_vec())
+        } else {
+            None
+        };
+        let initiator = HandshakeState::initiator(pattern, suite, prologue, initiator_static, remote_static).unwrap();
+        let responder = HandshakeState::responder(pattern, suite, prologue, responder_static).unwrap();
+
+        (initiator, responder)
+    }
+
+    /// Runs a cacophony vector: the handshake messages, the handshake hash
+    /// and then the transport messages, which must match byte for byte.
+    fn check_vector(pattern: NoisePattern, suite: CipherSuite, handshake_hash: &str, ciphertexts: &[&str]) {
+        let responder_static = Arc::new(key_pair(RESP_STATIC));
+        assert_eq!(responder_static.public_key_bytes(), &hex(RESP_STATIC_PUBLIC)[..]);
+        let initiator_static = if pattern.authenticates_initiator() {
+            Some(Arc::new(key_pair(INIT_STATIC)))
+        } else {
+            None
+        };
+
+        let (mut initiator, mut responder) = handshake(pattern, suite, &hex(PROLOGUE), initiator_static, responder_static);
+        initiator.e = Some(key_pair(INIT_EPHEMERAL));
+        responder.e = Some(key_pair(RESP_EPHEMERAL));
+
+        let handshake_messages = pattern.message_count();
+        for i in 0..handshake_messages {
+            let (writer, reader) = if i % 2 == 0 { (&mut initiator, &mut responder) } else { (&mut responder, &mut initiator) };
+            let message = writer.write_message(&hex(PAYLOADS[i])).unwrap();
+            assert_eq!(message, hex(ciphertexts[i]), "handshake message {}", i);
+            assert_eq!(reader.read_message(&message).unwrap(), hex(PAYLOADS[i]));
+        }
+
+        assert!(initiator.is_finished() && responder.is_finished());
+        assert_eq!(initiator.handshake_hash(), &hex(handshake_hash)[..]);
+        assert_eq!(responder.handshake_hash(), &hex(handshake_hash)[..]);
+
+        let mut client = initiator.into_handler(Role::Client).unwrap();
+        let mut server = responder.into_handler(Role::Server).unwrap();
+        for i in handshake_messages..PAYLOADS.len() {
+            let (sender, receiver) = if i % 2 == 0 { (&mut client, &mut server) } else { (&mut server, &mut client) };
+            let (seq, sealed) = sender.seal_data(b"", &hex(PAYLOADS[i])).unwrap();
+            assert_eq!(sealed, hex(ciphertexts[i]), "transport message {}", i);
+            assert_eq!(receiver.open_data(b"", seq, sealed).unwrap(), hex(PAYLOADS[i]));
+        }
+    }
+
+    #[test]
+    fn matches_nk_vector() {
+        check_vector(NoisePattern::NK, CipherSuite::ChaCha20Poly1305, "2efa38a9c7c93ac98f3a097af25c2f58b9e7673787717bc27e98827118c2c1a5", &[
+            "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c79448134d00711fdb390a0d178fa008f6d47d2891e5ea18ae136c3b4c23ac384efb0",
+            "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f1448088438ea16e3701bc0d77744f117bee22451c9afa7f4cdbbcff00c04a8ee0913c88",
+            "a62de29ce27cb80245d440d986ed816c156e9d757d7008df2198b0",
+            "174a35f11c689f4530d7208618e0564ae12f2f50ba8eb4df5382ff",
+            "337e475ebb8eae60f91974c4e455a5af38d1d8628d1803b160d60442874b0a1777",
+            "047e80e060b7bb08b53c5a23dfe9920cae135b9d1dc6302fc475003062723700366346ac9d",
+        ]);
+    }
+
+    #[test]
+    fn matches_xx_vector() {
+        check_vector(NoisePattern::XX, CipherSuite::ChaCha20Poly1305, "c8e5f64e846193be2a834104c2a009868d6c9f3bd3c186299888b488b2f1f58e", &[
+            "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c79444c756477696720766f6e204d69736573",
+            "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f14480884381cbad1f276e038c48378ffce2b65285e08d6b68aaa3629a5a8639392490e5b9bd5269c2f1e4f488ed8831161f19b7815528f8982ffe09be9b5c412f8a0db50f8814c7194e83f23dbd8d162c9326ad",
+            "c7195ffacac1307ff99046f219750fc47693e23c3cb08b89c2af808b444850a80ae475b9df0f169ae80a89be0865b57f58c9fea0d4ec82a286427402f113e4b6ae769a1d95941d49b25030",
+            "96763ed773f8e47bb3712f0e29b3060ffc956ffc146cee53d5e1df",
+            "3e40f15f6f3a46ae446b253bf8b1d9ffb6ed9b174d272328ff91a7e2e5c79c07f5",
+            "eb3f3515110702e047a6c9da4478b6ead94873c11c0f2d710ddb3f09fce024b3a58502ae3f",
+        ]);
+    }
+
+    #[test]
+    fn matches_xx_aesgcm_vector() {
+        check_vector(NoisePattern::XX, CipherSuite::Aes256Gcm, "1b7aefb1125762aa21a252890d00af54519638b76437444538f9a52f21e2e0dc", &[
+            "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c79444c756477696720766f6e204d69736573",
+            "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f144808843757117acceb05bd7a45733bc22015c97a9d0cbaf41b80446d5988ff5127235d76b79eade70f473d6a4ef521fdcbeda5340d01e028ba793fc059f2724a83af05f12dda0448a7621a926b379a92477fd",
+            "c90f1cf77eba4e50edb038991565e36c9758943a989229b6051244dc4fbecb6946744b401af2ee1a5881b65fbb87fd07cb6a328ececc9ce6ce84c399dc332d4fd521fa4bb7f467ce909395",
+            "bc3fa77f6aca3e8466d7dc6bea10013e88a6a29add5132b461806c",
+            "250b01074cdfe0df2ecf8ccbf1737b15a2ddb5b52fd9a396604e9c793cee3b3bb9",
+            "449d4d433b3cdc3d02bf6fc881774b9df54366ebcffb9689bb13f14709822cd7ef42bcdb4d",
+        ]);
+    }
+
+    #[test]
+    fn matches_ik_vector() {
+        check_vector(NoisePattern::IK, CipherSuite::ChaCha20Poly1305, "0b0f68fb0c27e03ce9b97565995ed4838cc0581b762ef72b062f6a546419fad7", &[
+            "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c7944718da798efbcd91528520204f904b9bd6c7413dccdc214d951e15253e39987f18146e8cd0873654207148333479d4d16c289f0294b29960a72f48e0b7bba2e89083169825e59642148d492020664ccf7",
+            "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f1448088435361e70b2ed446e6c9ec387d1d6b3b840f194e373979d241b203c4acafccf5",
+            "050e9f3c8fac16b68dbce8f8c4bfbf6617c897f9ada4aa29aa19c8",
+            "344233a6cabb7141d80f3da2fedc311d9646bbb0f505afe403a667",
+            "62cdeeb172ad7ade7aa7d9e069da5790f12331bfa00177787a1d0810c67dc3b2b4",
+            "029bead1b40992327044d409d9a1f3ad8f36c3c452775d557e18bbeb2e8dfcead32d514024",
+        ]);
+    }
+
+    #[test]
+    fn round_trips_every_pattern() {
+        for &pattern in &[NoisePattern::NK, NoisePattern::XX, NoisePattern::IK] {
+            let client_static = Arc::new(KeyPair::generate().unwrap());
+            let server_static = Arc::new(KeyPair::generate().unwrap());
+            let initiator_static = if pattern.authenticates_initiator() { Some(client_static.clone()) } else { None };
+            let (mut initiator, mut responder) = handshake(pattern, CipherSuite::ChaCha20Poly1305, b"prologue", initiator_static, server_static.clone());
+
+            while !initiator.is_finished() {
+                let (writer, reader) = if initiator.is_my_turn() { (&mut initiator, &mut responder) } else { (&mut responder, &mut initiator) };
+                let message = writer.write_message(b"payload").unwrap();
+                assert_eq!(reader.read_message(&message).unwrap(), b"payload");
+            }
+
+            assert!(responder.is_finished());
+            assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+            assert_eq!(initiator.remote_static(), Some(server_static.public_key_bytes()));
+            if pattern.authenticates_initiator() {
+                assert_eq!(responder.remote_static(), Some(client_static.public_key_bytes()));
+            } else {
+                assert_eq!(responder.remote_static(), None);
+            }
+
+            let mut client = initiator.into_handler(Role::Client).unwrap();
+            let mut server = responder.into_handler(Role::Server).unwrap();
+            let (seq, sealed) = client.seal_data(b"", b"ping").unwrap();
+            assert_eq!(server.open_data(b"", seq, sealed).unwrap(), b"ping");
+            let (seq, sealed) = server.seal_data(b"", b"pong").unwrap();
+            assert_eq!(client.open_data(b"", seq, sealed).unwrap(), b"pong");
+            assert_eq!(&client.export(b"label", b"", 32)[..], &server.export(b"label", b"", 32)[..]);
+
+            client.rekey_sealer().unwrap();
+            server.rekey_opener().unwrap();
+            let (seq, sealed) = client.seal_data(b"", b"after rekey").unwrap();
+            assert_eq!(server.open_data(b"", seq, sealed).unwrap(), b"after rekey");
+        }
+    }
+
+    #[test]
+    fn refuses_messages_out_of_turn() {
+        let (mut initiator, mut responder) = handshake(NoisePattern::XX, CipherSuite::ChaCha20Poly1305, b"", Some(Arc::new(KeyPair::generate().unwrap())), Arc::new(KeyPair::generate().unwrap()));
+
+        match responder.write_message(b"") {
+            Err(Error::HandshakeOutOfTurn) => (),
+            other => panic!("expected HandshakeOutOfTurn, got {:?}", other),
+        }
+        let message = initiator.write_message(b"").unwrap();
+        match initiator.read_message(&message) {
+            Err(Error::HandshakeOutOfTurn) => (),
+            other => panic!("expected HandshakeOutOfTurn, got {:?}", other),
+        }
+        responder.read_message(&message).unwrap();
+        match responder.read_message(&message) {
+            Err(Error::HandshakeOutOfTurn) => (),
+            other => panic!("expected HandshakeOutOfTurn, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_truncated_messages() {
+        let (mut initiator, mut responder) = handshake(NoisePattern::NK, CipherSuite::ChaCha20Poly1305, b"", None, Arc::new(KeyPair::generate().unwrap()));
+
+        let message = initiator.write_message(b"").unwrap();
+        match responder.read_message(&message[..16]) {
+            Err(Error::TruncatedHandshake) => (),
+            other => panic!("expected TruncatedHandshake, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_missing_static_keys() {
+        let server_key = Arc::new(KeyPair::generate().unwrap());
+
+        match HandshakeState::initiator(NoisePattern::XX, CipherSuite::ChaCha20Poly1305, b"", None, None) {
+            Err(Error::MissingStaticKey(_)) => (),
+            other => panic!("expected MissingStaticKey, got {:?}", other.map(|_| ())),
+        }
+        match HandshakeState::initiator(NoisePattern::NK, CipherSuite::ChaCha20Poly1305, b"", None, None) {
+            Err(Error::MissingStaticKey(_)) => (),
+            other => panic!("expected MissingStaticKey, got {:?}", other.map(|_| ())),
+        }
+        assert!(HandshakeState::initiator(NoisePattern::NK, CipherSuite::ChaCha20Poly1305, b"", None, Some(server_key.public_key_bytes().to_vec())).is_ok());
+    }
+
+    #[test]
+    fn refuses_unfinished_handshakes() {
+        let (initiator, _) = handshake(NoisePattern::NK, CipherSuite::ChaCha20Poly1305, b"", None, Arc::new(KeyPair::generate().unwrap()));
+
+        match initiator.into_handler(Role::Client) {
+            Err(Error::HandshakeIncomplete) => (),
+            other => panic!("expected HandshakeIncomplete, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn hkdf_matches_rfc_5869() {
+        let chaining_key = [1u8; 32];
+        let input_key_material = [2u8; 32];
+        let (output1, output2) = noise_hkdf(&chaining_key, &input_key_material);
+
+        let salt = hmac::SigningKey::new(&digest::SHA256, &chaining_key);
+        let prk = hkdf::extract(&salt, &input_key_material);
+        let mut expected = [0u8; 64];
+        hkdf::expand(&prk, &[], &mut expected);
+
+        assert_eq!(&output1[..], &expected[..32]);
+        assert_eq!(&output2[..], &expected[32..]);
+    }
+
+    #[test]
+    fn encodes_nonces_per_cipher() {
+        let n = 0x0102030405060708;
+
+        assert_eq!(nonce(CipherSuite::ChaCha20Poly1305, n), [0, 0, 0, 0, 8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(nonce(CipherSuite::Aes256Gcm, n), [0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+}
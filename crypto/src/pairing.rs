@@ -0,0 +1,333 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ::ring::{digest, hkdf, hmac, rand};
+use ::ring::rand::SecureRandom;
+use ::spake2::{Ed25519Group, Identity, Password, SPAKE2};
+
+use aead::Role;
+use errors::Error;
+use secret::SecretBytes;
+
+/// How long a pairing code is accepted for unless the server picks otherwise.
+pub const DEFAULT_CODE_LIFETIME: u64 = 10 * 60;
+
+/// How many pairing attempts a code allows unless the server picks otherwise.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+/// Characters in a pairing code, not counting the separator.
+pub const CODE_LEN: usize = 8;
+
+/// Crockford's base32 alphabet, which leaves out letters easily mistaken for
+/// digits.
+const CODE_ALPHABET: &'static [u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+const CLIENT_IDENTITY: &'static [u8] = b"libcart pairing client";
+const SERVER_IDENTITY: &'static [u8] = b"libcart pairing server";
+const CLIENT_CONFIRMATION: &'static [u8] = b"client confirmation";
+const SERVER_CONFIRMATION: &'static [u8] = b"server confirmation";
+
+/// A one-time code an operator reads off a server and types into a client,
+/// so the two can exchange long-term keys without either knowing the other's
+/// up front.
+///
+/// The code is only ever used as a SPAKE2 password, so an eavesdropper
+/// learns nothing from a pairing, and an attacker gets one guess per
+/// connection. Every connection that tries the code counts as an attempt,
+/// and once `max_attempts` is reached or the code expires it is refused even
+/// if entered correctly. A successful pairing uses the code up.
+pub struct PairingCode {
+    code: String,
+    expires: Instant,
+    state: Mutex<PairingState>,
+}
+
+struct PairingState {
+    attempts_left: u32,
+    used: bool,
+}
+
+impl PairingCode {
+    /// A random code accepted for `lifetime` and at most `max_attempts`
+    /// times.
+    pub fn generate(lifetime: Duration, max_attempts: u32) -> Result<PairingCode, Error> {
+        let rng = rand::SystemRandom::new();
+        let mut random = [0u8; CODE_LEN];
+        rng.fill(&mut random).map_err(|_| Error::RandomFailure)?;
+
+        let code = random.iter()
+            .map(|byte| CODE_ALPHABET[(*byte as usize) % CODE_ALPHABET.len()] as char)
+            .collect();
+
+        Ok(PairingCode {
+            code: code,
+            expires: Instant::now() + lifetime,
+            state: Mutex::new(PairingState {
+                attempts_left: max_attempts,
+                used: false,
+            }),
+        })
+    }
+
+    /// The code to show the operator, e.g. `7KQ2-M9XD`.
+    pub fn display(&self) -> String {
+        format!("{}-{}", &self.code[..CODE_LEN / 2], &self.code[CODE_LEN / 2..])
+    }
+
+    /// Counts an attempt against the code and starts the server side of the
+    /// exchange, or refuses if the code has expired, been used or run out of
+    /// attempts.
+    pub fn start(&self) -> Result<PairingExchange, Error> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return Err(Error::LockPoisoned("pairing state")),
+        };
+
+        if state.used || Instant::now() >= self.expires {
+            return Err(Error::PairingExpired);
+        }
+        if state.attempts_left == 0 {
+            return Err(Error::PairingAttemptsExhausted);
+        }
+        state.attempts_left -= 1;
+
+        Ok(PairingExchange::start(Role::Server, &self.code))
+    }
+
+    /// Uses the code up once a pairing with it has succeeded.
+    pub fn complete(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.used = true;
+        }
+    }
+}
+
+impl fmt::Debug for PairingCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PairingCode([REDACTED])")
+    }
+}
+
+/// One side of a SPAKE2 exchange keyed by a pairing code.
+pub struct PairingExchange {
+    role: Role,
+    state: SPAKE2<Ed25519Group>,
+    message: Vec<u8>,
+}
+
+impl PairingExchange {
+    /// Starts the client side with the code the operator entered. Case,
+    /// spaces and dashes are ignored.
+    pub fn client(code: &str) -> PairingExchange {
+        PairingExchange::start(Role::Client, &normalize_code(code))
+    }
+
+    fn start(role: Role, code: &str) -> PairingExchange {
+        let password = Password::new(code.as_bytes());
+        let client = Identity::new(CLIENT_IDENTITY);
+        let server = Identity::new(SERVER_IDENTITY);
+
+        let (state, message) = match role {
+            Role::Client => SPAKE2::<Ed25519Group>::start_a(&password, &client, &server),
+            Role::Server => SPAKE2::<Ed25519Group>::start_b(&password, &client, &server),
+        };
+
+        PairingExchange {
+            role: role,
+            state: state,
+            message: message,
+        }
+    }
+
+    /// The message to send to the peer.
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// Completes the exchange with the peer's message. A wrong code isn't
+    /// detected here; it surfaces when the peer's confirmation fails to
+    /// verify.
+    pub fn finish(self, peer_message: &[u8]) -> Result<PairingSession, Error> {
+        let mut transcript = Vec::with_capacity(self.message.len() + peer_message.len());
+        match self.role {
+            Role::Client => {
+                transcript.extend_from_slice(&self.message);
+                transcript.extend_from_slice(peer_message);
+            },
+            Role::Server => {
+                transcript.extend_from_slice(peer_message);
+                transcript.extend_from_slice(&self.message);
+            },
+        }
+
+        let key = match self.state.finish(peer_message) {
+            Ok(key) => SecretBytes::new(key),
+            Err(_) => return Err(Error::PairingFailed),
+        };
+
+        Ok(PairingSession {
+            role: self.role,
+            key: key,
+            transcript: transcript,
+        })
+    }
+}
+
+impl fmt::Debug for PairingExchange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PairingExchange {{ role: {:?} }}", self.role)
+    }
+}
+
+/// The key agreed by a pairing exchange, used to vouch for the long-term
+/// public keys each side sends.
+pub struct PairingSession {
+    role: Role,
+    key: SecretBytes,
+    transcript: Vec<u8>,
+}
+
+impl PairingSession {
+    /// Vouches for this side's long-term public key, or for having none if
+    /// `identity` is empty.
+    pub fn confirmation(&self, identity: &[u8]) -> Vec<u8> {
+        let key = self.confirmation_key(self.role);
+        hmac::sign(&key, &self.confirmed_message(identity)).as_ref().to_vec()
+    }
+
+    /// Checks the peer's `confirmation` for its long-term public key. Fails
+    /// with `Error::PairingFailed` if the peer used a different code.
+    pub fn verify_peer(&self, identity: &[u8], confirmation: &[u8]) -> Result<(), Error> {
+        let peer = match self.role {
+            Role::Client => Role::Server,
+            Role::Server => Role::Client,
+        };
+        let key = self.confirmation_key(peer);
+
+        match hmac::verify_with_own_key(&key, &self.confirmed_message(identity), confirmation) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(Error::PairingFailed),
+        }
+    }
+
+    fn confirmation_key(&self, role: Role) -> hmac::SigningKey {
+        let label = match role {
+            Role::Client => CLIENT_CONFIRMATION,
+            Role::Server => SERVER_CONFIRMATION,
+        };
+        let prk = hmac::SigningKey::new(&digest::SHA256, &self.key);
+        let mut key = SecretBytes::zeroed(digest::SHA256_OUTPUT_LEN);
+        hkdf::expand(&prk, label, &mut key);
+
+        hmac::SigningKey::new(&digest::SHA256, &key)
+    }
+
+    fn confirmed_message(&self, identity: &[u8]) -> Vec<u8> {
+        let mut msg = self.transcript.clone();
+        msg.extend_from_slice(identity);
+        msg
+    }
+}
+
+impl fmt::Debug for PairingSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PairingSession {{ role: {:?}, key: [REDACTED] }}", self.role)
+    }
+}
+
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .flat_map(|c| c.to_uppercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use errors::Error;
+    use super::{PairingCode, PairingExchange, PairingSession};
+
+    const SERVER_KEY: &'static [u8] = b"server key";
+
+    /// Runs both sides of an exchange, the client with `entered`.
+    fn exchange(code: &PairingCode, entered: &str) -> Result<(PairingSession, PairingSession), Error> {
+        let server = code.start()?;
+        let client = PairingExchange::client(entered);
+        let server_message = server.message().to_vec();
+        let client_message = client.message().to_vec();
+
+        Ok((client.finish(&server_message)?, server.finish(&client_message)?))
+    }
+
+    fn lifetime() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    #[test]
+    fn pairs_with_the_right_code() {
+        let code = PairingCode::generate(lifetime(), 3).unwrap();
+        let entered = code.display().to_lowercase();
+        let (client, server) = exchange(&code, &entered).unwrap();
+
+        client.verify_peer(SERVER_KEY, &server.confirmation(SERVER_KEY)).unwrap();
+        server.verify_peer(&[], &client.confirmation(&[])).unwrap();
+    }
+
+    #[test]
+    fn rejects_the_wrong_code() {
+        let code = PairingCode::generate(lifetime(), 3).unwrap();
+        let (client, server) = exchange(&code, "0000-0000").unwrap();
+
+        match client.verify_peer(SERVER_KEY, &server.confirmation(SERVER_KEY)) {
+            Err(Error::PairingFailed) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match server.verify_peer(&[], &client.confirmation(&[])) {
+            Err(Error::PairingFailed) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_a_substituted_key() {
+        let code = PairingCode::generate(lifetime(), 3).unwrap();
+        let entered = code.display();
+        let (client, server) = exchange(&code, &entered).unwrap();
+
+        match client.verify_peer(b"other key", &server.confirmation(SERVER_KEY)) {
+            Err(Error::PairingFailed) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn limits_attempts() {
+        let code = PairingCode::generate(lifetime(), 2).unwrap();
+        code.start().unwrap();
+        code.start().unwrap();
+
+        match code.start() {
+            Err(Error::PairingAttemptsExhausted) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_used_and_expired_codes() {
+        let code = PairingCode::generate(lifetime(), 3).unwrap();
+        code.complete();
+        match code.start() {
+            Err(Error::PairingExpired) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let code = PairingCode::generate(Duration::from_secs(0), 3).unwrap();
+        match code.start() {
+            Err(Error::PairingExpired) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
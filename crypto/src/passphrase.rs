@@ -0,0 +1,248 @@
+use std::env;
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
+use std::io::prelude::*;
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::io::{RawFd, FromRawFd};
+
+use ::ring::{aead, rand};
+use ::ring::rand::SecureRandom;
+use ::scrypt::{scrypt, ScryptParams};
+
+use errors::Error;
+use secret::{SecretBytes, zeroize};
+
+const FORMAT_VERSION: u8 = 1;
+const KDF_SCRYPT: u8 = 1;
+
+// 2^15 iterations with r = 8 needs 32 MiB per derivation.
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+// Upper bounds on the parameters accepted from a key file, which are read
+// before anything is authenticated: 2^20 with r = 8 is 1 GiB.
+const MAX_SCRYPT_LOG_N: u8 = 20;
+const MAX_SCRYPT_R: u32 = 8;
+const MAX_SCRYPT_P: u32 = 1;
+
+const SALT_LEN: usize = 16;
+// version + kdf + log_n + r + p + salt + nonce
+const HEADER_LEN: usize = 1 + 1 + 1 + 4 + 4 + SALT_LEN + 12;
+
+/// Where to get the passphrase for an encrypted private key.
+pub enum PassphraseSource {
+    /// Asks the caller, e.g. by prompting on a terminal.
+    Callback(Box<Fn() -> Result<Vec<u8>, Error>>),
+    /// Reads the named environment variable.
+    Env(String),
+    /// Reads the first line from an inherited file descriptor. The descriptor
+    /// is left open.
+    #[cfg(unix)]
+    Fd(RawFd),
+}
+
+impl PassphraseSource {
+    pub fn read(&self) -> Result<SecretBytes, Error> {
+        match *self {
+            PassphraseSource::Callback(ref callback) => callback().map(SecretBytes::new),
+            PassphraseSource::Env(ref name) => {
+                match env::var(name) {
+                    Ok(value) => Ok(SecretBytes::new(value.into_bytes())),
+                    Err(_) => Err(Error::PassphraseUnavailable(format!("environment variable {} is not set", name))),
+                }
+            },
+            #[cfg(unix)]
+            PassphraseSource::Fd(fd) => read_passphrase_fd(fd),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn read_passphrase_fd(fd: RawFd) -> Result<SecretBytes, Error> {
+    let f = unsafe { File::from_raw_fd(fd) };
+    let mut buf = Vec::new();
+    // a byte at a time, so nothing past the first line is consumed and a
+    // writer that keeps the descriptor open doesn't block us
+    let mut result = Ok(());
+    for byte in (&f).bytes() {
+        match byte {
+            Ok(b'\n') => break,
+            Ok(byte) => buf.push(byte),
+            Err(err) => {
+                result = Err(err);
+                break;
+            },
+        }
+    }
+    // the descriptor belongs to whoever handed it to us
+    mem::forget(f);
+    if let Err(err) = result {
+        zeroize(&mut buf);
+        return Err(Error::from(err));
+    }
+
+    if buf.last() == Some(&b'\r') {
+        buf.pop();
+    }
+
+    Ok(SecretBytes::new(buf))
+}
+
+/// Encrypts a PKCS#8 private key under `passphrase`.
+///
+/// The key is sealed with ChaCha20-Poly1305 under a key derived with scrypt.
+/// The output is a header (format version, KDF id, scrypt parameters, salt and
+/// nonce), which is authenticated, followed by the ciphertext and tag.
+pub fn seal_private_key(pkcs8: &[u8], passphrase: &[u8]) -> Result<Vec<u8>, Error> {
+    let rng = rand::SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    rng.fill(&mut salt).map_err(|_| Error::RandomFailure)?;
+    let mut nonce = [0u8; 12];
+    rng.fill(&mut nonce).map_err(|_| Error::RandomFailure)?;
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.push(FORMAT_VERSION);
+    header.push(KDF_SCRYPT);
+    header.push(SCRYPT_LOG_N);
+    header.extend_from_slice(&be_u32(SCRYPT_R));
+    header.extend_from_slice(&be_u32(SCRYPT_P));
+    header.extend_from_slice(&salt);
+    header.extend_from_slice(&nonce);
+    debug_assert!(header.len() == HEADER_LEN);
+
+    let key = derive_key(passphrase, &salt, SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P)?;
+    let key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &key)?;
+    let tag_len = aead::CHACHA20_POLY1305.tag_len();
+
+    let mut sealed = pkcs8.to_vec();
+    sealed.resize(pkcs8.len() + tag_len, 0);
+    aead::seal_in_place(&key, &nonce, &header, &mut sealed, tag_len)?;
+
+    header.append(&mut sealed);
+    Ok(header)
+}
+
+/// Reverses `seal_private_key`. A passphrase that doesn't open the key is
+/// reported as `Error::WrongPassphrase`.
+pub fn open_private_key(sealed: &[u8], passphrase: &[u8]) -> Result<SecretBytes, Error> {
+    if sealed.len() < HEADER_LEN {
+        return Err(Error::InvalidKeyFile("encrypted key is truncated".to_string()));
+    }
+    if sealed[0] != FORMAT_VERSION || sealed[1] != KDF_SCRYPT {
+        return Err(Error::InvalidKeyFile("unsupported encrypted key format".to_string()));
+    }
+
+    let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+    let log_n = header[2];
+    let r = read_be_u32(&header[3..7]);
+    let p = read_be_u32(&header[7..11]);
+    let salt = &header[11..11 + SALT_LEN];
+    let nonce = &header[11 + SALT_LEN..];
+    if log_n > MAX_SCRYPT_LOG_N || r > MAX_SCRYPT_R || p > MAX_SCRYPT_P {
+        return Err(Error::InvalidKeyFile("scrypt parameters are too expensive".to_string()));
+    }
+
+    let key = derive_key(passphrase, salt, log_n, r, p)?;
+    let key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &key)?;
+
+    let mut buf = ciphertext.to_vec();
+    let pkcs8 = match aead::open_in_place(&key, nonce, header, 0, &mut buf) {
+        Ok(plaintext) => Ok(SecretBytes::from_slice(plaintext)),
+        Err(_) => Err(Error::WrongPassphrase),
+    };
+    zeroize(&mut buf);
+
+    pkcs8
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8], log_n: u8, r: u32, p: u32) -> Result<SecretBytes, Error> {
+    let params = match ScryptParams::new(log_n, r, p) {
+        Ok(params) => params,
+        Err(_) => return Err(Error::InvalidKeyFile("invalid scrypt parameters".to_string())),
+    };
+
+    let mut key = SecretBytes::zeroed(aead::CHACHA20_POLY1305.key_len());
+    if let Err(_) = scrypt(passphrase, salt, &params, &mut key) {
+        return Err(Error::KeyDerivationFailed);
+    }
+
+    Ok(key)
+}
+
+fn be_u32(val: u32) -> [u8; 4] {
+    [(val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8, val as u8]
+}
+
+fn read_be_u32(buf: &[u8]) -> u32 {
+    buf.iter().fold(0, |acc, &b| (acc << 8) | b as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use errors::Error;
+    use super::{open_private_key, seal_private_key, be_u32, FORMAT_VERSION, KDF_SCRYPT, SALT_LEN};
+
+    const PKCS8: &'static [u8] = b"not really a PKCS#8 private key";
+
+    /// An encrypted key with the given scrypt parameters and a garbage body.
+    fn with_params(log_n: u8, r: u32, p: u32) -> Vec<u8> {
+        let mut sealed = vec![FORMAT_VERSION, KDF_SCRYPT, log_n];
+        sealed.extend_from_slice(&be_u32(r));
+        sealed.extend_from_slice(&be_u32(p));
+        sealed.extend_from_slice(&[0; SALT_LEN + 12 + 16]);
+
+        sealed
+    }
+
+    #[test]
+    fn round_trip() {
+        let sealed = seal_private_key(PKCS8, b"correct horse").unwrap();
+        assert_eq!(&open_private_key(&sealed, b"correct horse").unwrap()[..], PKCS8);
+
+        match open_private_key(&sealed, b"battery staple") {
+            Err(Error::WrongPassphrase) => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("opened a key with the wrong passphrase"),
+        }
+    }
+
+    #[test]
+    fn refuses_expensive_scrypt_parameters() {
+        for &(log_n, r, p) in &[(21, 8, 1), (15, 9, 1), (15, 8, 2)] {
+            match open_private_key(&with_params(log_n, r, p), b"passphrase") {
+                Err(Error::InvalidKeyFile(_)) => {},
+                Err(err) => panic!("unexpected error for log_n={} r={} p={}: {}", log_n, r, p, err),
+                Ok(_) => panic!("accepted log_n={} r={} p={}", log_n, r, p),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reads_first_line_from_fd() {
+        use std::env;
+        use std::fs::{self, File};
+        use std::io::{Read, Write};
+        use std::os::unix::io::AsRawFd;
+        use std::process;
+        use super::PassphraseSource;
+
+        let path = env::temp_dir().join(format!("libcart-passphrase-{}", process::id()));
+        let mut f = File::create(&path).unwrap();
+        f.write_all(b"passphrase\r\nrest of the file\n").unwrap();
+        let mut f = File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let passphrase = PassphraseSource::Fd(f.as_raw_fd()).read().unwrap();
+        assert_eq!(&passphrase[..], b"passphrase");
+
+        // the descriptor is still open and positioned just past the newline
+        let mut rest = String::new();
+        f.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "rest of the file\n");
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+
+use ::base64::decode;
+use ::ring::{digest, hkdf, hmac};
+use errors::Error;
+use secret::{SecretBytes, zeroize};
+
+/// The shortest pre-shared key accepted, in bytes.
+pub const MIN_PSK_LEN: usize = 32;
+
+const BINDER_LABEL: &'static [u8] = b"libcart psk binder";
+
+/// A symmetric key both peers were given out of band, and the identity the
+/// client uses to tell the server which one it holds.
+#[derive(Clone)]
+pub struct PreSharedKey {
+    identity: String,
+    key: SecretBytes,
+}
+
+impl PreSharedKey {
+    pub fn new(identity: &str, key: Vec<u8>) -> Result<PreSharedKey, Error> {
+        if identity.is_empty() || identity.contains(char::is_whitespace) {
+            return Err(Error::InvalidKey(format!("invalid PSK identity: {:?}", identity)));
+        }
+        if key.len() < MIN_PSK_LEN {
+            return Err(Error::InvalidKey(format!("PSK {} is shorter than {} bytes", identity, MIN_PSK_LEN)));
+        }
+
+        Ok(PreSharedKey {
+            identity: identity.to_string(),
+            key: SecretBytes::new(key),
+        })
+    }
+
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Proves knowledge of the key over a handshake transcript hash without
+    /// revealing it, so a server can reject a wrong key before replying.
+    pub fn binder(&self, transcript_hash: &[u8]) -> Vec<u8> {
+        hmac::sign(&self.binder_key(), transcript_hash).as_ref().to_vec()
+    }
+
+    /// Checks a binder produced by the peer's `binder`.
+    pub fn verify_binder(&self, transcript_hash: &[u8], binder: &[u8]) -> Result<(), Error> {
+        match hmac::verify_with_own_key(&self.binder_key(), transcript_hash, binder) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(Error::PskMismatch(self.identity.clone())),
+        }
+    }
+
+    fn binder_key(&self) -> hmac::SigningKey {
+        let prk = hmac::SigningKey::new(&digest::SHA256, &self.key);
+        let mut key = [0u8; digest::SHA256_OUTPUT_LEN];
+        hkdf::expand(&prk, BINDER_LABEL, &mut key);
+        let binder_key = hmac::SigningKey::new(&digest::SHA256, &key);
+        zeroize(&mut key);
+
+        binder_key
+    }
+}
+
+impl fmt::Debug for PreSharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PreSharedKey {{ identity: {:?} }}", self.identity)
+    }
+}
+
+/// The pre-shared keys a server knows, by identity.
+///
+/// On disk this is one `identity base64-key` pair per line. Blank lines and
+/// lines starting with `#` are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct PskTable {
+    keys: HashMap<String, PreSharedKey>,
+}
+
+impl PskTable {
+    pub fn new() -> PskTable {
+        PskTable {
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<PskTable, Error> {
+        debug!("Loading pre-shared keys: {}", path);
+        let mut f = File::open(path)?;
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)?;
+
+        PskTable::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<PskTable, Error> {
+        let mut table = PskTable::new();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let identity = fields.next().unwrap_or("");
+            let key = match fields.next() {
+                Some(key) => decode(key)?,
+                None => return Err(Error::InvalidKey(format!("missing key for PSK {}", identity))),
+            };
+
+            table.insert(PreSharedKey::new(identity, key)?);
+        }
+
+        debug!("Loaded {} pre-shared keys", table.len());
+        Ok(table)
+    }
+
+    /// Adds `psk`, replacing any key with the same identity.
+    pub fn insert(&mut self, psk: PreSharedKey) {
+        self.keys.insert(psk.identity.clone(), psk);
+    }
+
+    pub fn remove(&mut self, identity: &str) -> bool {
+        self.keys.remove(identity).is_some()
+    }
+
+    pub fn get(&self, identity: &str) -> Option<&PreSharedKey> {
+        self.keys.get(identity)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::base64::encode;
+
+    use errors::Error;
+    use super::{PreSharedKey, PskTable, MIN_PSK_LEN};
+
+    #[test]
+    fn parses_tables_with_comments_and_blank_lines() {
+        let contents = format!("# site keys\n\nalpha {}\n   \n  # indented comment\nbeta {} trailing\n",
+                               encode(&[1u8; MIN_PSK_LEN][..]), encode(&[2u8; 48][..]));
+        let table = PskTable::parse(&contents).unwrap();
+
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get("alpha").unwrap().key(), &[1u8; MIN_PSK_LEN][..]);
+        assert_eq!(table.get("beta").unwrap().key(), &[2u8; 48][..]);
+        assert!(table.get("gamma").is_none());
+    }
+
+    #[test]
+    fn refuses_short_keys() {
+        let contents = format!("alpha {}\n", encode(&[1u8; MIN_PSK_LEN - 1][..]));
+
+        match PskTable::parse(&contents) {
+            Err(Error::InvalidKey(_)) => (),
+            other => panic!("expected InvalidKey, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_missing_keys() {
+        match PskTable::parse("alpha\n") {
+            Err(Error::InvalidKey(_)) => (),
+            other => panic!("expected InvalidKey, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn verifies_binders() {
+        let psk = PreSharedKey::new("alpha", vec![1; MIN_PSK_LEN]).unwrap();
+        let other = PreSharedKey::new("alpha", vec![2; MIN_PSK_LEN]).unwrap();
+        let binder = psk.binder(b"transcript");
+
+        psk.verify_binder(b"transcript", &binder).unwrap();
+        match psk.verify_binder(b"other transcript", &binder) {
+            Err(Error::PskMismatch(ref identity)) => assert_eq!(identity, "alpha"),
+            other => panic!("expected PskMismatch, got {:?}", other),
+        }
+        match other.verify_binder(b"transcript", &binder) {
+            Err(Error::PskMismatch(_)) => (),
+            other => panic!("expected PskMismatch, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Wrappers that make values safe to log.
+//!
+//! Log sites in `crypto` and the codec pass anything that might be secret,
+//! plaintext or ciphertext through one of these instead of formatting it
+//! directly. By default only sizes and public key fingerprints are written.
+//! Building with the `insecure-log-secrets` feature makes the same log lines
+//! dump full contents, including session keys; never enable it in production.
+
+use std::fmt;
+
+use ::base64::encode;
+use keys::fingerprint;
+
+/// Whether this build dumps secrets into the logs.
+pub const LOGS_SECRETS: bool = cfg!(feature = "insecure-log-secrets");
+
+/// Formats as the length of `data` only.
+pub fn bytes(data: &[u8]) -> Bytes {
+    Bytes(data)
+}
+
+/// Formats as the fingerprint of `public_key`.
+pub fn key(public_key: &[u8]) -> Key {
+    Key(public_key)
+}
+
+/// Formats as a placeholder naming what was redacted.
+pub fn value<'a, T: fmt::Debug + ?Sized>(what: &'static str, value: &'a T) -> Value<'a, T> {
+    Value(what, value)
+}
+
+pub struct Bytes<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for Bytes<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if LOGS_SECRETS {
+            write!(f, "<{} bytes: {}>", self.0.len(), encode(self.0))
+        } else {
+            write!(f, "<{} bytes>", self.0.len())
+        }
+    }
+}
+
+pub struct Key<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for Key<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", fingerprint(self.0))
+    }
+}
+
+pub struct Value<'a, T: 'a + ?Sized>(&'static str, &'a T);
+
+impl<'a, T: fmt::Debug + ?Sized> fmt::Display for Value<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if LOGS_SECRETS {
+            write!(f, "{:?}", self.1)
+        } else {
+            write!(f, "<{}>", self.0)
+        }
+    }
+}
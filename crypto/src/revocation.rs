@@ -0,0 +1,346 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use ::pem;
+use ::ring::signature::Ed25519KeyPair;
+
+use errors::Error;
+use encoding::{Writer, Reader};
+use keys::fingerprint;
+use super::verify;
+
+const FORMAT_VERSION: u8 = 1;
+const SIGNATURE_LABEL: &'static [u8] = b"libcart revocation list";
+const REVOCATION_LIST_TAG: &'static str = "LIBCART REVOCATION LIST";
+
+/// How often, in seconds, a `RevocationStore` looks for a changed file.
+pub const RELOAD_INTERVAL: u64 = 5;
+
+/// A list of revoked key fingerprints (as produced by `keys::fingerprint`),
+/// signed by an authority key. `issued` is in seconds since the Unix epoch
+/// and orders successive lists from the same authority.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevocationList {
+    pub issued: u64,
+    pub authority_key: Vec<u8>,
+    pub fingerprints: Vec<String>,
+    pub signature: Vec<u8>,
+}
+
+impl RevocationList {
+    pub fn sign(authority: &Ed25519KeyPair, issued: u64, fingerprints: Vec<String>) -> RevocationList {
+        let mut list = RevocationList {
+            issued: issued,
+            authority_key: authority.public_key_bytes().to_vec(),
+            fingerprints: fingerprints,
+            signature: Vec::new(),
+        };
+        let signed = authority.sign(&list.signed_bytes());
+        list.signature = signed.as_ref().to_vec();
+
+        list
+    }
+
+    /// Decodes a list and checks that `authority_key` signed it.
+    pub fn from_bytes(bytes: &[u8], authority_key: &[u8]) -> Result<RevocationList, Error> {
+        let mut rdr = Reader::new(bytes);
+        if rdr.get_u8()? != FORMAT_VERSION {
+            return Err(Error::InvalidRevocationList("unsupported revocation list version".to_string()));
+        }
+
+        let issued = rdr.get_u64()?;
+        let list_authority = rdr.get_bytes()?.to_vec();
+        let mut fingerprints = Vec::new();
+        for _ in 0..rdr.get_u32()? {
+            fingerprints.push(rdr.get_str()?);
+        }
+        let signature = rdr.get_bytes()?.to_vec();
+        rdr.finish()?;
+
+        let list = RevocationList {
+            issued: issued,
+            authority_key: list_authority,
+            fingerprints: fingerprints,
+            signature: signature,
+        };
+
+        if &list.authority_key[..] != authority_key {
+            return Err(Error::InvalidRevocationList("signed by an unknown authority".to_string()));
+        }
+        if let Err(_) = verify(authority_key, &list.signed_bytes(), &list.signature) {
+            return Err(Error::InvalidRevocationList("bad authority signature".to_string()));
+        }
+
+        Ok(list)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        self.write_body(&mut w);
+        w.put_bytes(&self.signature);
+
+        w.into_vec()
+    }
+
+    pub fn load(path: &str, authority_key: &[u8]) -> Result<RevocationList, Error> {
+        let mut f = File::open(path)?;
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)?;
+
+        let p = match pem::parse(&contents) {
+            Ok(p) => p,
+            Err(_) => return Err(Error::InvalidRevocationList(format!("{} is not valid PEM", path))),
+        };
+        if p.tag != REVOCATION_LIST_TAG {
+            return Err(Error::InvalidRevocationList(format!("{} is not a revocation list", path)));
+        }
+
+        RevocationList::from_bytes(&p.contents, authority_key)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        let p = pem::Pem {
+            tag: String::from(REVOCATION_LIST_TAG),
+            contents: self.to_bytes(),
+        };
+
+        let mut f = File::create(path)?;
+        f.write_all(pem::encode(&p).as_bytes())?;
+        f.sync_all()?;
+
+        Ok(())
+    }
+
+    pub fn is_revoked(&self, public_key: &[u8]) -> bool {
+        let fp = fingerprint(public_key);
+        self.fingerprints.iter().any(|revoked| *revoked == fp)
+    }
+
+    fn write_body(&self, w: &mut Writer) {
+        w.put_u8(FORMAT_VERSION)
+            .put_u64(self.issued)
+            .put_bytes(&self.authority_key)
+            .put_u32(self.fingerprints.len() as u32);
+        for fp in &self.fingerprints {
+            w.put_str(fp);
+        }
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.put_raw(SIGNATURE_LABEL);
+        self.write_body(&mut w);
+
+        w.into_vec()
+    }
+}
+
+/// A revocation list on disk that is reloaded when the file changes.
+///
+/// `check` runs on every handshake, so the file is only looked at once every
+/// `RELOAD_INTERVAL` seconds; a replacement takes up to that long to apply.
+/// A replacement that fails to load, or that is older than the list already
+/// in use, is ignored with a warning so a bad or rolled-back file can't
+/// un-revoke keys.
+pub struct RevocationStore {
+    path: String,
+    authority_key: Vec<u8>,
+    state: Mutex<StoreState>,
+}
+
+struct StoreState {
+    modified: Option<SystemTime>,
+    last_checked: Instant,
+    list: RevocationList,
+}
+
+impl RevocationStore {
+    /// Loads the list at `path`, which must be signed by `authority_key`.
+    pub fn open(path: &str, authority_key: &[u8]) -> Result<RevocationStore, Error> {
+        let modified = modified_time(path);
+        let list = RevocationList::load(path, authority_key)?;
+        debug!("Loaded revocation list with {} entries", list.fingerprints.len());
+
+        Ok(RevocationStore {
+            path: path.to_string(),
+            authority_key: authority_key.to_vec(),
+            state: Mutex::new(StoreState {
+                modified: modified,
+                last_checked: Instant::now(),
+                list: list,
+            }),
+        })
+    }
+
+    /// Fails with `Error::KeyRevoked` if `public_key` is on the current list.
+    pub fn check(&self, public_key: &[u8]) -> Result<(), Error> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if state.last_checked.elapsed() >= Duration::from_secs(RELOAD_INTERVAL) {
+            self.reload(&mut state);
+        }
+
+        if state.list.is_revoked(public_key) {
+            Err(Error::KeyRevoked(fingerprint(public_key)))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn reload(&self, state: &mut StoreState) {
+        state.last_checked = Instant::now();
+
+        let modified = modified_time(&self.path);
+        if modified.is_some() && modified != state.modified {
+            match RevocationList::load(&self.path, &self.authority_key) {
+                Ok(ref list) if list.issued < state.list.issued => {
+                    warn!("Ignoring revocation list {} older than the one in use", self.path);
+                },
+                Ok(list) => {
+                    debug!("Reloaded revocation list with {} entries", list.fingerprints.len());
+                    state.list = list;
+                },
+                Err(err) => warn!("Unable to reload revocation list {}: {}", self.path, err),
+            }
+            state.modified = modified;
+        }
+    }
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::{Duration, Instant};
+
+    use ::ring::rand::SystemRandom;
+    use ::ring::signature::Ed25519KeyPair;
+    use ::untrusted;
+
+    use errors::Error;
+    use keys::fingerprint;
+    use super::{RevocationList, RevocationStore, RELOAD_INTERVAL};
+
+    const KEY: [u8; 32] = [1; 32];
+    const OTHER_KEY: [u8; 32] = [2; 32];
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8[..])).unwrap()
+    }
+
+    fn temp_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("libcart-revocation-{}-{}", process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Makes the next `check` look at the file again, as if it had changed
+    /// and `RELOAD_INTERVAL` had passed.
+    fn expire(store: &RevocationStore) {
+        let mut state = store.state.lock().unwrap();
+        state.modified = None;
+        state.last_checked = Instant::now() - Duration::from_secs(RELOAD_INTERVAL);
+    }
+
+    fn is_revoked(store: &RevocationStore, key: &[u8]) -> bool {
+        match store.check(key) {
+            Ok(()) => false,
+            Err(Error::KeyRevoked(ref fp)) if *fp == fingerprint(key) => true,
+            Err(err) => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn ignores_rolled_back_lists() {
+        let authority = key_pair();
+        let path = temp_path("rollback");
+        RevocationList::sign(&authority, 2, vec![fingerprint(&KEY)]).save(&path).unwrap();
+        let store = RevocationStore::open(&path, authority.public_key_bytes()).unwrap();
+
+        RevocationList::sign(&authority, 1, Vec::new()).save(&path).unwrap();
+        expire(&store);
+        let rolled_back = is_revoked(&store, &KEY);
+
+        RevocationList::sign(&authority, 3, vec![fingerprint(&OTHER_KEY)]).save(&path).unwrap();
+        expire(&store);
+        let replaced = (is_revoked(&store, &KEY), is_revoked(&store, &OTHER_KEY));
+        fs::remove_file(&path).unwrap();
+
+        assert!(rolled_back, "an older list un-revoked a key");
+        assert_eq!(replaced, (false, true));
+    }
+
+    #[test]
+    fn ignores_lists_that_fail_to_verify() {
+        let authority = key_pair();
+        let path = temp_path("unverified");
+        RevocationList::sign(&authority, 1, vec![fingerprint(&KEY)]).save(&path).unwrap();
+        let store = RevocationStore::open(&path, authority.public_key_bytes()).unwrap();
+
+        RevocationList::sign(&key_pair(), 2, Vec::new()).save(&path).unwrap();
+        expire(&store);
+        let other_authority = is_revoked(&store, &KEY);
+
+        fs::write(&path, b"not a revocation list").unwrap();
+        expire(&store);
+        let garbage = is_revoked(&store, &KEY);
+        fs::remove_file(&path).unwrap();
+
+        assert!(other_authority, "a list from another authority was loaded");
+        assert!(garbage, "an unreadable list was loaded");
+    }
+
+    #[test]
+    fn reloads_at_most_every_interval() {
+        let authority = key_pair();
+        let path = temp_path("throttle");
+        RevocationList::sign(&authority, 1, Vec::new()).save(&path).unwrap();
+        let store = RevocationStore::open(&path, authority.public_key_bytes()).unwrap();
+
+        RevocationList::sign(&authority, 2, vec![fingerprint(&KEY)]).save(&path).unwrap();
+        store.state.lock().unwrap().modified = None;
+        let before = is_revoked(&store, &KEY);
+
+        expire(&store);
+        let after = is_revoked(&store, &KEY);
+        fs::remove_file(&path).unwrap();
+
+        assert!(!before, "reloaded before the interval passed");
+        assert!(after, "didn't reload once the interval passed");
+    }
+
+    #[test]
+    fn refuses_lists_from_other_authorities() {
+        let authority = key_pair();
+        let other = key_pair();
+        let list = RevocationList::sign(&authority, 1, vec![fingerprint(&KEY)]);
+
+        match RevocationList::from_bytes(&list.to_bytes(), other.public_key_bytes()) {
+            Err(Error::InvalidRevocationList(ref message)) if message.contains("unknown authority") => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("accepted a list from another authority"),
+        }
+
+        // claiming to be from the trusted authority doesn't help without its
+        // signature
+        let mut forged = list.clone();
+        forged.authority_key = other.public_key_bytes().to_vec();
+        match RevocationList::from_bytes(&forged.to_bytes(), other.public_key_bytes()) {
+            Err(Error::InvalidRevocationList(ref message)) if message.contains("bad authority signature") => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("accepted a list with a forged authority"),
+        }
+
+        assert_eq!(RevocationList::from_bytes(&list.to_bytes(), authority.public_key_bytes()).unwrap(), list);
+    }
+}
@@ -0,0 +1,254 @@
+//! Anonymous sealed boxes: payloads encrypted to a recipient's X25519 public
+//! key, which only the holder of the matching private key can open, without
+//! the two ever talking.
+//!
+//! The sender generates an ephemeral X25519 key pair for every box and
+//! agrees a key with the recipient's key. The box is the envelope header
+//! followed by the payload sealed as an `aead` STREAM under that key:
+//!
+//! ```text
+//! u8 (version) + [u8; 32] (ephemeral public key) + <stream>
+//! ```
+//!
+//! The key is bound to the header and the recipient key, so a box can't be
+//! re-addressed. Nothing identifies the sender; anyone who knows the
+//! recipient key can seal a box to it.
+//!
+//! A libcart identity's recipient key is `keys::open_sealing_key`. Senders
+//! are handed it signed by the identity (`keys::signed_sealing_key`) and
+//! only seal to it once the signature checks out against the Ed25519 key
+//! they already trust, e.g. the one a client pins.
+
+use std::io::{self, Read, Write};
+
+use ::ring::{digest, hkdf, hmac};
+use ::ring::signature::Ed25519KeyPair;
+
+use aead::{CipherSuite, SealingWriter, OpeningReader, seal_stream, open_stream};
+use errors::Error;
+use secret::SecretBytes;
+use x25519::{self, KeyPair};
+use super::verify;
+
+const ENVELOPE_VERSION: u8 = 1;
+
+/// Length of the envelope header before the sealed stream.
+pub const ENVELOPE_HEADER_LEN: usize = 1 + x25519::KEY_LEN;
+
+const ENVELOPE_SALT: &'static [u8] = b"libcart sealed box";
+
+/// Length of a signed sealing key: the X25519 public key followed by the
+/// identity's Ed25519 signature over it.
+pub const SIGNED_SEALING_KEY_LEN: usize = x25519::KEY_LEN + 64;
+
+const SEALING_KEY_LABEL: &'static [u8] = b"libcart sealing key";
+
+/// Vouches for `sealing_key`, an X25519 public key, as the recipient key of
+/// `identity`.
+pub fn sign_sealing_key(identity: &Ed25519KeyPair, sealing_key: &[u8]) -> Vec<u8> {
+    let signed = identity.sign(&sealing_key_message(sealing_key));
+
+    let mut out = Vec::with_capacity(SIGNED_SEALING_KEY_LEN);
+    out.extend_from_slice(sealing_key);
+    out.extend_from_slice(signed.as_ref());
+    out
+}
+
+/// The X25519 key in `signed`, if `identity`, an Ed25519 public key, signed
+/// it with `sign_sealing_key`.
+pub fn verify_sealing_key<'a>(identity: &[u8], signed: &'a [u8]) -> Result<&'a [u8], Error> {
+    if signed.len() != SIGNED_SEALING_KEY_LEN {
+        return Err(Error::InvalidKey(format!("signed sealing key must be {} bytes", SIGNED_SEALING_KEY_LEN)));
+    }
+
+    let (sealing_key, signature) = signed.split_at(x25519::KEY_LEN);
+    verify(identity, &sealing_key_message(sealing_key), signature)?;
+
+    Ok(sealing_key)
+}
+
+fn sealing_key_message(sealing_key: &[u8]) -> Vec<u8> {
+    let mut msg = SEALING_KEY_LABEL.to_vec();
+    msg.extend_from_slice(sealing_key);
+    msg
+}
+
+/// Seals `plaintext` to the identity with Ed25519 public key `identity`.
+/// `sealing_key` is its signed sealing key, which is checked first.
+pub fn seal(suite: CipherSuite, identity: &[u8], sealing_key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut sealed = Vec::new();
+    seal_reader(suite, identity, sealing_key, &mut io::Cursor::new(plaintext), &mut sealed)?;
+
+    Ok(sealed)
+}
+
+/// Opens a box sealed by `seal` or `seal_reader` with the recipient's key.
+pub fn open(recipient: &KeyPair, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut plaintext = Vec::new();
+    open_reader(recipient, io::Cursor::new(sealed), &mut plaintext)?;
+
+    Ok(plaintext)
+}
+
+/// Seals everything read from `reader` to `identity` onto `writer`, a chunk
+/// at a time, returning the number of plaintext bytes sealed.
+pub fn seal_reader<R: Read, W: Write>(suite: CipherSuite, identity: &[u8], sealing_key: &[u8], reader: &mut R, mut writer: W) -> Result<u64, Error> {
+    let (header, key) = new_envelope(identity, sealing_key)?;
+    writer.write_all(&header)?;
+
+    seal_stream(suite, &key, reader, writer)
+}
+
+/// Opens a box read from `reader` onto `writer`, returning the number of
+/// plaintext bytes written. If this fails, whatever was written so far must
+/// be discarded.
+pub fn open_reader<R: Read, W: Write>(recipient: &KeyPair, mut reader: R, writer: &mut W) -> Result<u64, Error> {
+    let key = read_envelope(recipient, &mut reader)?;
+
+    open_stream(&key, reader, writer)
+}
+
+/// Writes the envelope header to `inner` and returns a writer that seals
+/// everything written to it to `identity`. Call `finish` on it once the
+/// whole payload is written.
+pub fn sealing_writer<W: Write>(suite: CipherSuite, identity: &[u8], sealing_key: &[u8], mut inner: W) -> Result<SealingWriter<W>, Error> {
+    let (header, key) = new_envelope(identity, sealing_key)?;
+    inner.write_all(&header)?;
+
+    SealingWriter::new(suite, &key, inner)
+}
+
+/// Reads the envelope header from `inner` and returns a reader that opens
+/// the rest of the box.
+pub fn opening_reader<R: Read>(recipient: &KeyPair, mut inner: R) -> Result<OpeningReader<R>, Error> {
+    let key = read_envelope(recipient, &mut inner)?;
+
+    Ok(OpeningReader::new(&key, inner))
+}
+
+/// A fresh envelope header for `identity` and the key its stream is sealed
+/// under.
+fn new_envelope(identity: &[u8], sealing_key: &[u8]) -> Result<(Vec<u8>, SecretBytes), Error> {
+    let recipient = verify_sealing_key(identity, sealing_key)?;
+
+    let ephemeral = KeyPair::generate()?;
+    let mut header = Vec::with_capacity(ENVELOPE_HEADER_LEN);
+    header.push(ENVELOPE_VERSION);
+    header.extend_from_slice(ephemeral.public_key_bytes());
+
+    let shared = ephemeral.agree(recipient)?;
+    let key = envelope_key(&shared, &header, recipient);
+
+    Ok((header, key))
+}
+
+fn read_envelope<R: Read>(recipient: &KeyPair, reader: &mut R) -> Result<SecretBytes, Error> {
+    let mut header = [0u8; ENVELOPE_HEADER_LEN];
+    if let Err(err) = reader.read_exact(&mut header) {
+        return match err.kind() {
+            io::ErrorKind::UnexpectedEof => Err(Error::InvalidStream("sealed box is too short".to_string())),
+            _ => Err(Error::from(err)),
+        };
+    }
+    if header[0] != ENVELOPE_VERSION {
+        return Err(Error::InvalidStream(format!("unsupported sealed box version {}", header[0])));
+    }
+
+    let shared = recipient.agree(&header[1..])?;
+    Ok(envelope_key(&shared, &header, recipient.public_key_bytes()))
+}
+
+fn envelope_key(shared: &[u8], header: &[u8], recipient: &[u8]) -> SecretBytes {
+    let salt = hmac::SigningKey::new(&digest::SHA256, ENVELOPE_SALT);
+    let mut info = header.to_vec();
+    info.extend_from_slice(recipient);
+
+    let mut key = SecretBytes::zeroed(digest::SHA256_OUTPUT_LEN);
+    hkdf::extract_and_expand(&salt, shared, &info, &mut key);
+
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use ::ring::rand::SystemRandom;
+    use ::ring::signature::Ed25519KeyPair;
+    use ::untrusted;
+
+    use aead::CipherSuite;
+    use errors::Error;
+    use x25519::KeyPair;
+    use super::{ENVELOPE_HEADER_LEN, open, seal, sign_sealing_key, verify_sealing_key};
+
+    fn identity() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8[..])).unwrap()
+    }
+
+    fn expect_unopenable(result: Result<Vec<u8>, Error>) {
+        match result {
+            Err(Error::InvalidStream(_)) => {},
+            other => panic!("expected InvalidStream, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let identity = identity();
+        let recipient = KeyPair::generate().unwrap();
+        let signed = sign_sealing_key(&identity, recipient.public_key_bytes());
+
+        for suite in CipherSuite::all() {
+            let sealed = seal(suite, identity.public_key_bytes(), &signed, b"payload").unwrap();
+            assert_eq!(open(&recipient, &sealed).unwrap(), b"payload");
+        }
+    }
+
+    #[test]
+    fn refuses_sealing_keys_signed_by_another_identity() {
+        let identity = identity();
+        let impostor = identity();
+        let recipient = KeyPair::generate().unwrap();
+        let signed = sign_sealing_key(&impostor, recipient.public_key_bytes());
+
+        match verify_sealing_key(identity.public_key_bytes(), &signed) {
+            Err(Error::BadSignature) => {},
+            other => panic!("expected BadSignature, got {:?}", other),
+        }
+        match seal(CipherSuite::ChaCha20Poly1305, identity.public_key_bytes(), &signed, b"payload") {
+            Err(Error::BadSignature) => {},
+            other => panic!("expected BadSignature, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_boxes_for_another_recipient() {
+        let identity = identity();
+        let recipient = KeyPair::generate().unwrap();
+        let other = KeyPair::generate().unwrap();
+        let signed = sign_sealing_key(&identity, recipient.public_key_bytes());
+        let sealed = seal(CipherSuite::ChaCha20Poly1305, identity.public_key_bytes(), &signed, b"payload").unwrap();
+
+        expect_unopenable(open(&other, &sealed));
+    }
+
+    #[test]
+    fn refuses_boxes_with_a_swapped_ephemeral_key() {
+        let identity = identity();
+        let recipient = KeyPair::generate().unwrap();
+        let signed = sign_sealing_key(&identity, recipient.public_key_bytes());
+        let mut sealed = seal(CipherSuite::ChaCha20Poly1305, identity.public_key_bytes(), &signed, b"payload").unwrap();
+
+        let replacement = KeyPair::generate().unwrap();
+        sealed[1..ENVELOPE_HEADER_LEN].copy_from_slice(replacement.public_key_bytes());
+
+        expect_unopenable(open(&recipient, &sealed));
+    }
+
+    #[test]
+    fn refuses_truncated_boxes() {
+        let recipient = KeyPair::generate().unwrap();
+
+        expect_unopenable(open(&recipient, &[1; ENVELOPE_HEADER_LEN - 1]));
+    }
+}
@@ -0,0 +1,195 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::sync::atomic::{self, Ordering};
+
+/// Overwrites `buf` with zeroes in a way the optimizer won't elide.
+pub fn zeroize(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+/// A heap buffer of secret bytes, such as a private key or traffic secret.
+///
+/// The buffer is wiped when dropped and its `Debug` output never includes the
+/// contents. With the `mlock` feature on Linux its pages are also locked so
+/// they can't be swapped to disk; failing to lock (e.g. because of
+/// `RLIMIT_MEMLOCK`) is logged and otherwise ignored. Page locks aren't
+/// counted, so dropping one secret unlocks a page it shares with another.
+///
+/// The length is fixed at construction so the buffer is never reallocated,
+/// which would leave an unwiped copy behind. Bytes a `SecretBytes` was built
+/// from are only wiped if the caller hands over the `Vec` itself.
+pub struct SecretBytes {
+    bytes: Vec<u8>,
+}
+
+impl SecretBytes {
+    /// Takes ownership of `bytes`, wiping the original if it has to be moved.
+    pub fn new(bytes: Vec<u8>) -> SecretBytes {
+        let mut bytes = bytes;
+        if bytes.capacity() != bytes.len() {
+            // move into an exact-sized allocation now, rather than letting a
+            // later reallocation leave a copy behind
+            let mut exact = Vec::with_capacity(bytes.len());
+            exact.extend_from_slice(&bytes);
+            zeroize(&mut bytes);
+            bytes = exact;
+        }
+
+        let secret = SecretBytes { bytes: bytes };
+        lock(&secret.bytes);
+
+        secret
+    }
+
+    pub fn zeroed(len: usize) -> SecretBytes {
+        SecretBytes::new(vec![0u8; len])
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> SecretBytes {
+        SecretBytes::new(bytes.to_vec())
+    }
+
+    /// Copies `bytes` and wipes the original, for secrets that arrive in a
+    /// fixed-size array.
+    pub fn take(bytes: &mut [u8]) -> SecretBytes {
+        let secret = SecretBytes::from_slice(bytes);
+        zeroize(bytes);
+
+        secret
+    }
+
+    /// A wiped, locked copy of the two secrets back to back.
+    pub fn concat(first: &[u8], second: &[u8]) -> SecretBytes {
+        let mut secret = SecretBytes::zeroed(first.len() + second.len());
+        secret[..first.len()].copy_from_slice(first);
+        secret[first.len()..].copy_from_slice(second);
+
+        secret
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl DerefMut for SecretBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+
+impl AsRef<[u8]> for SecretBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> SecretBytes {
+        SecretBytes::from_slice(&self.bytes)
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        zeroize(&mut self.bytes);
+        unlock(&self.bytes);
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretBytes([REDACTED; {} bytes])", self.bytes.len())
+    }
+}
+
+#[cfg(all(feature = "mlock", target_os = "linux"))]
+fn lock(bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+
+    let ret = unsafe { ::libc::mlock(bytes.as_ptr() as *const ::libc::c_void, bytes.len()) };
+    if ret != 0 {
+        debug!("unable to lock {} secret bytes in memory", bytes.len());
+    }
+}
+
+#[cfg(all(feature = "mlock", target_os = "linux"))]
+fn unlock(bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+
+    unsafe { ::libc::munlock(bytes.as_ptr() as *const ::libc::c_void, bytes.len()) };
+}
+
+#[cfg(not(all(feature = "mlock", target_os = "linux")))]
+fn lock(_: &[u8]) {}
+
+#[cfg(not(all(feature = "mlock", target_os = "linux")))]
+fn unlock(_: &[u8]) {}
+
+#[cfg(test)]
+mod tests {
+    use super::{SecretBytes, zeroize};
+
+    #[test]
+    fn zeroizes_buffers() {
+        let mut buf = [0xa5u8; 64];
+        zeroize(&mut buf);
+
+        assert!(buf.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn takes_and_wipes_the_source() {
+        let mut source = [7u8; 32];
+        let secret = SecretBytes::take(&mut source);
+
+        assert_eq!(&secret[..], &[7u8; 32][..]);
+        assert_eq!(source, [0u8; 32]);
+    }
+
+    #[test]
+    fn moves_spare_capacity_into_an_exact_allocation() {
+        let mut bytes = Vec::with_capacity(64);
+        bytes.extend_from_slice(&[3u8; 16]);
+        let secret = SecretBytes::new(bytes);
+
+        assert_eq!(&secret[..], &[3u8; 16][..]);
+        assert_eq!(secret.bytes.capacity(), secret.bytes.len());
+    }
+
+    #[test]
+    fn copies_into_exact_allocations() {
+        // a buffer that is exactly full can't be reallocated behind our back,
+        // so the only copies are the ones the caller holds
+        let secret = SecretBytes::from_slice(&[1u8; 33]);
+        assert_eq!(secret.bytes.capacity(), secret.bytes.len());
+
+        let joined = SecretBytes::concat(&[1u8; 20], &[2u8; 12]);
+        assert_eq!(joined.bytes.capacity(), joined.bytes.len());
+        assert_eq!(&joined[..20], &[1u8; 20][..]);
+        assert_eq!(&joined[20..], &[2u8; 12][..]);
+
+        let cloned = joined.clone();
+        assert_eq!(cloned.bytes.capacity(), cloned.bytes.len());
+        assert!(cloned.bytes.as_ptr() != joined.bytes.as_ptr());
+    }
+
+    #[test]
+    fn redacts_debug_output() {
+        let secret = SecretBytes::from_slice(b"hunter2");
+
+        assert_eq!(format!("{:?}", secret), "SecretBytes([REDACTED; 7 bytes])");
+    }
+}
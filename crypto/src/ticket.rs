@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use ::ring::{aead, rand};
+use ::ring::rand::SecureRandom;
+
+use aead::{CipherSuite, EncryptionHandler};
+use encoding::{Writer, Reader};
+use errors::Error;
+use psk::PreSharedKey;
+use secret::{SecretBytes, zeroize};
+
+/// Length in bytes of the secret a ticket resumes from.
+pub const RESUMPTION_SECRET_LEN: usize = 32;
+
+const RESUMPTION_LABEL: &'static [u8] = b"libcart resumption";
+
+const TICKET_VERSION: u8 = 1;
+const KEY_ID_LEN: usize = 8;
+const TICKET_ID_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// The secret a session's ticket resumes from. Both peers derive the same
+/// one, and it reveals nothing about the session's traffic keys.
+pub fn resumption_secret(handler: &EncryptionHandler) -> SecretBytes {
+    handler.export(RESUMPTION_LABEL, &[], RESUMPTION_SECRET_LEN)
+}
+
+/// The key both peers use to prove they hold a ticket's secret during a
+/// resumed handshake, through the usual `PreSharedKey` binders.
+pub fn resumption_psk(secret: &[u8]) -> Result<PreSharedKey, Error> {
+    PreSharedKey::new("resumption", secret.to_vec())
+}
+
+/// What a server recovers from a ticket it issued.
+pub struct TicketContents {
+    pub secret: SecretBytes,
+    pub cipher_suite: CipherSuite,
+    /// The client's verified long-term key in the original session, if it
+    /// authenticated.
+    pub client_identity: Option<Vec<u8>>,
+    /// Seconds since the Unix epoch.
+    pub issued: u64,
+}
+
+/// Issues and redeems session tickets on a server.
+///
+/// A ticket is the session's resumption secret and client identity, sealed
+/// with ChaCha20-Poly1305 under a ticket key only the server knows, so the
+/// server keeps no per-session state until a ticket comes back. The ticket
+/// key is replaced every `lifetime`; the previous one is kept for one more
+/// period so every unexpired ticket can still be opened.
+///
+/// Each ticket can be redeemed once. Redeemed tickets are remembered until
+/// they expire. Ticket keys and redemptions live in memory only, so a
+/// restart invalidates every outstanding ticket, and servers behind a load
+/// balancer each honor only their own tickets.
+pub struct TicketIssuer {
+    lifetime: Duration,
+    state: Mutex<TicketState>,
+}
+
+struct TicketState {
+    current: TicketKey,
+    previous: Option<TicketKey>,
+    /// Redeemed ticket ids and when they expire.
+    redeemed: HashMap<Vec<u8>, u64>,
+}
+
+struct TicketKey {
+    id: [u8; KEY_ID_LEN],
+    key: SecretBytes,
+    created: Instant,
+}
+
+impl TicketKey {
+    fn generate() -> Result<TicketKey, Error> {
+        let rng = rand::SystemRandom::new();
+        let mut id = [0u8; KEY_ID_LEN];
+        rng.fill(&mut id).map_err(|_| Error::RandomFailure)?;
+        let mut key = SecretBytes::zeroed(aead::CHACHA20_POLY1305.key_len());
+        rng.fill(&mut key).map_err(|_| Error::RandomFailure)?;
+
+        Ok(TicketKey {
+            id: id,
+            key: key,
+            created: Instant::now(),
+        })
+    }
+}
+
+impl TicketIssuer {
+    pub fn new(lifetime: Duration) -> Result<TicketIssuer, Error> {
+        Ok(TicketIssuer {
+            lifetime: lifetime,
+            state: Mutex::new(TicketState {
+                current: TicketKey::generate()?,
+                previous: None,
+                redeemed: HashMap::new(),
+            }),
+        })
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Seals a ticket for the session that derived `secret`.
+    pub fn issue(&self, secret: &[u8], cipher_suite: CipherSuite, client_identity: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+        let rng = rand::SystemRandom::new();
+        let mut ticket_id = [0u8; TICKET_ID_LEN];
+        rng.fill(&mut ticket_id).map_err(|_| Error::RandomFailure)?;
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill(&mut nonce).map_err(|_| Error::RandomFailure)?;
+
+        let mut body = Writer::new();
+        body.put_raw(&ticket_id)
+            .put_u64(now())
+            .put_u8(cipher_suite.id())
+            .put_bytes(client_identity.unwrap_or(&[]))
+            .put_bytes(secret);
+        let mut sealed = body.into_vec();
+
+        let mut state = self.lock()?;
+        rotate(&mut state, self.lifetime)?;
+
+        let mut ticket = Vec::with_capacity(1 + KEY_ID_LEN + NONCE_LEN + sealed.len() + aead::CHACHA20_POLY1305.tag_len());
+        ticket.push(TICKET_VERSION);
+        ticket.extend_from_slice(&state.current.id);
+
+        let tag_len = aead::CHACHA20_POLY1305.tag_len();
+        let len = sealed.len();
+        sealed.resize(len + tag_len, 0);
+
+        let key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &state.current.key)?;
+        if let Err(err) = aead::seal_in_place(&key, &nonce, &ticket, &mut sealed, tag_len) {
+            zeroize(&mut sealed);
+            return Err(Error::from(err));
+        }
+
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(&sealed);
+        Ok(ticket)
+    }
+
+    /// Opens a ticket, refusing it if it is malformed, was sealed under a
+    /// key that has been retired, has expired or was already redeemed.
+    pub fn redeem(&self, ticket: &[u8]) -> Result<TicketContents, Error> {
+        if ticket.len() < 1 + KEY_ID_LEN + NONCE_LEN || ticket[0] != TICKET_VERSION {
+            return Err(Error::InvalidTicket("unsupported ticket format".to_string()));
+        }
+        let (header, rest) = ticket.split_at(1 + KEY_ID_LEN);
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        let key_id = &header[1..];
+
+        let mut state = self.lock()?;
+        rotate(&mut state, self.lifetime)?;
+
+        let mut body = sealed.to_vec();
+        let opened = {
+            let ticket_key = if &state.current.id[..] == key_id {
+                &state.current
+            } else {
+                match state.previous {
+                    Some(ref previous) if &previous.id[..] == key_id => previous,
+                    _ => return Err(Error::InvalidTicket("ticket key has been retired".to_string())),
+                }
+            };
+
+            let key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &ticket_key.key)?;
+            match aead::open_in_place(&key, nonce, header, 0, &mut body) {
+                Ok(plaintext) => Ok(plaintext.len()),
+                Err(_) => Err(Error::InvalidTicket("ticket failed to authenticate".to_string())),
+            }
+        };
+        let len = match opened {
+            Ok(len) => len,
+            Err(err) => {
+                zeroize(&mut body);
+                return Err(err);
+            },
+        };
+
+        let contents = decode_body(&body[..len]);
+        zeroize(&mut body);
+        let (ticket_id, contents) = contents?;
+
+        let now = now();
+        let expires = contents.issued.saturating_add(self.lifetime.as_secs());
+        if now >= expires {
+            return Err(Error::TicketExpired);
+        }
+
+        state.redeemed.retain(|_, expiry| *expiry > now);
+        if state.redeemed.contains_key(&ticket_id) {
+            return Err(Error::TicketReused);
+        }
+        state.redeemed.insert(ticket_id, expires);
+
+        Ok(contents)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<TicketState>, Error> {
+        match self.state.lock() {
+            Ok(state) => Ok(state),
+            Err(_) => Err(Error::LockPoisoned("ticket state")),
+        }
+    }
+}
+
+impl fmt::Debug for TicketIssuer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TicketIssuer {{ lifetime: {:?} }}", self.lifetime)
+    }
+}
+
+/// Replaces the current ticket key once it has been in use for `lifetime`.
+fn rotate(state: &mut TicketState, lifetime: Duration) -> Result<(), Error> {
+    if state.current.created.elapsed() >= lifetime {
+        debug!("rotating session ticket key");
+        let retired = mem::replace(&mut state.current, TicketKey::generate()?);
+        state.previous = Some(retired);
+    }
+
+    Ok(())
+}
+
+fn decode_body(body: &[u8]) -> Result<(Vec<u8>, TicketContents), Error> {
+    let mut rdr = Reader::new(body);
+    let ticket_id = rdr.get_raw(TICKET_ID_LEN)?.to_vec();
+    let issued = rdr.get_u64()?;
+    let cipher_suite = match CipherSuite::from_id(rdr.get_u8()?) {
+        Some(suite) => suite,
+        None => return Err(Error::InvalidTicket("unknown cipher suite".to_string())),
+    };
+    let client_identity = rdr.get_bytes()?;
+    let secret = SecretBytes::from_slice(rdr.get_bytes()?);
+    rdr.finish()?;
+
+    if secret.len() != RESUMPTION_SECRET_LEN {
+        return Err(Error::InvalidTicket("wrong resumption secret length".to_string()));
+    }
+
+    Ok((ticket_id, TicketContents {
+        secret: secret,
+        cipher_suite: cipher_suite,
+        client_identity: if client_identity.is_empty() { None } else { Some(client_identity.to_vec()) },
+        issued: issued,
+    }))
+}
+
+/// A ticket a client holds for one server, with the secret it resumes from.
+#[derive(Clone)]
+pub struct ClientTicket {
+    pub ticket: Vec<u8>,
+    pub secret: SecretBytes,
+    /// The long-term key of the server that issued the ticket.
+    pub server_identity: Vec<u8>,
+    /// Seconds since the Unix epoch.
+    pub expires: u64,
+}
+
+impl fmt::Debug for ClientTicket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ClientTicket {{ ticket: <{} bytes>, expires: {} }}", self.ticket.len(), self.expires)
+    }
+}
+
+/// The tickets a client has been given, one per server. Taking a ticket
+/// removes it, since servers only accept each ticket once; every resumed
+/// session is issued a fresh one.
+#[derive(Debug, Default)]
+pub struct TicketCache {
+    tickets: Mutex<HashMap<String, ClientTicket>>,
+}
+
+impl TicketCache {
+    pub fn new() -> TicketCache {
+        TicketCache {
+            tickets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Stores a ticket for `server`, replacing any older one. `lifetime` is
+    /// in seconds, as announced by the server.
+    pub fn insert(&self, server: &str, ticket: Vec<u8>, secret: SecretBytes, server_identity: Vec<u8>, lifetime: u64) {
+        let entry = ClientTicket {
+            ticket: ticket,
+            secret: secret,
+            server_identity: server_identity,
+            expires: now().saturating_add(lifetime),
+        };
+
+        if let Ok(mut tickets) = self.tickets.lock() {
+            tickets.insert(server.to_string(), entry);
+        }
+    }
+
+    /// Removes and returns the ticket for `server`, unless it has expired.
+    pub fn take(&self, server: &str) -> Option<ClientTicket> {
+        let ticket = match self.tickets.lock() {
+            Ok(mut tickets) => tickets.remove(server),
+            Err(_) => None,
+        };
+
+        ticket.and_then(|ticket| if now() < ticket.expires { Some(ticket) } else { None })
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use aead::CipherSuite;
+    use errors::Error;
+    use super::{TicketIssuer, RESUMPTION_SECRET_LEN};
+
+    const SECRET: [u8; RESUMPTION_SECRET_LEN] = [3; RESUMPTION_SECRET_LEN];
+
+    #[test]
+    fn redeems_once() {
+        let issuer = TicketIssuer::new(Duration::from_secs(60)).unwrap();
+        let ticket = issuer.issue(&SECRET, CipherSuite::ChaCha20Poly1305, Some(&b"client key"[..])).unwrap();
+
+        let contents = issuer.redeem(&ticket).unwrap();
+        assert_eq!(&contents.secret[..], &SECRET[..]);
+        assert_eq!(contents.cipher_suite, CipherSuite::ChaCha20Poly1305);
+        assert_eq!(contents.client_identity, Some(b"client key".to_vec()));
+
+        match issuer.redeem(&ticket) {
+            Err(Error::TicketReused) => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("redeemed a ticket twice"),
+        }
+    }
+
+    #[test]
+    fn refuses_expired_tickets() {
+        let issuer = TicketIssuer::new(Duration::from_secs(0)).unwrap();
+        let ticket = issuer.issue(&SECRET, CipherSuite::ChaCha20Poly1305, None).unwrap();
+
+        match issuer.redeem(&ticket) {
+            Err(Error::TicketExpired) => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("redeemed an expired ticket"),
+        }
+    }
+
+    #[test]
+    fn refuses_tampered_tickets() {
+        let issuer = TicketIssuer::new(Duration::from_secs(60)).unwrap();
+        let mut ticket = issuer.issue(&SECRET, CipherSuite::ChaCha20Poly1305, None).unwrap();
+        let last = ticket.len() - 1;
+        ticket[last] ^= 1;
+
+        match issuer.redeem(&ticket) {
+            Err(Error::InvalidTicket(_)) => {},
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("redeemed a tampered ticket"),
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use ::ring::digest;
+
+/// A running hash over every handshake field both peers agree on.
+///
+/// Each entry is framed as a length-prefixed label followed by length-prefixed
+/// data, so distinct sequences of fields can never produce the same input to
+/// the hash.
+#[derive(Clone)]
+pub struct Transcript {
+    ctx: digest::Context,
+}
+
+impl Transcript {
+    /// Starts a transcript bound to the given protocol identifier.
+    pub fn new(protocol: &[u8]) -> Transcript {
+        let mut transcript = Transcript {
+            ctx: digest::Context::new(&digest::SHA256),
+        };
+        transcript.append(b"protocol", protocol);
+
+        transcript
+    }
+
+    pub fn append(&mut self, label: &[u8], data: &[u8]) {
+        self.ctx.update(&length_prefix(label.len()));
+        self.ctx.update(label);
+        self.ctx.update(&length_prefix(data.len()));
+        self.ctx.update(data);
+    }
+
+    /// The hash of everything appended so far. The transcript can keep growing
+    /// afterwards.
+    pub fn hash(&self) -> Vec<u8> {
+        self.ctx.clone().finish().as_ref().to_vec()
+    }
+}
+
+fn length_prefix(len: usize) -> [u8; 4] {
+    let len = len as u32;
+    [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]
+}
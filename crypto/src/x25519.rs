@@ -0,0 +1,102 @@
+use std::fmt;
+
+use ::ring::rand::{self, SecureRandom};
+use ::x25519_dalek;
+
+use errors::Error;
+use secret::{SecretBytes, zeroize};
+
+/// Length in bytes of X25519 public keys, private keys and shared secrets.
+pub const KEY_LEN: usize = 32;
+
+/// The Curve25519 base point, u = 9.
+const BASEPOINT: [u8; KEY_LEN] = [
+    9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// An X25519 key pair that can take part in any number of key agreements.
+///
+/// ring only offers single-use ephemeral agreement keys, which rules out the
+/// long-term keys and repeated DH operations Noise handshakes need; this wraps
+/// x25519-dalek instead.
+///
+/// The private key is wiped when the key pair is dropped. Key pairs can't be
+/// cloned, so that wipe covers every copy; share one through an `Arc` instead.
+pub struct KeyPair {
+    private_key: [u8; KEY_LEN],
+    public_key: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    pub fn generate() -> Result<KeyPair, Error> {
+        let rng = rand::SystemRandom::new();
+        let mut private_key = [0u8; KEY_LEN];
+        rng.fill(&mut private_key).map_err(|_| Error::RandomFailure)?;
+        let pair = KeyPair::from_private_key_bytes(private_key);
+        zeroize(&mut private_key);
+
+        Ok(pair)
+    }
+
+    /// Rebuilds a key pair from a private key saved with `private_key_bytes`.
+    pub fn from_private_key(private_key: &[u8]) -> Result<KeyPair, Error> {
+        if private_key.len() != KEY_LEN {
+            return Err(Error::InvalidKey(format!("X25519 private key must be {} bytes", KEY_LEN)));
+        }
+
+        let mut bytes = [0u8; KEY_LEN];
+        bytes.copy_from_slice(private_key);
+        let pair = KeyPair::from_private_key_bytes(bytes);
+        zeroize(&mut bytes);
+
+        Ok(pair)
+    }
+
+    fn from_private_key_bytes(private_key: [u8; KEY_LEN]) -> KeyPair {
+        let public_key = x25519_dalek::diffie_hellman(&private_key, &BASEPOINT);
+
+        KeyPair {
+            private_key: private_key,
+            public_key: public_key,
+        }
+    }
+
+    pub fn public_key_bytes(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    pub fn private_key_bytes(&self) -> &[u8] {
+        &self.private_key
+    }
+
+    /// Computes the shared secret with `peer_public_key`. Low-order peer keys,
+    /// which would give an all-zero secret, are refused.
+    pub fn agree(&self, peer_public_key: &[u8]) -> Result<SecretBytes, Error> {
+        if peer_public_key.len() != KEY_LEN {
+            return Err(Error::InvalidKey(format!("X25519 public key must be {} bytes", KEY_LEN)));
+        }
+
+        let mut peer = [0u8; KEY_LEN];
+        peer.copy_from_slice(peer_public_key);
+        let mut shared = x25519_dalek::diffie_hellman(&self.private_key, &peer);
+
+        if shared.iter().all(|&byte| byte == 0) {
+            return Err(Error::InvalidKey("X25519 agreement with a low-order point".to_string()));
+        }
+
+        Ok(SecretBytes::take(&mut shared))
+    }
+}
+
+impl Drop for KeyPair {
+    fn drop(&mut self) {
+        zeroize(&mut self.private_key);
+    }
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyPair {{ public_key: {:?}, private_key: [REDACTED] }}", &self.public_key[..])
+    }
+}
@@ -1,9 +1,12 @@
 use std::io;
 use std::net;
 
-use proto::Proto;
-use message_types::MessageWrapper;
+use proto::{self, Proto, ServerTrust};
+use errors::Error;
+use message_types::{Message, MessageWrapper};
 
+use ::crypto::aead::CipherSuite;
+use ::crypto::known_hosts::{KnownHosts, HostKeyPolicy};
 use ::futures::Future;
 use ::tokio_proto::TcpClient;
 use ::tokio_proto::pipeline::ClientService;
@@ -28,8 +31,12 @@ impl<T> Service for RPC<T>
     type Error = io::Error;
     type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
 
+    /// A `Message::Error` reply fails the call with `Error::Remote`.
     fn call(&self, req: Self::Request) -> Self::Future {
-        Box::new(self.inner.call(req))
+        Box::new(self.inner.call(req).and_then(|resp| match resp.payload {
+            Message::Error(message) => Err(Error::Remote(message).into()),
+            _ => Ok(resp),
+        }))
     }
 }
 
@@ -61,7 +68,41 @@ impl Service for Client {
 
 impl Client {
     pub fn connect(addr: &net::SocketAddr, handle: &Handle, server_public_key: Vec<u8>) -> Box<Future<Item = Client, Error = io::Error>> {
-        let ret = TcpClient::new(Proto::new_client(server_public_key.clone()))
+        let proto = Proto::new_client(server_public_key, CipherSuite::all());
+        Client::connect_with(addr, handle, proto)
+    }
+
+    /// Connects without a pinned server key, checking the key the server
+    /// presents against its `known_hosts` entry, keyed by `addr`.
+    pub fn connect_known_hosts(addr: &net::SocketAddr, handle: &Handle, known_hosts: KnownHosts, policy: HostKeyPolicy) -> Box<Future<Item = Client, Error = io::Error>> {
+        let trust = ServerTrust::KnownHosts(known_hosts, addr.to_string(), policy);
+        let proto = Proto::new_client_with_trust(trust, CipherSuite::all());
+        Client::connect_with(addr, handle, proto)
+    }
+
+    /// Connects trusting any server whose key is certified for `hostname` by
+    /// `root_key`.
+    pub fn connect_certified(addr: &net::SocketAddr, handle: &Handle, root_key: Vec<u8>, hostname: &str) -> Box<Future<Item = Client, Error = io::Error>> {
+        let trust = ServerTrust::CertificateAuthority(root_key, hostname.to_string());
+        let proto = Proto::new_client_with_trust(trust, CipherSuite::all());
+        Client::connect_with(addr, handle, proto)
+    }
+
+    /// Pairs with the server at `addr` using the one-time code its operator
+    /// read off it, and resolves to the server's public key, ready for
+    /// `connect`. `client_key` is this client's long-term public key, if it
+    /// has one, for the server to authorize.
+    pub fn pair(addr: &net::SocketAddr, handle: &Handle, code: &str, client_key: Option<Vec<u8>>) -> Box<Future<Item = Vec<u8>, Error = io::Error>> {
+        let code = code.to_string();
+        let ret = TcpStream::connect(addr, handle)
+            .and_then(move |io| proto::pair(io, &code, client_key));
+
+        Box::new(ret)
+    }
+
+    /// Connects using a caller-configured client `Proto`.
+    pub fn connect_with(addr: &net::SocketAddr, handle: &Handle, proto: Proto) -> Box<Future<Item = Client, Error = io::Error>> {
+        let ret = TcpClient::new(proto)
             .connect(addr, handle)
             .map(|service| {
                 let s = RPC { inner: service };
@@ -71,3 +112,50 @@ impl Client {
         Box::new(ret)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::RPC;
+    use errors::Error;
+    use message_types::{Message, MessageWrapper};
+
+    use ::futures::{future, Future};
+    use ::tokio_service::Service;
+
+    /// Answers every request with the message the function builds.
+    struct Reply(fn() -> Message);
+
+    impl Service for Reply {
+        type Request = MessageWrapper;
+        type Response = MessageWrapper;
+        type Error = io::Error;
+        type Future = future::FutureResult<MessageWrapper, io::Error>;
+
+        fn call(&self, _: MessageWrapper) -> Self::Future {
+            future::ok(MessageWrapper::new((self.0)()))
+        }
+    }
+
+    #[test]
+    fn error_replies_fail_with_remote_errors() {
+        let rpc = RPC { inner: Reply(|| Message::Error("no such key".to_string())) };
+
+        let err = rpc.call(MessageWrapper::new(Message::Ping)).wait().unwrap_err();
+        match Error::downcast(&err) {
+            Some(&Error::Remote(ref message)) => assert_eq!(message, "no such key"),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other_replies_pass_through() {
+        let rpc = RPC { inner: Reply(|| Message::Pong) };
+
+        match rpc.call(MessageWrapper::new(Message::Ping)).wait() {
+            Ok(MessageWrapper { payload: Message::Pong, .. }) => {},
+            other => panic!("unexpected reply: {:?}", other),
+        }
+    }
+}
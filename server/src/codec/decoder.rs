@@ -3,8 +3,10 @@ use std::io;
 use super::frame_utils::*;
 use super::serde_cbor;
 use super::{Codec, BytesMut, MessageWrapper, MessageKind, BigEndian, ReadBytesExt};
+use errors::{Error, FramingError};
+use message_types::Message;
 
-use ::crypto::encode_base64;
+use ::crypto::redact;
 use ::crypto::aead::EncryptionHandler;
 use ::tokio_io::codec::Decoder;
 
@@ -13,98 +15,223 @@ impl Decoder for Codec {
     type Error = io::Error;
 
     fn decode(&mut self, mut buf: &mut BytesMut) -> Result<Option<MessageWrapper>, io::Error> {
-        let size = buf.len();
-        if size < minimum_frame_size() {
-            debug!("buf == 0 waiting for more data");
-            return Ok(None);
-        }
+        // control frames carry no message, so keep going until a frame that
+        // does or the end of the buffered data
+        loop {
+            let size = buf.len();
+            if size < minimum_frame_size() {
+                debug!("buf == 0 waiting for more data");
+                return Ok(None);
+            }
+
+            debug!("Got new frame: {}", redact::bytes(&buf));
+            debug!("Got new raw frame: {} bytes", size);
+
+            let total_size = peek_frame_size(&buf)?;
+            debug!("Got total size: {}", total_size);
 
-        debug!("Got new frame: {:?}", &buf);
-        debug!("Got new raw frame: {} bytes", size);
+            if total_size == 0 {
+                return Err(FramingError::InvalidFrame.into());
+            }
+
+            if buf.len() < header_size() + total_size {
+                debug!("{} < {} waiting for more data", buf.len(), header_size() + total_size);
+                return Ok(None);
+            }
 
-        let total_size = extract_frame_size(&mut buf)?;
-        debug!("Got total size: {}", total_size);
-        let message_size = total_size - 1;
+            // length prefix and kind byte, authenticated on encrypted frames
+            let header = buf[..header_size() + 1].to_vec();
+            extract_frame_size(&mut buf)?;
+            let message_size = total_size - 1;
 
-        if buf.len() >= total_size {
             let kind = extract_message_kind(&mut buf)?;
             match kind {
                 MessageKind::Normal => {
-                    if let Some(ref handler) = self.handler {
-                        decode_encrypted(handler, &mut buf, message_size, kind)
+                    return if let Some(ref mut handler) = self.handler {
+                        let peer_identity = self.peer_identity.clone();
+                        decode_encrypted(handler, &header, &mut buf, message_size, kind, peer_identity)
                     } else {
-                        Err(new_io_error("missing encryption handler"))
+                        Err(FramingError::MissingEncryption.into())
+                    };
+                },
+                MessageKind::Rekey => {
+                    // the rekey frame itself carries no message; move on to
+                    // whatever follows it under the new key
+                    match self.handler {
+                        Some(ref mut handler) => decode_rekey(handler, &header, &mut buf, message_size)?,
+                        None => return Err(FramingError::MissingEncryption.into()),
                     }
                 },
-                _ => decode_unencrypted(&mut buf, message_size, kind)
+                MessageKind::Ticket => {
+                    let ticket = match self.handler {
+                        Some(ref mut handler) => decode_encrypted(handler, &header, &mut buf, message_size, kind, None)?,
+                        None => return Err(FramingError::MissingEncryption.into()),
+                    };
+
+                    match ticket {
+                        Some(MessageWrapper { payload: Message::NewTicket(ticket), .. }) => {
+                            if let Some(ref sink) = self.ticket_sink {
+                                sink.store(ticket);
+                            }
+                        },
+                        _ => return Err(FramingError::InvalidFrame.into()),
+                    }
+                },
+                // once the session is encrypted, a plaintext frame can only
+                // have been injected
+                _ if self.handler.is_some() => return Err(FramingError::InvalidFrame.into()),
+                _ => return decode_unencrypted(&mut buf, message_size, kind),
             }
-        } else {
-            debug!("{} < {} waiting for more data", buf.len(), total_size);
-            Ok(None)
         }
     }
 }
 
-fn decode_encrypted(handler: &EncryptionHandler, mut buf: &mut BytesMut, _: usize, kind: MessageKind) -> Result<Option<MessageWrapper>, io::Error> {
+fn decode_encrypted(handler: &mut EncryptionHandler, header: &[u8], mut buf: &mut BytesMut, message_size: usize, kind: MessageKind, peer_identity: Option<Vec<u8>>) -> Result<Option<MessageWrapper>, io::Error> {
     debug!("decoding encrypted packet");
-    let header_size = header_size();
-
-    let mut rdr = io::Cursor::new(buf.split_to(header_size));
-    let nonce_size = rdr.read_u32::<BigEndian>()? as usize;
-    debug!("nonce size: {}", nonce_size);
-    let nonce = buf.split_to(nonce_size);
-    let nonce = nonce.to_vec();
-    debug!("nonce: {} {:?}", encode_base64(&nonce), &nonce);
-
-    if nonce.len() != nonce_size {
-        return Err(new_io_error("invalid packet"));
-    }
-
-    let payload_size = buf.len();
-    let payload = extract_raw_payload(&mut buf, payload_size)?;
-    debug!("crypted payload: {} {:?}", encode_base64(&payload), &payload);
 
-    if payload.len() != payload_size {
-        return Err(new_io_error("invalid packet"));
-    }
-
-    let payload = handler.open_data(nonce.clone(), payload);
-    let payload = match payload {
-        Ok(payload) => payload,
-        Err(_) => return Err(new_io_error("unable to decrypt data"))
-    };
-    debug!("decrypted payload: {:?}", &payload);
+    let payload = open_sealed(handler, header, &mut buf, message_size)?;
     let payload = serde_cbor::from_slice(&payload);
     let payload = match payload {
         Ok(payload) => payload,
-        Err(_) => return Err(new_io_error("unable to deserialize data"))
+        Err(err) => return Err(Error::EncodingError(err).into())
     };
     let wrapper: MessageWrapper = MessageWrapper {
         kind: kind,
         payload: payload,
+        peer_identity: peer_identity,
     };
-    debug!("decrypted wrapper: {:?}", wrapper);
+    debug!("decrypted wrapper: {}", redact::value("decrypted message", &wrapper));
 
     Ok(Some(wrapper))
 }
 
+fn decode_rekey(handler: &mut EncryptionHandler, header: &[u8], mut buf: &mut BytesMut, message_size: usize) -> Result<(), io::Error> {
+    debug!("decoding rekey packet");
+
+    let payload = open_sealed(handler, header, &mut buf, message_size)?;
+    if !payload.is_empty() {
+        return Err(FramingError::InvalidFrame.into());
+    }
+
+    match handler.rekey_opener() {
+        Ok(()) => Ok(()),
+        Err(err) => Err(Error::CryptoError(err).into()),
+    }
+}
+
+fn open_sealed(handler: &mut EncryptionHandler, header: &[u8], mut buf: &mut BytesMut, message_size: usize) -> Result<Vec<u8>, io::Error> {
+    let sequence_size = sequence_size();
+
+    if message_size < sequence_size {
+        return Err(FramingError::InvalidFrame.into());
+    }
+
+    let mut rdr = io::Cursor::new(buf.split_to(sequence_size));
+    let seq = rdr.read_u64::<BigEndian>()?;
+    debug!("sequence number: {}", seq);
+
+    let payload_size = message_size - sequence_size;
+    let payload = extract_raw_payload(&mut buf, payload_size)?;
+    debug!("crypted payload: {}", redact::bytes(&payload));
+
+    let payload = handler.open_data(header, seq, payload);
+    let payload = match payload {
+        Ok(payload) => payload,
+        Err(err) => return Err(Error::CryptoError(err).into()),
+    };
+    debug!("decrypted payload: {}", redact::bytes(&payload));
+
+    Ok(payload)
+}
+
 fn decode_unencrypted(mut buf: &mut BytesMut, payload_size: usize, kind: MessageKind) -> Result<Option<MessageWrapper>, io::Error> {
     debug!("decoding unencrypted packet");
 
     let payload = extract_raw_payload(&mut buf, payload_size)?;
     let payload = serde_cbor::from_slice(&payload);
-    debug!("deserialized message: {:?}", payload);
+    debug!("deserialized message: {}", redact::value("message", &payload));
     let payload = match payload {
         Ok(payload) => payload,
-        Err(_) => return Err(new_io_error("unable to deserialize data"))
+        Err(err) => return Err(Error::EncodingError(err).into())
     };
     let wrapper = MessageWrapper {
         kind: kind,
         payload: payload,
+        peer_identity: None,
     };
 
-    debug!("decoded wrapper: {:?}", wrapper);
+    debug!("decoded wrapper: {}", redact::value("message", &wrapper));
 
     Ok(Some(wrapper))
 }
 
+
+#[cfg(test)]
+mod tests {
+    use codec::Codec;
+    use errors::{Error, FramingError};
+    use message_types::{Message, MessageWrapper, MessageKind};
+
+    use ::bytes::BytesMut;
+    use ::crypto::aead::{CipherSuite, EncryptionHandler, Role, SessionSecrets};
+    use ::crypto::errors::Error as CryptoError;
+    use ::tokio_io::codec::{Decoder, Encoder};
+
+    fn session_codec(role: Role) -> Codec {
+        let secrets = SessionSecrets::derive(b"salt", b"shared secret", b"transcript");
+        let handler = EncryptionHandler::new(role, b"session".to_vec(), CipherSuite::ChaCha20Poly1305, secrets).unwrap();
+        Codec::new_handler(handler)
+    }
+
+    #[test]
+    fn decodes_encrypted_frames() {
+        let mut client = session_codec(Role::Client);
+        let mut server = session_codec(Role::Server);
+
+        let mut buf = BytesMut::new();
+        client.encode(MessageWrapper::new(Message::Ping), &mut buf).unwrap();
+
+        match server.decode(&mut buf).unwrap() {
+            Some(MessageWrapper { payload: Message::Ping, .. }) => {},
+            other => panic!("unexpected decode result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_plaintext_frames_after_handshake() {
+        let mut server = session_codec(Role::Server);
+
+        for kind in vec![MessageKind::HandshakeInit, MessageKind::HandshakeReply, MessageKind::HandshakeFinal] {
+            let injected = MessageWrapper {
+                kind: kind,
+                payload: Message::Ping,
+                peer_identity: None,
+            };
+            let mut buf = BytesMut::new();
+            Codec::new().encode(injected, &mut buf).unwrap();
+
+            let err = server.decode(&mut buf).unwrap_err();
+            match Error::downcast(&err) {
+                Some(&Error::Framing(FramingError::InvalidFrame)) => {},
+                other => panic!("unexpected error: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn reports_tampered_frames_as_decrypt_failures() {
+        let mut client = session_codec(Role::Client);
+        let mut server = session_codec(Role::Server);
+
+        let mut buf = BytesMut::new();
+        client.encode(MessageWrapper::new(Message::Ping), &mut buf).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+
+        let err = server.decode(&mut buf).unwrap_err();
+        match Error::downcast(&err) {
+            Some(&Error::CryptoError(CryptoError::DecryptFailed)) => {},
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+}
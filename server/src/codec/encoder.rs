@@ -1,11 +1,11 @@
 use std::io;
-use std::error::Error;
 
 use super::frame_utils::*;
 use super::serde_cbor;
 use super::{Codec, MessageWrapper, MessageKind, BytesMut, BigEndian, WriteBytesExt};
 
-use ::crypto::encode_base64;
+use errors::{Error, FramingError};
+use ::crypto::redact;
 use ::crypto::aead::EncryptionHandler;
 use ::tokio_io::codec::Encoder;
 
@@ -16,14 +16,14 @@ impl Encoder for Codec {
     type Error = io::Error;
 
     fn encode(&mut self, item: MessageWrapper, mut buf: &mut BytesMut) -> CodingResult {
-        debug!("new message to encode: {:?}", item);
+        debug!("new message to encode: kind {}, {}", item.kind, redact::value("message", &item));
 
         match item.kind {
-            MessageKind::Normal => {
-                if let Some(ref handler) = self.handler {
+            MessageKind::Normal | MessageKind::Ticket => {
+                if let Some(ref mut handler) = self.handler {
                     encode_encrypted(handler, item, &mut buf)
                 } else {
-                    Err(new_io_error("missing encryption handler"))
+                    Err(FramingError::MissingEncryption.into())
                 }
             },
             _ => encode_decrypted(item, &mut buf)
@@ -32,40 +32,53 @@ impl Encoder for Codec {
 }
 
 
-fn encode_encrypted(handler: &EncryptionHandler, item: MessageWrapper, mut buf: &mut BytesMut) -> CodingResult {
+fn encode_encrypted(handler: &mut EncryptionHandler, item: MessageWrapper, mut buf: &mut BytesMut) -> CodingResult {
     debug!("encoding encrypted");
 
+    if handler.needs_rekey() {
+        encode_rekey(handler, &mut buf)?;
+    }
+
     let msg = serialize(&item)?;
-    let res = handler.seal_data(&msg);
-    let (mut nonce, mut crypted) = match res {
-        Ok(res) => res,
-        Err(err) => return Err(new_io_error(err.description())),
-    };
-    let nonce_size = nonce.len();
-    let crypted_size = crypted.len();
-    debug!("nonce: {} {:?}", encode_base64(&nonce), &nonce);
-    debug!("crypted payload: {} {:?}", encode_base64(&crypted), &crypted);
+    encode_sealed(handler, item.kind, &msg, &mut buf)
+}
 
-    let mut vec: Vec<u8> = Vec::new();
-    vec.write_u8(item.kind as u8)?;
-    debug_assert!(vec.len() == 1, "vector length should be 1 after writing kind byte");
+fn encode_rekey(handler: &mut EncryptionHandler, mut buf: &mut BytesMut) -> CodingResult {
+    debug!("rekey limit reached, ratcheting outgoing key");
+
+    encode_sealed(handler, MessageKind::Rekey, &[], &mut buf)?;
+    match handler.rekey_sealer() {
+        Ok(()) => Ok(()),
+        Err(err) => Err(Error::CryptoError(err).into()),
+    }
+}
 
-    vec.write_u32::<BigEndian>(nonce_size as u32)?;
-    debug_assert!(vec.len() == 1 + header_size());
+fn encode_sealed(handler: &mut EncryptionHandler, kind: MessageKind, msg: &[u8], buf: &mut BytesMut) -> CodingResult {
+    // the header is authenticated by the seal, so its length field has to be
+    // known up front: kind byte + sequence number + ciphertext + tag
+    let total_size = 1 + sequence_size() + msg.len() + handler.tag_len();
 
-    vec.append(&mut nonce);
-    debug_assert!(vec.len() == 1 + header_size() + nonce_size);
+    let mut header: Vec<u8> = Vec::new();
+    header.write_u32::<BigEndian>(total_size as u32)?;
+    header.write_u8(kind as u8)?;
+    debug_assert!(header.len() == header_size() + 1);
 
-    vec.append(&mut crypted);
-    debug_assert!(vec.len() == 1 + header_size() + nonce_size + crypted_size);
+    let res = handler.seal_data(&header, msg);
+    let (seq, mut crypted) = match res {
+        Ok(res) => res,
+        Err(err) => return Err(Error::CryptoError(err).into()),
+    };
+    debug!("sequence number: {}", seq);
+    debug!("crypted payload: {}", redact::bytes(&crypted));
 
-    let mut sized: Vec<u8> = Vec::new();
-    debug!("size should be {:?}", vec.len());
-    sized.write_u32::<BigEndian>(vec.len() as u32)?;
-    sized.append(&mut vec);
+    let mut sized = header;
+    sized.write_u64::<BigEndian>(seq)?;
+    sized.append(&mut crypted);
+    debug_assert!(sized.len() == header_size() + total_size, "sized ({}) != header_size + total_size ({})",
+                  sized.len(), header_size() + total_size);
 
     debug!("encoded packet total length: {}", sized.len());
-    debug!("sending encoded packet: {:?}", sized);
+    debug!("sending encoded packet: {}", redact::bytes(&sized));
 
     buf.extend_from_slice(&sized);
 
@@ -93,7 +106,7 @@ fn encode_decrypted(item: MessageWrapper, mut buf: &mut BytesMut) -> CodingResul
                   sized.len(), total_length + 4);
 
     debug!("encoded packet total length: {}", sized.len());
-    debug!("sending encoded packet: {:?}", sized);
+    debug!("sending encoded packet: {}", redact::bytes(&sized));
 
     buf.extend_from_slice(&sized);
 
@@ -101,16 +114,13 @@ fn encode_decrypted(item: MessageWrapper, mut buf: &mut BytesMut) -> CodingResul
 }
 
 fn serialize(item: &MessageWrapper) -> Result<Vec<u8>, io::Error> {
-    debug!("serializing msg: {:?}", item);
+    debug!("serializing msg: {}", redact::value("message", item));
     let res = serde_cbor::to_vec(&item.payload);
     match res {
         Ok(msg) => {
-            debug!("serialized message: {:?}", msg);
+            debug!("serialized message: {}", redact::bytes(&msg));
             Ok(msg)
         },
-        Err(err) => {
-            let ioerr = new_io_error(err.description());
-            Err(ioerr)
-        }
+        Err(err) => Err(Error::EncodingError(err).into()),
     }
 }
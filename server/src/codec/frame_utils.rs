@@ -2,17 +2,31 @@ use std::io;
 use std::mem;
 
 use super::{BytesMut, BigEndian, MessageKind, ReadBytesExt};
+use errors::FramingError;
+use ::crypto::redact;
 
 #[inline]
 pub fn header_size() -> usize {
     mem::size_of::<u32>()
 }
 
+#[inline]
+pub fn sequence_size() -> usize {
+    mem::size_of::<u64>()
+}
+
 #[inline]
 pub fn minimum_frame_size() -> usize {
     header_size() + 1
 }
 
+pub fn peek_frame_size(buf: &BytesMut) -> io::Result<usize> {
+    let mut rdr = io::Cursor::new(&buf[..header_size()]);
+    let total_size = rdr.read_u32::<BigEndian>()? as usize;
+
+    Ok(total_size)
+}
+
 pub fn extract_frame_size(buf: &mut BytesMut) -> io::Result<usize> {
     let header_size = header_size();
     let mut rdr = io::Cursor::new(buf.split_to(header_size));
@@ -25,11 +39,12 @@ pub fn extract_message_kind(buf: &mut BytesMut) -> io::Result<MessageKind> {
     let buf_size = buf.len();
     let mut rdr = io::Cursor::new(buf.split_to(1));
     debug_assert!(buf.len() == buf_size - 1);
-    let message_kind = MessageKind::from(rdr.read_u8()?);
+    let kind_byte = rdr.read_u8()?;
+    let message_kind = MessageKind::from(kind_byte);
     debug!("Message kind: {}", message_kind);
 
     if let MessageKind::Unknown = message_kind {
-        Err(new_io_error("got unknown message kind byte"))
+        Err(FramingError::UnknownKind(kind_byte).into())
     } else {
         Ok(message_kind)
     }
@@ -39,13 +54,6 @@ pub fn extract_raw_payload(buf: &mut BytesMut, length: usize) -> io::Result<Vec<
     debug_assert!(buf.len() >= length, "{} >= {}", buf.len(), length);
     let payload = buf.split_to(length);
     let payload = payload.to_vec();
-    debug!("extracted payload bytes: {:?}", payload);
+    debug!("extracted payload bytes: {}", redact::bytes(&payload));
     Ok(payload)
 }
-
-pub fn new_io_error(msg: &str) -> io::Error {
-    io::Error::new(
-        io::ErrorKind::InvalidData,
-        msg
-    )
-}
@@ -1,5 +1,9 @@
-use message_types::{MessageWrapper, MessageKind};
+use std::sync::Arc;
+
+use message_types::{MessageWrapper, MessageKind, NewTicket};
 use crypto::aead::EncryptionHandler;
+use crypto::secret::SecretBytes;
+use crypto::ticket::TicketCache;
 
 use ::byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
 use ::serde_cbor;
@@ -11,28 +15,73 @@ mod frame_utils;
 
 pub struct Codec {
     pub handler: Option<EncryptionHandler>,
+    /// Verified long-term public key of the peer, attached to every decoded
+    /// encrypted message.
+    pub peer_identity: Option<Vec<u8>>,
+    /// Where a client keeps the session tickets the server sends.
+    pub ticket_sink: Option<TicketSink>,
+}
+
+/// Files session tickets a client receives in its `TicketCache`.
+pub struct TicketSink {
+    pub cache: Arc<TicketCache>,
+    pub server: String,
+    /// This session's `crypto::ticket::resumption_secret`.
+    pub secret: SecretBytes,
+    pub server_identity: Vec<u8>,
+}
+
+impl TicketSink {
+    fn store(&self, ticket: NewTicket) {
+        debug!("storing session ticket for {}", self.server);
+        self.cache.insert(&self.server, ticket.ticket, self.secret.clone(), self.server_identity.clone(), ticket.lifetime);
+    }
 }
 
 /*
 frame: [ u32 (total message size) + u8 (MessageKind) + <message> ]
 
 <message> for encrypted types:
-u32 (nonce size) + [u8] (nonce) + [u8] (cbor-serialized payload)
+u64 (sequence number) + [u8] (sealed cbor-serialized payload + tag)
+
+The sequence number doubles as the AEAD nonce, so no nonce is sent. The
+u32 size and MessageKind byte are authenticated as associated data, together
+with the session id and a direction label.
+
+MessageKind::Rekey frames are encrypted like any other, with an empty payload.
+They are sealed under the sender's current key and mark the boundary after
+which every frame in that direction uses the next ratcheted key. They are
+consumed by the codec and never surfaced as messages.
+
+MessageKind::Ticket frames are encrypted like Normal ones and carry a
+NewTicket. They are handed to the codec's TicketSink, if it has one, and
+never surfaced as messages either.
+
+If the handshake negotiated a ratchet interval, the handler also steps each
+direction's key after every that many frames, counting rekey frames. Both
+ends step at the same sequence numbers, so nothing extra is sent.
 
 <message> for unencrypted types:
 [u8] (cbor-serialized payload)
+
+Unencrypted frames are only accepted before the handshake installs a
+handler; after that the decoder refuses them.
  */
 
 impl Codec {
     pub fn new() -> Codec {
         Codec {
-            handler: None
+            handler: None,
+            peer_identity: None,
+            ticket_sink: None,
         }
     }
 
     pub fn new_handler(handler: EncryptionHandler) -> Codec {
         Codec {
-            handler: Some(handler)
+            handler: Some(handler),
+            peer_identity: None,
+            ticket_sink: None,
         }
     }
 
@@ -5,21 +5,160 @@ use std::error;
 use ::crypto;
 use ::serde_cbor;
 
+/// Everything that can go wrong on a connection.
+///
+/// tokio-proto only passes `io::Error`s through its transports and services,
+/// so these travel wrapped in one: `io::Error::from(err)` keeps the typed
+/// error as the inner error, with an `io::ErrorKind` that fits it. Use
+/// `Error::downcast` or `Error::from_io` to get it back out.
 #[derive(Debug)]
 pub enum Error {
     IOError(io::Error),
-    InvalidPacket,
     CryptoError(crypto::errors::Error),
     EncodingError(serde_cbor::Error),
+    Handshake(HandshakeError),
+    Framing(FramingError),
+    /// The peer answered with a `Message::Error`.
+    Remote(String),
+}
+
+/// Why a handshake or pairing exchange failed.
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// The peer speaks a different handshake version.
+    UnsupportedVersion(u8),
+    /// The peer sent something other than the next handshake message.
+    UnexpectedMessage,
+    /// A handshake field has the wrong size or makes no sense.
+    InvalidParameter(&'static str),
+    NoCommonCipherSuite,
+    /// The server selected a cipher suite the client didn't offer.
+    CipherSuiteNotOffered,
+    /// The server selected a looser ratchet interval than the client asked for.
+    RatchetDowngrade,
+    /// The peer's signature over the handshake transcript doesn't verify.
+    BadSignature,
+    /// The server's key isn't the one the client pinned or resumed with.
+    ServerKeyMismatch,
+    /// The server's key was refused by known hosts or its certificate.
+    ServerNotTrusted(crypto::errors::Error),
+    ClientAuthRequired,
+    ClientNotAuthorized,
+    PskRequired,
+    /// The pre-shared key or resumption ticket was refused.
+    Psk(crypto::errors::Error),
+    /// The peer's key has been revoked.
+    Revoked(crypto::errors::Error),
+    /// The pairing code was wrong, expired or used up.
+    Pairing(crypto::errors::Error),
+    /// The server closed the connection without accepting the pairing.
+    PairingRejected,
+    /// A pairing exchange finished; the connection is closed on purpose.
+    PairingFinished,
+    /// This side isn't configured for what the peer asked for.
+    Unsupported(&'static str),
+    /// This side's own settings don't fit together.
+    Misconfigured(&'static str),
+    /// Key agreement or session key setup failed.
+    KeyAgreement(crypto::errors::Error),
+}
+
+/// A frame that breaks the wire format.
+#[derive(Debug)]
+pub enum FramingError {
+    /// The frame's length or layout is wrong.
+    InvalidFrame,
+    UnknownKind(u8),
+    /// An encrypted frame arrived, or was sent, before the handshake finished.
+    MissingEncryption,
+}
+
+impl Error {
+    /// The typed error carried by `err`, if it has one.
+    pub fn downcast(err: &io::Error) -> Option<&Error> {
+        err.get_ref().and_then(|inner| inner.downcast_ref::<Error>())
+    }
+
+    /// Unwraps the typed error carried by `err`, or wraps `err` itself if it
+    /// is a plain I/O error, such as a refused connection.
+    pub fn from_io(err: io::Error) -> Error {
+        if Error::downcast(&err).is_none() {
+            return Error::IOError(err);
+        }
+
+        let kind = err.kind();
+        match err.into_inner().map(|inner| inner.downcast::<Error>()) {
+            Some(Ok(inner)) => *inner,
+            Some(Err(inner)) => Error::IOError(io::Error::new(kind, inner)),
+            None => Error::IOError(kind.into()),
+        }
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match *self {
+            Error::IOError(ref err) => err.kind(),
+            Error::CryptoError(_) => io::ErrorKind::InvalidData,
+            Error::EncodingError(_) => io::ErrorKind::InvalidData,
+            Error::Handshake(ref err) => err.kind(),
+            Error::Framing(_) => io::ErrorKind::InvalidData,
+            Error::Remote(_) => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl HandshakeError {
+    fn kind(&self) -> io::ErrorKind {
+        match *self {
+            HandshakeError::ServerKeyMismatch |
+            HandshakeError::ServerNotTrusted(_) |
+            HandshakeError::ClientAuthRequired |
+            HandshakeError::ClientNotAuthorized |
+            HandshakeError::PskRequired |
+            HandshakeError::Psk(_) |
+            HandshakeError::Revoked(_) |
+            HandshakeError::Pairing(_) |
+            HandshakeError::PairingRejected => io::ErrorKind::PermissionDenied,
+            HandshakeError::PairingFinished => io::ErrorKind::ConnectionAborted,
+            HandshakeError::Unsupported(_) |
+            HandshakeError::Misconfigured(_) |
+            HandshakeError::KeyAgreement(_) => io::ErrorKind::Other,
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::IOError(ref err) => write!(f, "IO Error: {}", err),
-            Error::InvalidPacket => write!(f, "InvalidPacket"),
-            Error::CryptoError(_) => write!(f, "Crypto Error"),
+            Error::CryptoError(ref err) => write!(f, "{}", err),
             Error::EncodingError(ref err) => write!(f, "Encoding Error: {}", err),
+            Error::Handshake(ref err) => write!(f, "Handshake Error: {}", err),
+            Error::Framing(ref err) => write!(f, "Framing Error: {}", err),
+            Error::Remote(ref err) => write!(f, "Remote Error: {}", err),
+        }
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandshakeError::UnsupportedVersion(version) => write!(f, "unsupported handshake version {}", version),
+            HandshakeError::ServerNotTrusted(ref err) |
+            HandshakeError::Psk(ref err) |
+            HandshakeError::Revoked(ref err) |
+            HandshakeError::Pairing(ref err) |
+            HandshakeError::KeyAgreement(ref err) => write!(f, "{}", err),
+            _ => write!(f, "{}", error::Error::description(self)),
+        }
+    }
+}
+
+impl fmt::Display for FramingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FramingError::UnknownKind(kind) => write!(f, "unknown message kind {}", kind),
+            _ => write!(f, "{}", error::Error::description(self)),
         }
     }
 }
@@ -28,38 +167,105 @@ impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
             Error::IOError(ref err) => err.description(),
-            Error::InvalidPacket => "invalid packet",
-            _ => "error"
+            Error::CryptoError(ref err) => err.description(),
+            Error::EncodingError(ref err) => err.description(),
+            Error::Handshake(ref err) => err.description(),
+            Error::Framing(ref err) => err.description(),
+            Error::Remote(ref err) => &err,
         }
     }
 
     fn cause(&self) -> Option<&error::Error> {
         match *self {
             Error::IOError(ref err) => Some(err),
-            Error::InvalidPacket => None,
-            Error::CryptoError(_) => None,
-            Error::EncodingError(ref err) => Some(err)
+            Error::CryptoError(ref err) => Some(err),
+            Error::EncodingError(ref err) => Some(err),
+            Error::Handshake(ref err) => Some(err),
+            Error::Framing(ref err) => Some(err),
+            Error::Remote(_) => None,
         }
     }
 }
 
+impl error::Error for HandshakeError {
+    fn description(&self) -> &str {
+        match *self {
+            HandshakeError::UnsupportedVersion(_) => "unsupported handshake version",
+            HandshakeError::UnexpectedMessage => "unexpected handshake message",
+            HandshakeError::InvalidParameter(message) => message,
+            HandshakeError::NoCommonCipherSuite => "no cipher suite in common with peer",
+            HandshakeError::CipherSuiteNotOffered => "server selected a cipher suite that was not offered",
+            HandshakeError::RatchetDowngrade => "server selected a looser ratchet interval than requested",
+            HandshakeError::BadSignature => "unable to verify handshake signature",
+            HandshakeError::ServerKeyMismatch => "server key does not match pinned key",
+            HandshakeError::ServerNotTrusted(ref err) => err.description(),
+            HandshakeError::ClientAuthRequired => "client authentication required",
+            HandshakeError::ClientNotAuthorized => "client key is not authorized",
+            HandshakeError::PskRequired => "pre-shared key required",
+            HandshakeError::Psk(ref err) => err.description(),
+            HandshakeError::Revoked(ref err) => err.description(),
+            HandshakeError::Pairing(ref err) => err.description(),
+            HandshakeError::PairingRejected => "server did not accept the pairing",
+            HandshakeError::PairingFinished => "pairing finished",
+            HandshakeError::Unsupported(message) => message,
+            HandshakeError::Misconfigured(message) => message,
+            HandshakeError::KeyAgreement(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            HandshakeError::ServerNotTrusted(ref err) |
+            HandshakeError::Psk(ref err) |
+            HandshakeError::Revoked(ref err) |
+            HandshakeError::Pairing(ref err) |
+            HandshakeError::KeyAgreement(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl error::Error for FramingError {
+    fn description(&self) -> &str {
+        match *self {
+            FramingError::InvalidFrame => "invalid frame",
+            FramingError::UnknownKind(_) => "unknown message kind",
+            FramingError::MissingEncryption => "missing encryption handler",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        None
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
-        Error::IOError(err)
+        Error::from_io(err)
     }
 }
 
-impl Into<io::Error> for Error {
-    fn into(self) -> io::Error {
-        match self {
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
             Error::IOError(err) => err,
-            Error::CryptoError(_) => io::ErrorKind::Other.into(),
-            Error::InvalidPacket => io::ErrorKind::InvalidData.into(),
-            Error::EncodingError(_) => io::ErrorKind::InvalidInput.into(),
+            err => io::Error::new(err.kind(), err),
         }
     }
 }
 
+impl From<HandshakeError> for io::Error {
+    fn from(err: HandshakeError) -> Self {
+        io::Error::from(Error::Handshake(err))
+    }
+}
+
+impl From<FramingError> for io::Error {
+    fn from(err: FramingError) -> Self {
+        io::Error::from(Error::Framing(err))
+    }
+}
+
 impl From<crypto::errors::Error> for Error {
     fn from(err: crypto::errors::Error) -> Self {
         Error::CryptoError(err)
@@ -72,3 +278,59 @@ impl From<serde_cbor::Error> for Error {
     }
 }
 
+impl From<HandshakeError> for Error {
+    fn from(err: HandshakeError) -> Self {
+        Error::Handshake(err)
+    }
+}
+
+impl From<FramingError> for Error {
+    fn from(err: FramingError) -> Self {
+        Error::Framing(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{Error, HandshakeError};
+
+    use ::crypto::errors::Error as CryptoError;
+
+    #[test]
+    fn handshake_errors_survive_io_errors() {
+        let err = io::Error::from(HandshakeError::BadSignature);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        match Error::downcast(&err) {
+            Some(&Error::Handshake(HandshakeError::BadSignature)) => {},
+            other => panic!("unexpected error: {:?}", other),
+        }
+        match Error::from_io(err) {
+            Error::Handshake(HandshakeError::BadSignature) => {},
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn crypto_errors_survive_io_errors() {
+        let err = io::Error::from(Error::CryptoError(CryptoError::DecryptFailed));
+
+        match Error::from(err) {
+            Error::CryptoError(CryptoError::DecryptFailed) => {},
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn plain_io_errors_stay_io_errors() {
+        let err = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        assert!(Error::downcast(&err).is_none());
+
+        match Error::from_io(err) {
+            Error::IOError(ref err) => assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+}
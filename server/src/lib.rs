@@ -23,16 +23,32 @@ mod codec;
 
 pub use client::Client;
 
+use std::io;
+
 use ::tokio_proto::TcpServer;
-use ::crypto::keys::load_or_create_key;
+use ::crypto::aead::CipherSuite;
+use ::crypto::key_store::KeyStore;
+
+/// Serves on `addr` with the identity key from `key_store`. Use
+/// `FileKeyStore::create_if_missing` to have a key generated on first run.
+pub fn start(addr: &str, key_store: &KeyStore) -> Result<(), errors::Error> {
+    if ::crypto::redact::LOGS_SECRETS {
+        warn!("built with insecure-log-secrets: session keys and plaintext will be logged");
+    }
 
-pub fn start(addr: &str) {
-    let addr = addr.parse().unwrap();
-    let server_key = load_or_create_key("server.key").unwrap();
-    let protocol = proto::Proto::new_server(server_key);
+    let addr = match addr.parse() {
+        Ok(addr) => addr,
+        Err(_) => return Err(errors::Error::IOError(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid address: {}", addr)))),
+    };
+
+    debug!("Loading server key from {}", key_store.describe());
+    let server_key = key_store.load()?;
+    let protocol = proto::Proto::new_server(server_key, CipherSuite::all());
 
     let server = TcpServer::new(protocol, addr);
     server.serve(move || Ok(service::RPC));
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -5,6 +5,10 @@ use std::u8::MAX as U8_MAX;
 pub struct MessageWrapper {
     pub kind: MessageKind,
     pub payload: Message,
+    /// The verified long-term public key of the peer that sent this message,
+    /// if it authenticated during the handshake: an Ed25519 key, or an X25519
+    /// static key after a Noise handshake. Never sent on the wire.
+    pub peer_identity: Option<Vec<u8>>,
 }
 
 impl MessageWrapper {
@@ -12,29 +16,35 @@ impl MessageWrapper {
         MessageWrapper {
             kind: MessageKind::Normal,
             payload: payload,
+            peer_identity: None,
         }
     }
 
     pub fn new_error(message: String) -> MessageWrapper {
-        MessageWrapper {
-            kind: MessageKind::Normal,
-            payload: Message::Error(message),
-        }
+        MessageWrapper::new(Message::Error(message))
     }
 }
 
 impl From<Message> for MessageWrapper {
     fn from(msg: Message) -> Self {
-        match msg {
-            Message::Handshake(key) => MessageWrapper {
-                kind: MessageKind::HandshakeInit,
-                payload: Message::Handshake(key),
-            },
-            Message::SignedHandshake(key, sig) => MessageWrapper {
-                kind: MessageKind::HandshakeReply,
-                payload: Message::SignedHandshake(key, sig),
-            },
-            _ => MessageWrapper::new(msg),
+        let kind = match msg {
+            Message::Handshake(_) => MessageKind::HandshakeInit,
+            Message::SignedHandshake(_) => MessageKind::HandshakeReply,
+            Message::NoiseHandshake(_) => MessageKind::HandshakeInit,
+            Message::NoiseReply(_) => MessageKind::HandshakeReply,
+            Message::NoiseFinal(_) => MessageKind::HandshakeFinal,
+            Message::NewTicket(_) => MessageKind::Ticket,
+            Message::PairingInit(_) => MessageKind::HandshakeInit,
+            Message::PairingReply(_) => MessageKind::HandshakeReply,
+            Message::PairingFinal(_) => MessageKind::HandshakeFinal,
+            Message::PairingComplete => MessageKind::HandshakeReply,
+            _ => MessageKind::Normal,
+        };
+
+        MessageWrapper {
+            kind: kind,
+            payload: msg,
+            peer_identity: None,
         }
     }
 }
@@ -44,8 +54,151 @@ pub enum Message {
     Ping,
     Pong,
     Error(String),
-    SignedHandshake(Vec<u8>, Vec<u8>),
-    Handshake(Vec<u8>),
+    SignedHandshake(HandshakeReply),
+    Handshake(HandshakeInit),
+    /// First message of a Noise handshake.
+    NoiseHandshake(NoiseInit),
+    /// The server's Noise handshake message.
+    NoiseReply(Vec<u8>),
+    /// The client's closing Noise handshake message, for patterns that need
+    /// one (`XX`).
+    NoiseFinal(Vec<u8>),
+    /// A session ticket, sent encrypted by the server right after the
+    /// handshake. Consumed by the codec.
+    NewTicket(NewTicket),
+    /// Opens a pairing exchange instead of a handshake.
+    PairingInit(PairingInit),
+    PairingReply(PairingReply),
+    PairingFinal(PairingFinal),
+    /// Sent by the server once it has accepted the client's key. The
+    /// connection closes after it.
+    PairingComplete,
+}
+
+/// First handshake message, sent by the client.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HandshakeInit {
+    /// Handshake protocol version the client speaks.
+    pub version: u8,
+    /// Client ephemeral X25519 public key.
+    pub public_key: Vec<u8>,
+    /// The client's share of the key schedule salt.
+    pub client_random: Vec<u8>,
+    /// Offered cipher suite ids, in the client's order of preference.
+    pub cipher_suites: Vec<u8>,
+    /// Frames per key ratchet step the client asks for, 0 for none.
+    pub ratchet_interval: u32,
+    /// Present when the client authenticates with a long-term key.
+    pub client_identity: Option<ClientIdentity>,
+    /// Present when the client wants a pre-shared key mixed into the session.
+    pub psk: Option<PskOffer>,
+    /// Present when the client wants to resume an earlier session.
+    pub ticket: Option<TicketOffer>,
+}
+
+/// A session ticket from an earlier connection, and proof that the client
+/// holds the secret it resumes from.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TicketOffer {
+    pub ticket: Vec<u8>,
+    /// Binder of `crypto::ticket::resumption_psk` over the transcript of the
+    /// `HandshakeInit`.
+    pub binder: Vec<u8>,
+}
+
+/// A ticket the client can present to resume this session later.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewTicket {
+    pub ticket: Vec<u8>,
+    /// Seconds the server will accept the ticket for.
+    pub lifetime: u64,
+}
+
+/// Names the pre-shared key a client is using and proves it holds it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PskOffer {
+    pub identity: String,
+    /// `PreSharedKey::binder` over the transcript of the `HandshakeInit`.
+    pub binder: Vec<u8>,
+}
+
+/// A client's long-term Ed25519 public key and its signature over the
+/// transcript of its `HandshakeInit`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientIdentity {
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// Server's reply to `HandshakeInit`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HandshakeReply {
+    /// Server long-term Ed25519 public key.
+    pub server_identity: Vec<u8>,
+    /// Encoded `crypto::certificate::Certificate` for `server_identity`, if
+    /// the server has one.
+    pub certificate: Option<Vec<u8>>,
+    /// Server ephemeral X25519 public key.
+    pub public_key: Vec<u8>,
+    /// The server's share of the key schedule salt.
+    pub server_random: Vec<u8>,
+    /// Server long-term key signature over the full handshake transcript.
+    /// Empty when the session was resumed.
+    pub signature: Vec<u8>,
+    /// Id of the selected cipher suite.
+    pub cipher_suite: u8,
+    /// Frames per key ratchet step, from `negotiate_ratchet_interval`.
+    pub ratchet_interval: u32,
+    /// `PreSharedKey::binder` over the full handshake transcript, present
+    /// when the client offered a pre-shared key.
+    pub psk_binder: Option<Vec<u8>>,
+    /// Whether the server accepted the client's ticket. If it did, the
+    /// server proves it with `ticket_binder` instead of a signature.
+    pub resumed: bool,
+    /// Binder of `crypto::ticket::resumption_psk` over the full handshake
+    /// transcript, present when the session was resumed.
+    pub ticket_binder: Option<Vec<u8>>,
+}
+
+/// Opens a Noise handshake. The pattern and cipher suite are also bound into
+/// the Noise protocol name, so tampering with them breaks the handshake.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NoiseInit {
+    /// Id of a `crypto::noise::NoisePattern`.
+    pub pattern: u8,
+    /// Id of the cipher suite the client chose.
+    pub cipher_suite: u8,
+    /// The first Noise handshake message.
+    pub message: Vec<u8>,
+}
+
+/// Opens a pairing exchange. See `crypto::pairing`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PairingInit {
+    pub version: u8,
+    /// The client's `PairingExchange` message.
+    pub message: Vec<u8>,
+}
+
+/// The server's side of a pairing exchange, and its long-term key.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PairingReply {
+    /// The server's `PairingExchange` message.
+    pub message: Vec<u8>,
+    /// Server long-term Ed25519 public key.
+    pub server_identity: Vec<u8>,
+    /// `PairingSession::confirmation` for `server_identity`.
+    pub confirmation: Vec<u8>,
+}
+
+/// The client's long-term key, if it has one, closing a pairing exchange.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PairingFinal {
+    /// Client long-term Ed25519 public key.
+    pub client_identity: Option<Vec<u8>>,
+    /// `PairingSession::confirmation` for `client_identity`, or for an empty
+    /// key if there is none.
+    pub confirmation: Vec<u8>,
 }
 
 impl From<MessageWrapper> for Message {
@@ -57,6 +210,9 @@ pub enum MessageKind {
     HandshakeInit,
     HandshakeReply,
     Normal,
+    Rekey,
+    HandshakeFinal,
+    Ticket,
     Unknown,
 }
 
@@ -66,6 +222,9 @@ impl From<u8> for MessageKind {
             0 => MessageKind::HandshakeInit,
             1 => MessageKind::HandshakeReply,
             2 => MessageKind::Normal,
+            3 => MessageKind::Rekey,
+            4 => MessageKind::HandshakeFinal,
+            5 => MessageKind::Ticket,
             _ => MessageKind::Unknown,
         }
     }
@@ -77,6 +236,9 @@ impl Display for MessageKind {
             MessageKind::HandshakeInit => 0,
             MessageKind::HandshakeReply => 1,
             MessageKind::Normal => 2,
+            MessageKind::Rekey => 3,
+            MessageKind::HandshakeFinal => 4,
+            MessageKind::Ticket => 5,
             _ => U8_MAX
         };
 
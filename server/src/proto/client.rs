@@ -1,12 +1,16 @@
 use std::io;
 
 
-use proto::Mode;
-use proto::Proto;
-use codec::Codec;
-use ::crypto::{aead, encode_base64, verify};
+use proto::{Mode, PROTOCOL_VERSION, CLIENT_AUTH_LABEL, SERVER_AUTH_LABEL};
+use proto::{client_transcript, append_server_reply, session_salt, auth_message, check_revocation, check_ratchet_interval, trust_server};
+use proto::{noise, Proto};
+use codec::{Codec, TicketSink};
+use errors::{Error, HandshakeError};
+use ::crypto::{aead, encode_base64, redact, verify};
+use ::crypto::aead::{CipherSuite, Role};
+use ::crypto::ticket::{resumption_psk, resumption_secret};
 
-use message_types::{MessageWrapper, Message, MessageKind};
+use message_types::{MessageWrapper, Message, MessageKind, HandshakeInit, HandshakeReply, ClientIdentity, PskOffer, TicketOffer};
 use ::tokio_io::{AsyncRead, AsyncWrite};
 use ::tokio_io::codec::{Framed};
 use ::tokio_proto::pipeline::ClientProto;
@@ -24,28 +28,88 @@ impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for Proto {
         debug!("Binding new protocol");
 
         if let Mode::Server = self.mode {
-            let err = io::Error::new(io::ErrorKind::Other,
-                                     "wrong mode for client proto");
-            return Box::new(future::err(err));
+            let err = HandshakeError::Unsupported("wrong mode for client proto");
+            return Box::new(future::err(err.into()));
         }
-        let signing_key = self.server_signing_key.clone().unwrap();
+        if let Some(&pattern) = self.noise_patterns.first() {
+            return noise::initiate(self, pattern, io);
+        }
+
+        let server_trust = self.server_trust.clone().unwrap();
+        let offered_suites: Vec<u8> = self.cipher_suites.iter().map(|suite| suite.id()).collect();
+        let rekey_policy = self.rekey_policy;
+        let ratchet_interval = self.ratchet_interval;
+        let revocation = self.revocation.clone();
+        let psk = self.psk.clone();
+        let ticket_cache = self.ticket_cache.clone();
+        let ticket = ticket_cache.as_ref().and_then(|&(ref cache, ref server)| cache.take(server));
+        let ticket_psk = match ticket {
+            Some(ref ticket) => match resumption_psk(&ticket.secret) {
+                Ok(psk) => Some(psk),
+                Err(err) => return Box::new(future::err(Error::CryptoError(err).into())),
+            },
+            None => None,
+        };
 
         let result = aead::new_ephemeral_key();
         let (private_key, public_key) = match result {
             Ok(keys) => keys,
-            Err(_) => {
-                let err = io::Error::new(io::ErrorKind::Other,
-                                         "unable to generate new ephemeral key pair");
-                return Box::new(future::err(err));
-            }
+            Err(err) => return Box::new(future::err(Error::CryptoError(err).into())),
         };
         debug!("Generated new public key: {:?}", encode_base64(&public_key));
+        let client_random = match aead::new_session_random() {
+            Ok(random) => random,
+            Err(err) => return Box::new(future::err(Error::CryptoError(err).into())),
+        };
+
+        let identity_key = self.client_private_key.as_ref().map(|key| key.public_key_bytes().to_vec());
+        let mut transcript = client_transcript(
+            PROTOCOL_VERSION,
+            &public_key,
+            &client_random,
+            &offered_suites,
+            ratchet_interval,
+            identity_key.as_ref().map(|key| &key[..]),
+            psk.as_ref().map(|psk| psk.identity()),
+            ticket.as_ref().map(|ticket| &ticket.ticket[..])
+        );
 
-        let req = MessageWrapper {
-            kind: MessageKind::HandshakeInit,
-            payload: Message::Handshake(public_key.clone()),
+        let client_identity = self.client_private_key.as_ref().map(|key| {
+            let signed = key.sign(&auth_message(CLIENT_AUTH_LABEL, &transcript.hash()));
+            ClientIdentity {
+                public_key: key.public_key_bytes().to_vec(),
+                signature: Vec::from(signed.as_ref()),
+            }
+        });
+
+        let psk_offer = psk.as_ref().map(|psk| {
+            PskOffer {
+                identity: psk.identity().to_string(),
+                binder: psk.binder(&transcript.hash()),
+            }
+        });
+
+        // the server may not accept the ticket, so everything a full
+        // handshake needs is sent as well
+        let ticket_offer = match (ticket.as_ref(), ticket_psk.as_ref()) {
+            (Some(ticket), Some(ticket_psk)) => Some(TicketOffer {
+                ticket: ticket.ticket.clone(),
+                binder: ticket_psk.binder(&transcript.hash()),
+            }),
+            _ => None,
         };
 
+        let req = MessageWrapper::from(Message::Handshake(HandshakeInit {
+            version: PROTOCOL_VERSION,
+            public_key: public_key,
+            client_random: client_random.clone(),
+            cipher_suites: offered_suites.clone(),
+            ratchet_interval: ratchet_interval,
+            client_identity: client_identity,
+            psk: psk_offer,
+            ticket: ticket_offer,
+        }));
+
         debug!("Sending handshake init");
         let transport = io.framed(Codec::new());
 
@@ -55,44 +119,105 @@ impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for Proto {
                 match msg {
                     Some(MessageWrapper {
                         kind: MessageKind::HandshakeReply,
-                        payload: Message::SignedHandshake(ref server_public_key, ref sig),
+                        payload: Message::SignedHandshake(HandshakeReply {
+                            server_identity: ref server_identity,
+                            ref certificate,
+                            public_key: ref server_public_key,
+                            ref server_random,
+                            signature: ref sig,
+                            cipher_suite: suite_id,
+                            ratchet_interval: selected_ratchet_interval,
+                            ref psk_binder,
+                            resumed,
+                            ref ticket_binder,
+                        }),
+                        ..
                     }) => {
-                        debug!("got handshake response: {:?}", msg);
+                        debug!("got handshake response: {}", redact::value("handshake", &msg));
+
+                        let suite = match CipherSuite::from_id(suite_id) {
+                            Some(ref suite) if offered_suites.contains(&suite.id()) => *suite,
+                            _ => return Err(HandshakeError::CipherSuiteNotOffered.into()),
+                        };
+                        debug!("server selected cipher suite: {:?}", suite);
+                        check_ratchet_interval(ratchet_interval, selected_ratchet_interval)?;
 
-                        if let Err(_) = verify(&signing_key, &server_public_key, &sig) {
-                            return Err(io::Error::new(
-                                io::ErrorKind::InvalidInput,
-                                "unable to verify server public key signing"
-                            ));
+                        if server_random.len() != aead::SESSION_RANDOM_LEN {
+                            return Err(HandshakeError::InvalidParameter("invalid server random").into());
                         }
 
+                        let certificate = certificate.as_ref().map(|cert| &cert[..]);
+                        append_server_reply(&mut transcript, server_identity, certificate, server_public_key, server_random, suite_id, selected_ratchet_interval, resumed);
+                        let transcript_hash = transcript.hash();
+
+                        let session_psk = if resumed {
+                            // the ticket binder proves the server opened the
+                            // ticket, which only the server it came from can
+                            let (ticket, ticket_psk) = match (ticket.as_ref(), ticket_psk) {
+                                (Some(ticket), Some(ticket_psk)) => (ticket, ticket_psk),
+                                _ => return Err(HandshakeError::InvalidParameter("server resumed a session without a ticket").into()),
+                            };
+                            let binder = ticket_binder.as_ref().map(|binder| &binder[..]).unwrap_or(&[]);
+                            if let Err(err) = ticket_psk.verify_binder(&transcript_hash, binder) {
+                                return Err(HandshakeError::Psk(err).into());
+                            }
+                            if &ticket.server_identity[..] != &server_identity[..] {
+                                return Err(HandshakeError::ServerKeyMismatch.into());
+                            }
+
+                            check_revocation(&revocation, server_identity)?;
+                            Some(ticket_psk)
+                        } else {
+                            let signed = auth_message(SERVER_AUTH_LABEL, &transcript_hash);
+                            if let Err(_) = verify(server_identity, &signed, &sig) {
+                                return Err(HandshakeError::BadSignature.into());
+                            }
+
+                            if let Some(ref psk) = psk {
+                                let binder = psk_binder.as_ref().map(|binder| &binder[..]).unwrap_or(&[]);
+                                if let Err(err) = psk.verify_binder(&transcript_hash, binder) {
+                                    return Err(HandshakeError::Psk(err).into());
+                                }
+                            }
+
+                            // only consult the trust store once the server has
+                            // proven it holds the key, so TOFU never records a
+                            // key an impostor merely copied
+                            check_revocation(&revocation, server_identity)?;
+                            trust_server(&server_trust, server_identity, certificate)?;
+                            psk.clone()
+                        };
+
                         let result = aead::EncryptionHandler::from_agreement(
-                            (private_key, public_key),
-                            server_public_key
+                            Role::Client,
+                            suite,
+                            private_key,
+                            server_public_key,
+                            &session_salt(&client_random, server_random),
+                            &transcript_hash,
+                            session_psk.as_ref().map(|psk| psk.key())
                         );
-                        let handler = match result {
+                        let mut handler = match result {
                             Ok(handler) => handler,
-                            Err(_) => {
-                                let err = io::Error::new(
-                                    io::ErrorKind::Other,
-                                    "unable to create encryption handler"
-                                );
-                                return Err(err);
-                            }
+                            Err(err) => return Err(HandshakeError::KeyAgreement(err).into()),
                         };
-                        let codec = Codec::new_handler(handler);
+                        handler.set_rekey_policy(rekey_policy);
+                        handler.set_ratchet_interval(selected_ratchet_interval);
+
+                        let ticket_sink = ticket_cache.as_ref().map(|&(ref cache, ref server)| TicketSink {
+                            cache: cache.clone(),
+                            server: server.clone(),
+                            secret: resumption_secret(&handler),
+                            server_identity: server_identity.clone(),
+                        });
+                        let mut codec = Codec::new_handler(handler);
+                        codec.ticket_sink = ticket_sink;
                         let parts = transport.into_parts();
                         let transport = Framed::from_parts(parts, codec);
 
                         Ok(transport)
                     },
-                    _ => {
-                        let err = io::Error::new(
-                            io::ErrorKind::Other,
-                            "invalid handshake response"
-                        );
-                        Err(err)
-                    }
+                    _ => Err(HandshakeError::UnexpectedMessage.into()),
                 }
             });
 
@@ -1,36 +1,396 @@
+use std::io;
 use std::vec::Vec;
+use std::sync::Arc;
 
 
 use ::ring::signature::Ed25519KeyPair;
+use ::crypto::aead::{CipherSuite, RekeyPolicy};
+use ::crypto::authorized_keys::AuthorizedKeys;
+use ::crypto::known_hosts::{KnownHosts, HostKeyPolicy};
+use ::crypto::certificate::{Certificate, KeyUsage};
+use ::crypto::errors::Error as CryptoError;
+use ::crypto::noise::NoisePattern;
+use ::crypto::pairing::PairingCode;
+use ::crypto::psk::{PreSharedKey, PskTable};
+use ::crypto::revocation::RevocationStore;
+use ::crypto::ticket::{TicketCache, TicketIssuer};
+use ::crypto::transcript::Transcript;
+use ::crypto::x25519::KeyPair;
+use errors::HandshakeError;
+use message_types::HandshakeInit;
 
 mod client;
 mod server;
+mod noise;
+mod pairing;
+
+pub use self::pairing::pair;
+
+/// Called with the long-term key of each client that pairs, or `None` if it
+/// sent none. An error fails the pairing and leaves the code usable.
+pub type PairedCallback = Arc<Fn(Option<&[u8]>) -> Result<(), CryptoError> + Send + Sync>;
 
 enum Mode {
     Client,
     Server
 }
 
+/// How a client decides whether to trust the long-term key a server presents.
+#[derive(Debug, Clone)]
+pub enum ServerTrust {
+    /// Accept only this Ed25519 public key.
+    Pinned(Vec<u8>),
+    /// Check the key against the known hosts entry for the given host name.
+    KnownHosts(KnownHosts, String, HostKeyPolicy),
+    /// Require a certificate for the given host name issued by this root key.
+    CertificateAuthority(Vec<u8>, String),
+}
+
 pub struct Proto {
     mode: Mode,
-    server_private_key: Option<Ed25519KeyPair>,
-    server_signing_key: Option<Vec<u8>>,
+    server_private_key: Option<Arc<Ed25519KeyPair>>,
+    server_trust: Option<ServerTrust>,
+    cipher_suites: Vec<CipherSuite>,
+    rekey_policy: RekeyPolicy,
+    ratchet_interval: u32,
+    client_private_key: Option<Ed25519KeyPair>,
+    authorized_keys: Option<Arc<AuthorizedKeys>>,
+    certificate: Option<Certificate>,
+    revocation: Option<Arc<RevocationStore>>,
+    noise_patterns: Vec<NoisePattern>,
+    static_key: Option<Arc<KeyPair>>,
+    psk: Option<PreSharedKey>,
+    psk_table: Option<Arc<PskTable>>,
+    ticket_issuer: Option<Arc<TicketIssuer>>,
+    ticket_cache: Option<(Arc<TicketCache>, String)>,
+    pairing_code: Option<Arc<PairingCode>>,
+    on_paired: Option<PairedCallback>,
+}
+
+const PROTOCOL_ID: &'static [u8] = b"libcart handshake";
+/// Bumped whenever the handshake messages or transcript change.
+const PROTOCOL_VERSION: u8 = 7;
+
+const CLIENT_AUTH_LABEL: &'static [u8] = b"libcart client auth";
+const SERVER_AUTH_LABEL: &'static [u8] = b"libcart server auth";
+
+/// Mixed into every Noise handshake so it can't be confused with another
+/// protocol using the same pattern. The version changes whenever the
+/// handshake payloads or the transport keying do.
+const NOISE_PROLOGUE: &'static [u8] = b"libcart noise v3";
+
+/// Starts the handshake transcript with everything the client sends.
+/// `client_identity` is the client's long-term public key, if it has one, and
+/// `psk_identity` names the pre-shared key it is using, if any, and `ticket`
+/// is the session ticket it offers to resume from.
+fn client_transcript(version: u8, public_key: &[u8], client_random: &[u8], cipher_suites: &[u8], ratchet_interval: u32, client_identity: Option<&[u8]>, psk_identity: Option<&str>, ticket: Option<&[u8]>) -> Transcript {
+    let mut transcript = Transcript::new(PROTOCOL_ID);
+    transcript.append(b"version", &[version]);
+    transcript.append(b"client ephemeral key", public_key);
+    transcript.append(b"client random", client_random);
+    transcript.append(b"offered cipher suites", cipher_suites);
+    transcript.append(b"offered ratchet interval", &be_u32(ratchet_interval));
+    transcript.append(b"client identity", client_identity.unwrap_or(&[]));
+    transcript.append(b"psk identity", psk_identity.unwrap_or("").as_bytes());
+    transcript.append(b"resumption ticket", ticket.unwrap_or(&[]));
+
+    transcript
+}
+
+fn init_transcript(init: &HandshakeInit) -> Transcript {
+    let identity = init.client_identity.as_ref().map(|identity| &identity.public_key[..]);
+    let psk_identity = init.psk.as_ref().map(|psk| &psk.identity[..]);
+    let ticket = init.ticket.as_ref().map(|offer| &offer.ticket[..]);
+    client_transcript(init.version, &init.public_key, &init.client_random, &init.cipher_suites, init.ratchet_interval, identity, psk_identity, ticket)
+}
+
+/// Adds the server's reply to a transcript started by `client_transcript`.
+fn append_server_reply(transcript: &mut Transcript, server_identity: &[u8], certificate: Option<&[u8]>, public_key: &[u8], server_random: &[u8], cipher_suite: u8, ratchet_interval: u32, resumed: bool) {
+    transcript.append(b"server identity", server_identity);
+    transcript.append(b"server certificate", certificate.unwrap_or(&[]));
+    transcript.append(b"server ephemeral key", public_key);
+    transcript.append(b"server random", server_random);
+    transcript.append(b"selected cipher suite", &[cipher_suite]);
+    transcript.append(b"selected ratchet interval", &be_u32(ratchet_interval));
+    transcript.append(b"resumed", &[resumed as u8]);
+}
+
+/// Checks the ratchet interval the server picked against the one the client
+/// asked for: it may be stricter, but never looser or off.
+fn check_ratchet_interval(requested: u32, selected: u32) -> Result<(), io::Error> {
+    if requested != 0 && (selected == 0 || selected > requested) {
+        return Err(HandshakeError::RatchetDowngrade.into());
+    }
+
+    Ok(())
+}
+
+fn be_u32(val: u32) -> [u8; 4] {
+    [(val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8, val as u8]
+}
+
+/// The key schedule salt: the client's random followed by the server's.
+fn session_salt(client_random: &[u8], server_random: &[u8]) -> Vec<u8> {
+    let mut salt = client_random.to_vec();
+    salt.extend_from_slice(server_random);
+    salt
+}
+
+/// The message signed by a long-term key to authenticate a transcript hash.
+/// The label keeps client and server signatures from being interchangeable.
+fn auth_message(label: &[u8], transcript_hash: &[u8]) -> Vec<u8> {
+    let mut msg = label.to_vec();
+    msg.extend_from_slice(transcript_hash);
+    msg
 }
 
 impl Proto {
-    pub fn new_server(key: Ed25519KeyPair) -> Proto {
+    /// `cipher_suites` is the server's order of preference; the first one the
+    /// client also offers is selected.
+    pub fn new_server(key: Ed25519KeyPair, cipher_suites: Vec<CipherSuite>) -> Proto {
         Proto {
             mode: Mode::Server,
-            server_private_key: Some(key),
-            server_signing_key: None,
+            server_private_key: Some(Arc::new(key)),
+            server_trust: None,
+            cipher_suites: cipher_suites,
+            rekey_policy: RekeyPolicy::default(),
+            ratchet_interval: 0,
+            client_private_key: None,
+            authorized_keys: None,
+            certificate: None,
+            revocation: None,
+            noise_patterns: Vec::new(),
+            static_key: None,
+            psk: None,
+            psk_table: None,
+            ticket_issuer: None,
+            ticket_cache: None,
+            pairing_code: None,
+            on_paired: None,
         }
     }
 
-    pub fn new_client(key: Vec<u8>) -> Proto {
+    /// `cipher_suites` is offered to the server in the given order.
+    pub fn new_client(key: Vec<u8>, cipher_suites: Vec<CipherSuite>) -> Proto {
+        Proto::new_client_with_trust(ServerTrust::Pinned(key), cipher_suites)
+    }
+
+    /// Like `new_client`, but with a choice of how the server key is trusted.
+    pub fn new_client_with_trust(trust: ServerTrust, cipher_suites: Vec<CipherSuite>) -> Proto {
         Proto {
             mode: Mode::Client,
             server_private_key: None,
-            server_signing_key: Some(key),
+            server_trust: Some(trust),
+            cipher_suites: cipher_suites,
+            rekey_policy: RekeyPolicy::default(),
+            ratchet_interval: 0,
+            client_private_key: None,
+            authorized_keys: None,
+            certificate: None,
+            revocation: None,
+            noise_patterns: Vec::new(),
+            static_key: None,
+            psk: None,
+            psk_table: None,
+            ticket_issuer: None,
+            ticket_cache: None,
+            pairing_code: None,
+            on_paired: None,
         }
     }
+
+    /// Sets how long this side uses each outgoing session key before rekeying.
+    /// The peer's limits only govern its own outgoing direction.
+    pub fn with_rekey_policy(mut self, policy: RekeyPolicy) -> Proto {
+        self.rekey_policy = policy;
+        self
+    }
+
+    /// Asks for each direction's key to be ratcheted forward, and the old
+    /// one erased, after every `frames` frames (1 for every frame). If both
+    /// peers ask, the smaller interval wins; 0, the default, asks for none.
+    pub fn with_ratchet(mut self, frames: u32) -> Proto {
+        self.ratchet_interval = frames;
+        self
+    }
+
+    /// Authenticates this client to the server with a long-term Ed25519 key.
+    pub fn with_client_key(mut self, key: Ed25519KeyPair) -> Proto {
+        self.client_private_key = Some(key);
+        self
+    }
+
+    /// Requires clients to authenticate with one of `keys`. Clients that don't
+    /// are rejected during the handshake.
+    pub fn with_authorized_keys(mut self, keys: AuthorizedKeys) -> Proto {
+        self.authorized_keys = Some(Arc::new(keys));
+        self
+    }
+
+    /// Presents `certificate` to clients so they can trust the server through
+    /// its issuing root instead of pinning the server key.
+    ///
+    /// The subject must be the server key or the Noise static key, and the
+    /// certificate is only presented in handshakes that authenticate that
+    /// key. Since either key may be set after this, the subject is checked
+    /// when a handshake starts: a certificate for neither key fails every
+    /// handshake with `HandshakeError::Misconfigured`.
+    pub fn with_certificate(mut self, certificate: Certificate) -> Proto {
+        self.certificate = Some(certificate);
+        self
+    }
+
+    /// Refuses peers whose long-term key appears on `revocation`. The store
+    /// can be shared between protocols and picks up changes to its file.
+    pub fn with_revocation_list(mut self, revocation: Arc<RevocationStore>) -> Proto {
+        self.revocation = Some(revocation);
+        self
+    }
+
+    /// Mixes `psk` into the session keys. The server must have the same key
+    /// under the same identity. Applies to the legacy handshake only.
+    pub fn with_psk(mut self, psk: PreSharedKey) -> Proto {
+        self.psk = Some(psk);
+        self
+    }
+
+    /// Requires clients to use one of the pre-shared keys in `table`. Clients
+    /// that offer none, or one the server doesn't hold, are rejected during
+    /// the handshake.
+    pub fn with_psk_table(mut self, table: PskTable) -> Proto {
+        self.psk_table = Some(Arc::new(table));
+        self
+    }
+
+    /// Sends every client a single-use ticket after the handshake, which it
+    /// can present to resume the session on its next connection. Resumed
+    /// handshakes skip signatures and certificate checks, and still run a
+    /// fresh key agreement. Tickets are only issued for the legacy handshake.
+    pub fn with_tickets(mut self, issuer: TicketIssuer) -> Proto {
+        self.ticket_issuer = Some(Arc::new(issuer));
+        self
+    }
+
+    /// Keeps the tickets this client is sent in `cache`, filed under
+    /// `server`, and offers them on later connections to the same `server`.
+    /// The cache can be shared between protocols.
+    pub fn with_resumption(mut self, cache: Arc<TicketCache>, server: &str) -> Proto {
+        self.ticket_cache = Some((cache, server.to_string()));
+        self
+    }
+
+    /// Lets clients that don't know the server key yet pair with `code` (see
+    /// `pair`). The server presents its key, and `on_paired` is handed the
+    /// client's so it can be added to the authorized keys.
+    pub fn with_pairing<F>(mut self, code: Arc<PairingCode>, on_paired: F) -> Proto
+        where F: Fn(Option<&[u8]>) -> Result<(), CryptoError> + Send + Sync + 'static
+    {
+        self.pairing_code = Some(code);
+        self.on_paired = Some(Arc::new(on_paired));
+        self
+    }
+
+    /// Uses a Noise handshake instead of the legacy one.
+    ///
+    /// A client runs the first pattern it was given. A server keeps accepting
+    /// the legacy handshake and also accepts every pattern it was given.
+    ///
+    /// With Noise, peers are identified by their X25519 static keys (see
+    /// `with_static_key`) rather than Ed25519 keys: pinned server keys,
+    /// authorized client keys, certificate subjects and revocation lists all
+    /// refer to static keys. `NK` and `IK` need the server's static key up
+    /// front, so clients using them must pin it with `ServerTrust::Pinned`.
+    pub fn with_noise(mut self, pattern: NoisePattern) -> Proto {
+        self.noise_patterns.push(pattern);
+        self
+    }
+
+    /// The long-term X25519 key this side presents in Noise handshakes.
+    /// Servers always need one; clients need one for `XX` and `IK`.
+    pub fn with_static_key(mut self, key: KeyPair) -> Proto {
+        self.static_key = Some(Arc::new(key));
+        self
+    }
+
+    /// Fails if the certificate, if there is one, is for neither of this
+    /// server's keys.
+    fn check_certificate(&self) -> Result<(), HandshakeError> {
+        let subject = match self.certificate {
+            Some(ref certificate) => &certificate.subject_key[..],
+            None => return Ok(()),
+        };
+        let server_key = self.server_private_key.as_ref().map_or(false, |key| key.public_key_bytes() == subject);
+        let static_key = self.static_key.as_ref().map_or(false, |key| key.public_key_bytes() == subject);
+
+        if server_key || static_key {
+            Ok(())
+        } else {
+            Err(HandshakeError::Misconfigured("certificate subject is not this server's key"))
+        }
+    }
+
+    /// The encoded certificate to present in a handshake that authenticates
+    /// `server_key`, if the certificate is for that key.
+    fn certificate_for(&self, server_key: &[u8]) -> Option<Vec<u8>> {
+        match self.certificate {
+            Some(ref certificate) if &certificate.subject_key[..] == server_key => Some(certificate.to_bytes()),
+            _ => None,
+        }
+    }
+}
+
+/// Fails the handshake if `public_key` has been revoked.
+fn check_revocation(revocation: &Option<Arc<RevocationStore>>, public_key: &[u8]) -> Result<(), io::Error> {
+    match *revocation {
+        Some(ref store) => match store.check(public_key) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                warn!("Rejecting revoked key: {}", err);
+                Err(HandshakeError::Revoked(err).into())
+            },
+        },
+        None => Ok(()),
+    }
+}
+
+/// Checks the server's verified long-term key against the client's trust
+/// settings.
+fn trust_server(trust: &ServerTrust, server_identity: &[u8], certificate: Option<&[u8]>) -> Result<(), io::Error> {
+    match *trust {
+        ServerTrust::Pinned(ref pinned) => {
+            if &pinned[..] == server_identity {
+                Ok(())
+            } else {
+                Err(HandshakeError::ServerKeyMismatch.into())
+            }
+        },
+        ServerTrust::KnownHosts(ref known_hosts, ref host, policy) => {
+            match known_hosts.verify(host, server_identity, policy) {
+                Ok(()) => Ok(()),
+                Err(err) => Err(HandshakeError::ServerNotTrusted(err).into()),
+            }
+        },
+        ServerTrust::CertificateAuthority(ref root_key, ref hostname) => {
+            let certificate = match certificate {
+                Some(certificate) => certificate,
+                None => return Err(HandshakeError::ServerNotTrusted(CryptoError::InvalidCertificate(
+                    "server did not present a certificate".to_string()
+                )).into()),
+            };
+
+            let result = Certificate::from_bytes(certificate).and_then(|cert| {
+                if &cert.subject_key[..] != server_identity {
+                    return Err(CryptoError::InvalidCertificate(
+                        "certificate is for a different key".to_string()
+                    ));
+                }
+                cert.verify(root_key, hostname, KeyUsage::ServerAuth)
+            });
+
+            match result {
+                Ok(()) => Ok(()),
+                Err(err) => Err(HandshakeError::ServerNotTrusted(err).into()),
+            }
+        },
+    }
 }
@@ -0,0 +1,307 @@
+use std::io;
+use std::sync::Arc;
+
+use proto::{Proto, ServerTrust, NOISE_PROLOGUE, check_ratchet_interval, check_revocation, trust_server};
+use codec::Codec;
+use errors::HandshakeError;
+use ::crypto::redact;
+use ::crypto::aead::{self, CipherSuite, RekeyPolicy, Role};
+use ::crypto::encoding::{Reader, Writer};
+use ::crypto::authorized_keys::AuthorizedKeys;
+use ::crypto::errors::Error as CryptoError;
+use ::crypto::noise::{self, HandshakeState, NoisePattern};
+use ::crypto::revocation::RevocationStore;
+use ::crypto::x25519::KeyPair;
+
+use message_types::{MessageWrapper, Message, MessageKind, NoiseInit};
+use ::tokio_io::{AsyncRead, AsyncWrite};
+use ::tokio_io::codec::{Framed};
+use ::futures::future;
+use ::futures::{Future, Stream, Sink};
+
+type BindTransport<T> = Box<Future<Item = Framed<T, Codec>, Error = io::Error>>;
+
+/// Runs the client side of a Noise handshake.
+///
+/// The client chooses the first of its cipher suites that Noise defines. The
+/// server's static key is checked against the client's trust settings as soon
+/// as it is known, before the client reveals anything further.
+///
+/// The client's first payload is the ratchet interval it asks for. The
+/// server's payload is the interval it selected followed by its certificate,
+/// if it has one.
+pub fn initiate<T: AsyncRead + AsyncWrite + 'static>(proto: &Proto, pattern: NoisePattern, io: T) -> BindTransport<T> {
+    if proto.psk.is_some() {
+        return error(HandshakeError::Unsupported("pre-shared keys are only supported by the legacy handshake"));
+    }
+
+    let server_trust = proto.server_trust.clone().unwrap();
+    let rekey_policy = proto.rekey_policy;
+    let ratchet_interval = proto.ratchet_interval;
+    let revocation = proto.revocation.clone();
+
+    let suite = match proto.cipher_suites.iter().find(|suite| noise::protocol_name(pattern, **suite).is_some()) {
+        Some(suite) => *suite,
+        None => return error(HandshakeError::Unsupported("no configured cipher suite can be used with Noise")),
+    };
+
+    let remote_static = if pattern.needs_remote_static() {
+        match server_trust {
+            ServerTrust::Pinned(ref key) => Some(key.clone()),
+            _ => return error(HandshakeError::Unsupported("Noise NK and IK need a pinned server static key")),
+        }
+    } else {
+        None
+    };
+    let static_key = proto.static_key.clone();
+
+    let mut state = match HandshakeState::initiator(pattern, suite, NOISE_PROLOGUE, static_key, remote_static) {
+        Ok(state) => state,
+        Err(err) => return Box::new(future::err(handshake_error(err))),
+    };
+    let mut payload = Writer::new();
+    payload.put_u32(ratchet_interval);
+    let message = match state.write_message(payload.as_slice()) {
+        Ok(message) => message,
+        Err(err) => return Box::new(future::err(handshake_error(err))),
+    };
+
+    let req = MessageWrapper::from(Message::NoiseHandshake(NoiseInit {
+        pattern: pattern.id(),
+        cipher_suite: suite.id(),
+        message: message,
+    }));
+
+    debug!("Sending Noise {} handshake", pattern.name());
+    let transport = io.framed(Codec::new());
+
+    let handshake = transport.send(req)
+        .and_then(|transport| transport.into_future().map_err(|(e, _)| e))
+        .and_then(move |(msg, transport)| -> BindTransport<T> {
+            let reply = match msg {
+                Some(MessageWrapper {
+                    kind: MessageKind::HandshakeReply,
+                    payload: Message::NoiseReply(reply),
+                    ..
+                }) => reply,
+                _ => return error(HandshakeError::UnexpectedMessage),
+            };
+
+            let payload = match state.read_message(&reply) {
+                Ok(payload) => payload,
+                Err(err) => return Box::new(future::err(handshake_error(err))),
+            };
+            let (selected_ratchet_interval, certificate) = match read_server_payload(&payload) {
+                Ok(fields) => fields,
+                Err(err) => return Box::new(future::err(handshake_error(err))),
+            };
+            if let Err(err) = check_ratchet_interval(ratchet_interval, selected_ratchet_interval) {
+                return Box::new(future::err(err));
+            }
+            let certificate = if certificate.is_empty() { None } else { Some(certificate) };
+
+            let server_static = match state.remote_static() {
+                Some(key) => key.to_vec(),
+                None => return error(HandshakeError::InvalidParameter("server did not send a static key")),
+            };
+            let trusted = check_revocation(&revocation, &server_static)
+                .and_then(|()| trust_server(&server_trust, &server_static, certificate));
+            if let Err(err) = trusted {
+                return Box::new(future::err(err));
+            }
+
+            if state.is_finished() {
+                return Box::new(future::result(with_handler(transport, state, Role::Client, rekey_policy, selected_ratchet_interval, None)));
+            }
+
+            let last = match state.write_message(&[]) {
+                Ok(last) => last,
+                Err(err) => return Box::new(future::err(handshake_error(err))),
+            };
+            let ret = transport.send(MessageWrapper::from(Message::NoiseFinal(last)))
+                .and_then(move |transport| with_handler(transport, state, Role::Client, rekey_policy, selected_ratchet_interval, None));
+
+            Box::new(ret)
+        });
+
+    Box::new(handshake)
+}
+
+/// The server settings a Noise handshake needs, taken from `Proto` so they
+/// can move into the handshake future.
+pub struct Responder {
+    static_key: Option<Arc<KeyPair>>,
+    patterns: Vec<NoisePattern>,
+    cipher_suites: Vec<CipherSuite>,
+    rekey_policy: RekeyPolicy,
+    ratchet_interval: u32,
+    authorized_keys: Option<Arc<AuthorizedKeys>>,
+    revocation: Option<Arc<RevocationStore>>,
+    certificate: Option<Vec<u8>>,
+    requires_psk: bool,
+}
+
+impl Responder {
+    pub fn new(proto: &Proto) -> Responder {
+        Responder {
+            static_key: proto.static_key.clone(),
+            patterns: proto.noise_patterns.clone(),
+            cipher_suites: proto.cipher_suites.clone(),
+            rekey_policy: proto.rekey_policy,
+            ratchet_interval: proto.ratchet_interval,
+            authorized_keys: proto.authorized_keys.clone(),
+            revocation: proto.revocation.clone(),
+            certificate: proto.static_key.as_ref().and_then(|key| proto.certificate_for(key.public_key_bytes())),
+            requires_psk: proto.psk_table.is_some(),
+        }
+    }
+
+    /// Runs the server side of a Noise handshake the client opened with
+    /// `init`.
+    pub fn respond<T: AsyncRead + AsyncWrite + 'static>(&self, init: &NoiseInit, transport: Framed<T, Codec>) -> BindTransport<T> {
+        // Noise handshakes can't carry a PSK, so a server that requires one
+        // only speaks the legacy handshake
+        if self.requires_psk {
+            return error(HandshakeError::PskRequired);
+        }
+
+        let pattern = match NoisePattern::from_id(init.pattern) {
+            Some(pattern) if self.patterns.contains(&pattern) => pattern,
+            _ => return error(HandshakeError::Unsupported("unsupported Noise pattern")),
+        };
+        let suite = match CipherSuite::from_id(init.cipher_suite) {
+            Some(suite) if self.cipher_suites.contains(&suite) => suite,
+            _ => return error(HandshakeError::NoCommonCipherSuite),
+        };
+        let static_key = match self.static_key {
+            Some(ref key) => key.clone(),
+            None => return error(HandshakeError::Unsupported("server has no Noise static key")),
+        };
+        debug!("responding to Noise {} handshake with {:?}", pattern.name(), suite);
+
+        let mut state = match HandshakeState::responder(pattern, suite, NOISE_PROLOGUE, static_key) {
+            Ok(state) => state,
+            Err(err) => return Box::new(future::err(handshake_error(err))),
+        };
+        let requested = match state.read_message(&init.message).and_then(|payload| read_client_payload(&payload)) {
+            Ok(requested) => requested,
+            Err(err) => return Box::new(future::err(handshake_error(err))),
+        };
+        let ratchet_interval = aead::negotiate_ratchet_interval(self.ratchet_interval, requested);
+
+        // IK and NK clients have said everything they will by now; XX
+        // clients send their static key after the reply
+        let client_identity = if pattern != NoisePattern::XX {
+            match authenticate_static(&state, &self.authorized_keys, &self.revocation) {
+                Ok(identity) => identity,
+                Err(err) => return Box::new(future::err(err)),
+            }
+        } else {
+            None
+        };
+
+        let mut payload = Writer::new();
+        payload.put_u32(ratchet_interval);
+        payload.put_bytes(self.certificate.as_ref().map(|cert| &cert[..]).unwrap_or(&[]));
+        let reply = match state.write_message(payload.as_slice()) {
+            Ok(reply) => reply,
+            Err(err) => return Box::new(future::err(handshake_error(err))),
+        };
+        let reply = MessageWrapper::from(Message::NoiseReply(reply));
+        let rekey_policy = self.rekey_policy;
+
+        if state.is_finished() {
+            let transport = match with_handler(transport, state, Role::Server, rekey_policy, ratchet_interval, client_identity) {
+                Ok(transport) => transport,
+                Err(err) => return Box::new(future::err(err)),
+            };
+            return Box::new(transport.send(reply));
+        }
+
+        let authorized_keys = self.authorized_keys.clone();
+        let revocation = self.revocation.clone();
+        let ret = transport.send(reply)
+            .and_then(|transport| transport.into_future().map_err(|(e, _)| e))
+            .and_then(move |(msg, transport)| -> Result<Framed<T, Codec>, io::Error> {
+                let last = match msg {
+                    Some(MessageWrapper {
+                        kind: MessageKind::HandshakeFinal,
+                        payload: Message::NoiseFinal(last),
+                        ..
+                    }) => last,
+                    _ => return Err(HandshakeError::UnexpectedMessage.into()),
+                };
+
+                state.read_message(&last).map_err(handshake_error)?;
+                let client_identity = authenticate_static(&state, &authorized_keys, &revocation)?;
+                with_handler(transport, state, Role::Server, rekey_policy, ratchet_interval, client_identity)
+            });
+
+        Box::new(ret)
+    }
+}
+
+/// Checks the client's static key, if the pattern made it send one, against
+/// the server's authorized and revoked keys.
+fn authenticate_static(state: &HandshakeState, authorized_keys: &Option<Arc<AuthorizedKeys>>, revocation: &Option<Arc<RevocationStore>>) -> Result<Option<Vec<u8>>, io::Error> {
+    let identity = match state.remote_static() {
+        Some(identity) => identity.to_vec(),
+        None if authorized_keys.is_some() => {
+            return Err(HandshakeError::ClientAuthRequired.into());
+        },
+        None => return Ok(None),
+    };
+
+    if let Some(ref keys) = *authorized_keys {
+        if !keys.contains(&identity) {
+            return Err(HandshakeError::ClientNotAuthorized.into());
+        }
+    }
+    check_revocation(revocation, &identity)?;
+
+    debug!("authenticated client static key: {}", redact::key(&identity));
+    Ok(Some(identity))
+}
+
+/// The ratchet interval the client asked for.
+fn read_client_payload(payload: &[u8]) -> Result<u32, CryptoError> {
+    let mut reader = Reader::new(payload);
+    let ratchet_interval = reader.get_u32()?;
+    reader.finish()?;
+
+    Ok(ratchet_interval)
+}
+
+/// The ratchet interval the server selected and its certificate, which is
+/// empty if it has none.
+fn read_server_payload(payload: &[u8]) -> Result<(u32, &[u8]), CryptoError> {
+    let mut reader = Reader::new(payload);
+    let ratchet_interval = reader.get_u32()?;
+    let certificate = reader.get_bytes()?;
+    reader.finish()?;
+
+    Ok((ratchet_interval, certificate))
+}
+
+/// Switches `transport` over to the session keys of a finished handshake.
+fn with_handler<T: AsyncRead + AsyncWrite>(transport: Framed<T, Codec>, state: HandshakeState, role: Role, rekey_policy: RekeyPolicy, ratchet_interval: u32, peer_identity: Option<Vec<u8>>) -> Result<Framed<T, Codec>, io::Error> {
+    let mut handler = state.into_handler(role).map_err(handshake_error)?;
+    handler.set_rekey_policy(rekey_policy);
+    handler.set_ratchet_interval(ratchet_interval);
+
+    let mut codec = Codec::new_handler(handler);
+    codec.peer_identity = peer_identity;
+    let parts = transport.into_parts();
+
+    Ok(Framed::from_parts(parts, codec))
+}
+
+fn handshake_error(err: CryptoError) -> io::Error {
+    warn!("Noise handshake failed: {}", err);
+    HandshakeError::KeyAgreement(err).into()
+}
+
+fn error<T: 'static>(err: HandshakeError) -> Box<Future<Item = T, Error = io::Error>> {
+    warn!("Invalid handshake: {}", err);
+    Box::new(future::err(err.into()))
+}
@@ -0,0 +1,170 @@
+use std::io;
+use std::sync::Arc;
+
+use proto::{Proto, PairedCallback, PROTOCOL_VERSION};
+use codec::Codec;
+use errors::HandshakeError;
+use ::crypto::redact;
+use ::crypto::errors::Error as CryptoError;
+use ::crypto::keys::PUBLIC_KEY_LEN;
+use ::crypto::pairing::{PairingCode, PairingExchange};
+
+use message_types::{MessageWrapper, Message, MessageKind, PairingInit, PairingReply, PairingFinal};
+use ::tokio_io::{AsyncRead, AsyncWrite};
+use ::tokio_io::codec::{Framed};
+use ::futures::future;
+use ::futures::{Future, Stream, Sink};
+
+type BindTransport<T> = Box<Future<Item = Framed<T, Codec>, Error = io::Error>>;
+
+/// Pairs with a server over `io` using the code its operator read off it,
+/// and returns the server's long-term Ed25519 public key for the caller to
+/// pin. `client_identity` is this client's long-term public key, which the
+/// server records if it is given.
+///
+/// Both keys are vouched for by the pairing exchange, so neither side has to
+/// know the other's in advance. The connection closes once the keys are
+/// exchanged; connect again with the pinned key.
+pub fn pair<T: AsyncRead + AsyncWrite + 'static>(io: T, code: &str, client_identity: Option<Vec<u8>>) -> Box<Future<Item = Vec<u8>, Error = io::Error>> {
+    let exchange = PairingExchange::client(code);
+    let req = MessageWrapper::from(Message::PairingInit(PairingInit {
+        version: PROTOCOL_VERSION,
+        message: exchange.message().to_vec(),
+    }));
+
+    debug!("Sending pairing init");
+    let transport = io.framed(Codec::new());
+
+    let ret = transport.send(req)
+        .and_then(|transport| transport.into_future().map_err(|(e, _)| e))
+        .and_then(move |(msg, transport)| {
+            let reply = match msg {
+                Some(MessageWrapper {
+                    kind: MessageKind::HandshakeReply,
+                    payload: Message::PairingReply(reply),
+                    ..
+                }) => reply,
+                _ => return Err(HandshakeError::UnexpectedMessage.into()),
+            };
+
+            let session = exchange.finish(&reply.message).map_err(pairing_error)?;
+            session.verify_peer(&reply.server_identity, &reply.confirmation).map_err(pairing_error)?;
+            if reply.server_identity.len() != PUBLIC_KEY_LEN {
+                return Err(HandshakeError::InvalidParameter("server sent an invalid public key").into());
+            }
+
+            let confirmation = session.confirmation(client_identity.as_ref().map(|key| &key[..]).unwrap_or(&[]));
+            let last = MessageWrapper::from(Message::PairingFinal(PairingFinal {
+                client_identity: client_identity,
+                confirmation: confirmation,
+            }));
+
+            Ok((transport, last, reply.server_identity))
+        })
+        .and_then(|(transport, last, server_identity)| {
+            transport.send(last)
+                .and_then(|transport| transport.into_future().map_err(|(e, _)| e))
+                .and_then(move |(msg, _)| match msg {
+                    Some(MessageWrapper { payload: Message::PairingComplete, .. }) => {
+                        debug!("paired with server key: {}", redact::key(&server_identity));
+                        Ok(server_identity)
+                    },
+                    _ => Err(HandshakeError::PairingRejected.into()),
+                })
+        });
+
+    Box::new(ret)
+}
+
+/// The server settings a pairing exchange needs, taken from `Proto` so they
+/// can move into the handshake future.
+pub struct PairingResponder {
+    code: Option<Arc<PairingCode>>,
+    on_paired: Option<PairedCallback>,
+    server_identity: Vec<u8>,
+}
+
+impl PairingResponder {
+    pub fn new(proto: &Proto) -> PairingResponder {
+        PairingResponder {
+            code: proto.pairing_code.clone(),
+            on_paired: proto.on_paired.clone(),
+            server_identity: proto.server_private_key.as_ref()
+                .map(|key| key.public_key_bytes().to_vec())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Runs the server side of a pairing exchange the client opened with
+    /// `init`. Once the client's key is recorded the connection is closed,
+    /// so this never yields a transport.
+    pub fn respond<T: AsyncRead + AsyncWrite + 'static>(&self, init: &PairingInit, transport: Framed<T, Codec>) -> BindTransport<T> {
+        if init.version != PROTOCOL_VERSION {
+            return error(HandshakeError::UnsupportedVersion(init.version));
+        }
+        let (code, on_paired) = match (self.code.clone(), self.on_paired.clone()) {
+            (Some(code), Some(on_paired)) => (code, on_paired),
+            _ => return error(HandshakeError::Unsupported("pairing is not enabled")),
+        };
+
+        let exchange = match code.start() {
+            Ok(exchange) => exchange,
+            Err(err) => return Box::new(future::err(pairing_error(err))),
+        };
+        let message = exchange.message().to_vec();
+        let session = match exchange.finish(&init.message) {
+            Ok(session) => session,
+            Err(err) => return Box::new(future::err(pairing_error(err))),
+        };
+
+        let reply = MessageWrapper::from(Message::PairingReply(PairingReply {
+            message: message,
+            server_identity: self.server_identity.clone(),
+            confirmation: session.confirmation(&self.server_identity),
+        }));
+
+        let ret = transport.send(reply)
+            .and_then(|transport| transport.into_future().map_err(|(e, _)| e))
+            .and_then(move |(msg, transport)| {
+                let last = match msg {
+                    Some(MessageWrapper {
+                        kind: MessageKind::HandshakeFinal,
+                        payload: Message::PairingFinal(last),
+                        ..
+                    }) => last,
+                    _ => return Err(HandshakeError::UnexpectedMessage.into()),
+                };
+
+                let client_identity = last.client_identity.as_ref().map(|key| &key[..]);
+                session.verify_peer(client_identity.unwrap_or(&[]), &last.confirmation).map_err(pairing_error)?;
+                if client_identity.map_or(false, |key| key.len() != PUBLIC_KEY_LEN) {
+                    return Err(HandshakeError::InvalidParameter("client sent an invalid public key").into());
+                }
+
+                on_paired(client_identity).map_err(pairing_error)?;
+                code.complete();
+                match client_identity {
+                    Some(key) => info!("Paired with client key: {}", redact::key(key)),
+                    None => info!("Paired with a client that sent no key"),
+                }
+
+                Ok(transport)
+            })
+            .and_then(|transport| transport.send(MessageWrapper::from(Message::PairingComplete)))
+            .and_then(|_| -> Result<Framed<T, Codec>, io::Error> {
+                Err(HandshakeError::PairingFinished.into())
+            });
+
+        Box::new(ret)
+    }
+}
+
+fn pairing_error(err: CryptoError) -> io::Error {
+    warn!("Pairing failed: {}", err);
+    HandshakeError::Pairing(err).into()
+}
+
+fn error<T: 'static>(err: HandshakeError) -> Box<Future<Item = T, Error = io::Error>> {
+    warn!("Invalid pairing exchange: {}", err);
+    Box::new(future::err(err.into()))
+}
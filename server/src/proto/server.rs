@@ -1,11 +1,21 @@
 use std::io;
+use std::sync::Arc;
 
-use proto::Mode;
+use proto::{Mode, PROTOCOL_VERSION, CLIENT_AUTH_LABEL, SERVER_AUTH_LABEL};
+use proto::{init_transcript, append_server_reply, session_salt, auth_message, check_revocation};
 use proto::Proto;
+use proto::noise::Responder;
+use proto::pairing::PairingResponder;
 use codec::Codec;
-use ::crypto::aead;
+use errors::{Error, HandshakeError};
+use ::crypto::{aead, redact, verify};
+use ::crypto::aead::{CipherSuite, Role};
+use ::crypto::authorized_keys::AuthorizedKeys;
+use ::crypto::errors::Error as CryptoError;
+use ::crypto::psk::{PreSharedKey, PskTable};
+use ::crypto::ticket::{TicketIssuer, resumption_psk, resumption_secret};
 
-use message_types::{MessageWrapper, Message, MessageKind};
+use message_types::{MessageWrapper, Message, MessageKind, HandshakeInit, HandshakeReply, NewTicket};
 use ::tokio_io::{AsyncRead, AsyncWrite};
 use ::tokio_io::codec::{Framed};
 use ::tokio_proto::pipeline::ServerProto;
@@ -23,71 +33,174 @@ impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for Proto {
         debug!("Binding new protocol");
 
         if let Mode::Client = self.mode {
-            let err = io::Error::new(io::ErrorKind::Other,
-                                     "wrong mode for server proto");
-            return Box::new(future::err(err));
+            let err = HandshakeError::Unsupported("wrong mode for server proto");
+            return Box::new(future::err(err.into()));
+        }
+        if let Err(err) = self.check_certificate() {
+            warn!("Unable to start handshake: {}", err);
+            return Box::new(future::err(err.into()));
         }
 
         let result = aead::new_ephemeral_key();
         let (private_key, public_key) = match result {
             Ok(keys) => keys,
-            Err(_) => {
-                let err = io::Error::new(io::ErrorKind::Other,
-                                         "unable to generate new ephemeral key pair");
-                return Box::new(future::err(err));
-            }
+            Err(err) => return Box::new(future::err(Error::CryptoError(err).into())),
         };
-
-
-        let sig: Option<Vec<u8>> = if let Some(ref server_key) = self.server_private_key {
-            let signed = server_key.sign(&public_key);
-            Some(Vec::from(signed.as_ref()))
-        } else {
-            None
+        let server_random = match aead::new_session_random() {
+            Ok(random) => random,
+            Err(err) => return Box::new(future::err(Error::CryptoError(err).into())),
         };
-        let sig = sig.unwrap();
-        debug!("signed server key: {:?}", &sig);
 
+        let server_key = self.server_private_key.clone().unwrap();
+        let cipher_suites = self.cipher_suites.clone();
+        let rekey_policy = self.rekey_policy;
+        let ratchet_interval = self.ratchet_interval;
+        let authorized_keys = self.authorized_keys.clone();
+        let certificate = self.certificate_for(server_key.public_key_bytes());
+        let revocation = self.revocation.clone();
+        let psk_table = self.psk_table.clone();
+        let ticket_issuer = self.ticket_issuer.clone();
+        let responder = Responder::new(self);
+        let pairing = PairingResponder::new(self);
         let transport = io.framed(Codec::new());
 
         let handshake = transport.into_future()
             .map_err(|(e, _)| e)
-            .and_then(|(msg, transport)| {
-                debug!("got new handshake attempt: {:?}", msg);
-
-                let error = |message| {
-                    warn!("Invalid handshake");
-                    let err = io::Error::new(io::ErrorKind::Other,
-                                             message);
-                    Box::new(future::err(err)) as Self::BindTransport
+            .and_then(move |(msg, transport)| {
+                debug!("got new handshake attempt: {}", redact::value("handshake", &msg));
+
+                let error = |err: HandshakeError| {
+                    warn!("Invalid handshake: {}", err);
+                    Box::new(future::err(err.into())) as Self::BindTransport
                 };
 
                 match msg {
                     Some(MessageWrapper {
                         kind: MessageKind::HandshakeInit,
-                        payload: Message::Handshake(ref peer_public_key),
+                        payload: Message::Handshake(ref init),
+                        ..
                     }) => {
-                        let response = MessageWrapper {
-                            kind: MessageKind::HandshakeReply,
-                            payload: Message::SignedHandshake(public_key.clone(), sig),
+                        if init.version != PROTOCOL_VERSION {
+                            return error(HandshakeError::UnsupportedVersion(init.version));
+                        }
+                        if init.client_random.len() != aead::SESSION_RANDOM_LEN {
+                            return error(HandshakeError::InvalidParameter("invalid client random"));
+                        }
+
+                        let suite = match CipherSuite::negotiate(&cipher_suites, &init.cipher_suites) {
+                            Some(suite) => suite,
+                            None => return error(HandshakeError::NoCommonCipherSuite)
                         };
+                        debug!("selected cipher suite: {:?}", suite);
+                        let ratchet_interval = aead::negotiate_ratchet_interval(ratchet_interval, init.ratchet_interval);
+
+                        let mut transcript = init_transcript(init);
+                        let resumption = match resume_session(init, &transcript.hash(), &ticket_issuer, suite, &authorized_keys) {
+                            Ok(resumption) => resumption,
+                            Err(err) => return Box::new(future::err(err)) as Self::BindTransport,
+                        };
+                        let resumed = resumption.is_some();
+
+                        // a resumed session is keyed with the ticket's secret,
+                        // which already carries the original session's PSK
+                        let (client_identity, psk) = match resumption {
+                            Some((identity, ticket_psk)) => (identity, Some(ticket_psk)),
+                            None => {
+                                let identity = match authenticate_client(init, &transcript.hash(), &authorized_keys) {
+                                    Ok(identity) => identity,
+                                    Err(err) => return error(err)
+                                };
+
+                                match check_psk(init, &transcript.hash(), &psk_table) {
+                                    Ok(psk) => (identity, psk),
+                                    Err(err) => return Box::new(future::err(err)) as Self::BindTransport,
+                                }
+                            },
+                        };
+
+                        if let Some(ref identity) = client_identity {
+                            if let Err(err) = check_revocation(&revocation, identity) {
+                                return Box::new(future::err(err)) as Self::BindTransport;
+                            }
+                        }
+
+                        let server_identity = server_key.public_key_bytes().to_vec();
+                        append_server_reply(
+                            &mut transcript,
+                            &server_identity,
+                            certificate.as_ref().map(|cert| &cert[..]),
+                            &public_key,
+                            &server_random,
+                            suite.id(),
+                            ratchet_interval,
+                            resumed
+                        );
+                        let transcript_hash = transcript.hash();
+                        let binder = psk.as_ref().map(|psk| psk.binder(&transcript_hash));
+
+                        let (signature, psk_binder, ticket_binder) = if resumed {
+                            (Vec::new(), None, binder)
+                        } else {
+                            let signed = server_key.sign(&auth_message(SERVER_AUTH_LABEL, &transcript_hash));
+                            debug!("signed handshake transcript: {}", redact::bytes(signed.as_ref()));
+                            (Vec::from(signed.as_ref()), binder, None)
+                        };
+
+                        let response = MessageWrapper::from(Message::SignedHandshake(HandshakeReply {
+                            server_identity: server_identity,
+                            certificate: certificate,
+                            public_key: public_key,
+                            server_random: server_random.clone(),
+                            signature: signature,
+                            cipher_suite: suite.id(),
+                            ratchet_interval: ratchet_interval,
+                            psk_binder: psk_binder,
+                            resumed: resumed,
+                            ticket_binder: ticket_binder,
+                        }));
 
                         let result = aead::EncryptionHandler::from_agreement(
-                            (private_key, public_key), peer_public_key
+                            Role::Server,
+                            suite,
+                            private_key,
+                            &init.public_key,
+                            &session_salt(&init.client_random, &server_random),
+                            &transcript_hash,
+                            psk.as_ref().map(|psk| psk.key())
                         );
-                        let handler = match result {
+                        let mut handler = match result {
                             Ok(handler) => handler,
-                            Err(_) => return error("unable to create encryption handler")
+                            Err(err) => return error(HandshakeError::KeyAgreement(err))
                         };
+                        handler.set_rekey_policy(rekey_policy);
+                        handler.set_ratchet_interval(ratchet_interval);
+
+                        let new_ticket = ticket_issuer.as_ref().and_then(|issuer| {
+                            issue_ticket(issuer, &handler, suite, client_identity.as_ref().map(|identity| &identity[..]))
+                        });
 
-                        let codec = Codec::new_handler(handler);
+                        let mut codec = Codec::new_handler(handler);
+                        codec.peer_identity = client_identity;
                         let parts = transport.into_parts();
                         let transport = Framed::from_parts(parts, codec);
 
                         let ret = transport.send(response);
-                        Box::new(ret) as Self::BindTransport
+                        match new_ticket {
+                            Some(ticket) => Box::new(ret.and_then(move |transport| transport.send(ticket))) as Self::BindTransport,
+                            None => Box::new(ret) as Self::BindTransport,
+                        }
                     },
-                    _ => error("invalid handshake")
+                    Some(MessageWrapper {
+                        kind: MessageKind::HandshakeInit,
+                        payload: Message::NoiseHandshake(ref init),
+                        ..
+                    }) => responder.respond(init, transport),
+                    Some(MessageWrapper {
+                        kind: MessageKind::HandshakeInit,
+                        payload: Message::PairingInit(ref init),
+                        ..
+                    }) => pairing.respond(init, transport),
+                    _ => error(HandshakeError::UnexpectedMessage)
                 }
             });
 
@@ -95,3 +208,109 @@ impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for Proto {
     }
 
 }
+
+/// Checks the client's signature over `transcript_hash`, if it sent one, and
+/// its key against `authorized_keys`. Returns the verified client public key.
+fn authenticate_client(init: &HandshakeInit, transcript_hash: &[u8], authorized_keys: &Option<Arc<AuthorizedKeys>>) -> Result<Option<Vec<u8>>, HandshakeError> {
+    let identity = match init.client_identity {
+        Some(ref identity) => identity,
+        None if authorized_keys.is_some() => return Err(HandshakeError::ClientAuthRequired),
+        None => return Ok(None),
+    };
+
+    let msg = auth_message(CLIENT_AUTH_LABEL, transcript_hash);
+    if let Err(_) = verify(&identity.public_key, &msg, &identity.signature) {
+        return Err(HandshakeError::BadSignature);
+    }
+
+    if let Some(ref keys) = *authorized_keys {
+        if !keys.contains(&identity.public_key) {
+            return Err(HandshakeError::ClientNotAuthorized);
+        }
+    }
+
+    debug!("authenticated client key: {}", redact::key(&identity.public_key));
+    Ok(Some(identity.public_key.clone()))
+}
+
+/// Finds the pre-shared key the client named and checks its binder over
+/// `transcript_hash`. A server with a PSK table refuses clients without one.
+fn check_psk(init: &HandshakeInit, transcript_hash: &[u8], psk_table: &Option<Arc<PskTable>>) -> Result<Option<PreSharedKey>, io::Error> {
+    let result = match (init.psk.as_ref(), psk_table.as_ref()) {
+        (Some(offer), Some(table)) => match table.get(&offer.identity) {
+            Some(psk) => psk.verify_binder(transcript_hash, &offer.binder).map(|()| Some(psk.clone())),
+            None => Err(CryptoError::UnknownPsk(offer.identity.clone())),
+        },
+        (Some(offer), None) => Err(CryptoError::UnknownPsk(offer.identity.clone())),
+        (None, Some(_)) => {
+            return Err(HandshakeError::PskRequired.into());
+        },
+        (None, None) => Ok(None),
+    };
+
+    result.map_err(|err| {
+        warn!("Rejecting client PSK: {}", err);
+        HandshakeError::Psk(err).into()
+    })
+}
+
+/// Redeems the ticket the client offered, if any, and checks its binder over
+/// `transcript_hash`. Returns the client identity the ticket was issued to and
+/// the key to resume with, or `None` for a full handshake: when the client
+/// offered no ticket, or one that is expired, already used, for another cipher
+/// suite or for a client that is no longer authorized.
+fn resume_session(init: &HandshakeInit, transcript_hash: &[u8], issuer: &Option<Arc<TicketIssuer>>, suite: CipherSuite, authorized_keys: &Option<Arc<AuthorizedKeys>>) -> Result<Option<(Option<Vec<u8>>, PreSharedKey)>, io::Error> {
+    let (offer, issuer) = match (init.ticket.as_ref(), issuer.as_ref()) {
+        (Some(offer), Some(issuer)) => (offer, issuer),
+        _ => return Ok(None),
+    };
+
+    let contents = match issuer.redeem(&offer.ticket) {
+        Ok(contents) => contents,
+        Err(err) => {
+            debug!("Not resuming session: {}", err);
+            return Ok(None);
+        },
+    };
+    if contents.cipher_suite != suite {
+        debug!("Not resuming session: ticket is for {:?}", contents.cipher_suite);
+        return Ok(None);
+    }
+    if let Some(ref keys) = *authorized_keys {
+        match contents.client_identity {
+            Some(ref identity) if keys.contains(identity) => {},
+            _ => {
+                debug!("Not resuming session: client key is no longer authorized");
+                return Ok(None);
+            },
+        }
+    }
+
+    let psk = match resumption_psk(&contents.secret) {
+        Ok(psk) => psk,
+        Err(err) => return Err(Error::CryptoError(err).into()),
+    };
+    if let Err(err) = psk.verify_binder(transcript_hash, &offer.binder) {
+        warn!("Rejecting session ticket: {}", err);
+        return Err(HandshakeError::Psk(err).into());
+    }
+
+    debug!("resuming session from ticket");
+    Ok(Some((contents.client_identity, psk)))
+}
+
+/// Seals a ticket for the session `handler` belongs to. Failing to issue one
+/// only costs the client a full handshake next time, so it isn't fatal.
+fn issue_ticket(issuer: &TicketIssuer, handler: &aead::EncryptionHandler, suite: CipherSuite, client_identity: Option<&[u8]>) -> Option<MessageWrapper> {
+    let secret = resumption_secret(handler);
+    match issuer.issue(&secret, suite, client_identity) {
+        Ok(ticket) => Some(MessageWrapper::from(Message::NewTicket(NewTicket {
+            ticket: ticket,
+            lifetime: issuer.lifetime().as_secs(),
+        }))),
+        Err(err) => {
+            warn!("Unable to issue session ticket: {}", err);
+            None
+        },
+    }
+}
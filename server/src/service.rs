@@ -1,4 +1,6 @@
-use message_types::Message;
+use message_types::{Message, MessageWrapper};
+
+use ::crypto::redact;
 
 use ::tokio_service::{Service, NewService};
 use ::futures::future;
@@ -9,28 +11,31 @@ use std::io;
 pub struct RPC;
 
 impl Service for RPC {
-    type Request = Message;
-    type Response = Message;
+    type Request = MessageWrapper;
+    type Response = MessageWrapper;
 
     type Error = io::Error;
     type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        debug!("Service called: {:?}", req);
+        debug!("Service called: {}", redact::value("request", &req));
+
+        if let Some(ref identity) = req.peer_identity {
+            debug!("Request from authenticated client: {}", redact::key(identity));
+        }
 
-        match req {
-            Message::Ping => future::finished(Message::Pong).boxed(),
-            _ => future::err(
-                io::Error::new(io::ErrorKind::InvalidInput,
-                               format!("unknown message type: {:?}", req))
+        match req.payload {
+            Message::Ping => future::finished(MessageWrapper::new(Message::Pong)).boxed(),
+            _ => future::finished(
+                MessageWrapper::new_error(format!("unknown message type: {:?}", req.payload))
             ).boxed()
         }
     }
 }
 
 impl NewService for RPC {
-    type Request = Message;
-    type Response = Message;
+    type Request = MessageWrapper;
+    type Response = MessageWrapper;
     type Error = io::Error;
     type Instance = RPC;
 
@@ -0,0 +1,366 @@
+//! Creates and inspects libcart keys.
+//!
+//! Run `cart-keygen help` for usage.
+
+extern crate base64;
+extern crate libcart;
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+use base64::encode;
+use libcart::crypto::errors::Error;
+use libcart::crypto::key_file::{KeyFile, KeyFormat, KeyKind};
+use libcart::crypto::keys::{fingerprint, generate_keypair_with, public_key_of, save_key_file, set_passphrase, signed_sealing_key};
+use libcart::crypto::passphrase::PassphraseSource;
+
+const USAGE: &'static str = "\
+usage: cart-keygen <command> [options] <args>
+
+commands:
+    generate [--comment TEXT] [--force] [--new-passphrase-env VAR | --new-passphrase-fd FD] NAME
+        Write a new server or client key pair to NAME.key and NAME.pub.
+        Existing files are only replaced with --force.
+    show [--passphrase-env VAR | --passphrase-fd FD] FILE
+        Print a key file's metadata, public key and fingerprint.
+    fingerprint [--passphrase-env VAR | --passphrase-fd FD] FILE
+        Print the fingerprint of a public key, or of a private key's public key.
+    pin [--format hex|base64|raw] [--passphrase-env VAR | --passphrase-fd FD] FILE
+        Print the public key bytes a client passes to `Client::connect`.
+    sealing-key [--format hex|base64|raw] [--passphrase-env VAR | --passphrase-fd FD] PRIVATE
+        Print the signed X25519 key senders seal boxes to this identity with.
+    convert --format current|pem|base64 IN OUT
+        Rewrite a key file in another format. Encrypted keys stay encrypted.
+    passphrase [--passphrase-env VAR | --passphrase-fd FD]
+               (--new-passphrase-env VAR | --new-passphrase-fd FD | --remove) FILE
+        Encrypt a private key, change its passphrase or decrypt it.
+    verify [--passphrase-env VAR | --passphrase-fd FD] PRIVATE PUBLIC
+        Check that a private key belongs to a public key.
+    help
+        Show this message.
+
+passphrase options:
+    --passphrase-env VAR        read the current passphrase from $VAR
+    --passphrase-fd FD          read the current passphrase from descriptor FD
+    --new-passphrase-env VAR    read the new passphrase from $VAR
+    --new-passphrase-fd FD      read the new passphrase from descriptor FD
+
+other options:
+    --allow-insecure-permissions
+                                load private keys other users can read
+    --force                     let generate replace existing key files
+";
+
+/// Parsed command line: the command, its `--name value` options and flags,
+/// and the remaining positional arguments.
+struct Args {
+    command: String,
+    options: Vec<(String, String)>,
+    flags: Vec<String>,
+    paths: Vec<String>,
+}
+
+impl Args {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+        let command = match args.next() {
+            Some(command) => command,
+            None => return Err("missing command".to_string()),
+        };
+
+        let mut parsed = Args {
+            command: command,
+            options: Vec::new(),
+            flags: Vec::new(),
+            paths: Vec::new(),
+        };
+
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                parsed.paths.push(arg);
+                continue;
+            }
+
+            let name = arg[2..].to_string();
+            match &name[..] {
+                "remove" | "help" | "allow-insecure-permissions" | "force" => parsed.flags.push(name),
+                "comment" | "format" | "passphrase-env" | "passphrase-fd" | "new-passphrase-env" | "new-passphrase-fd" => {
+                    match args.next() {
+                        Some(value) => parsed.options.push((name, value)),
+                        None => return Err(format!("--{} needs a value", name)),
+                    }
+                },
+                _ => return Err(format!("unknown option --{}", name)),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.iter().rev().find(|&&(ref n, _)| n == name).map(|&(_, ref value)| &value[..])
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|flag| flag == name)
+    }
+
+    /// Loads a key file, accepting exposed private keys only with
+    /// `--allow-insecure-permissions`.
+    fn load_key(&self, path: &str) -> Result<KeyFile, Error> {
+        KeyFile::load_with(path, self.flag("allow-insecure-permissions"))
+    }
+
+    /// The positional arguments, which must number exactly `count`.
+    fn paths(&self, count: usize) -> Result<&[String], String> {
+        if self.paths.len() != count {
+            return Err(format!("{} takes {} file argument(s), got {}", self.command, count, self.paths.len()));
+        }
+
+        Ok(&self.paths)
+    }
+
+    /// Where to read the passphrase of an existing key. Keys that turn out
+    /// not to be encrypted never ask for one.
+    fn passphrase(&self) -> Result<PassphraseSource, String> {
+        match self.passphrase_source("passphrase-env", "passphrase-fd")? {
+            Some(source) => Ok(source),
+            None => Ok(PassphraseSource::Callback(Box::new(|| {
+                Err(Error::PassphraseUnavailable("key is encrypted; use --passphrase-env or --passphrase-fd".to_string()))
+            }))),
+        }
+    }
+
+    fn new_passphrase(&self) -> Result<Option<PassphraseSource>, String> {
+        self.passphrase_source("new-passphrase-env", "new-passphrase-fd")
+    }
+
+    fn passphrase_source(&self, env_option: &str, fd_option: &str) -> Result<Option<PassphraseSource>, String> {
+        if let Some(name) = self.option(env_option) {
+            return Ok(Some(PassphraseSource::Env(name.to_string())));
+        }
+
+        match self.option(fd_option) {
+            Some(fd) => fd_source(fd).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn fd_source(fd: &str) -> Result<PassphraseSource, String> {
+    match fd.parse() {
+        Ok(fd) => Ok(PassphraseSource::Fd(fd)),
+        Err(_) => Err(format!("invalid file descriptor: {}", fd)),
+    }
+}
+
+#[cfg(not(unix))]
+fn fd_source(_: &str) -> Result<PassphraseSource, String> {
+    Err("reading passphrases from a file descriptor is only supported on Unix".to_string())
+}
+
+/// A command failed: either it was used wrongly, or the key operation did.
+enum Failure {
+    Usage(String),
+    Key(Error),
+}
+
+impl From<String> for Failure {
+    fn from(err: String) -> Failure {
+        Failure::Usage(err)
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(err: Error) -> Failure {
+        Failure::Key(err)
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Failure {
+        Failure::Key(Error::from(err))
+    }
+}
+
+fn main() {
+    let result = Args::parse(env::args().skip(1))
+        .map_err(Failure::Usage)
+        .and_then(|args| run(&args));
+
+    match result {
+        Ok(()) => {},
+        Err(Failure::Usage(err)) => {
+            let _ = writeln!(io::stderr(), "cart-keygen: {}\n\n{}", err, USAGE);
+            process::exit(2);
+        },
+        Err(Failure::Key(err)) => {
+            let _ = writeln!(io::stderr(), "cart-keygen: {}", err);
+            process::exit(1);
+        },
+    }
+}
+
+fn run(args: &Args) -> Result<(), Failure> {
+    if args.flag("help") {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+
+    match &args.command[..] {
+        "generate" => generate(args),
+        "show" => show(args),
+        "fingerprint" => print_fingerprint(args),
+        "pin" => pin(args),
+        "sealing-key" => sealing_key(args),
+        "convert" => convert(args),
+        "passphrase" => passphrase(args),
+        "verify" => verify(args),
+        "help" => {
+            print!("{}", USAGE);
+            Ok(())
+        },
+        command => Err(Failure::Usage(format!("unknown command: {}", command))),
+    }
+}
+
+fn generate(args: &Args) -> Result<(), Failure> {
+    let name = &args.paths(1)?[0];
+    let comment = args.option("comment").unwrap_or("");
+
+    let passphrase = match args.new_passphrase()? {
+        Some(source) => Some(source.read()?),
+        None => None,
+    };
+
+    // replacing a key pair destroys the identity it belongs to, so without
+    // --force the files are only created if nothing is there yet
+    let public_key = match generate_keypair_with(name, comment, passphrase.as_ref().map(|p| &p[..]), args.flag("force")) {
+        Ok(public_key) => public_key,
+        Err(Error::KeyWriteFailed(ref path, ref err)) if err.kind() == io::ErrorKind::AlreadyExists => {
+            let err = io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists; use --force to replace it", path));
+            return Err(Failure::from(err));
+        },
+        Err(err) => return Err(Failure::from(err)),
+    };
+
+    println!("wrote {}.key and {}.pub", name, name);
+    println!("{}", fingerprint(&public_key));
+    Ok(())
+}
+
+fn show(args: &Args) -> Result<(), Failure> {
+    let path = &args.paths(1)?[0];
+    let key = args.load_key(path)?;
+
+    println!("kind: {}", key.kind);
+    println!("algorithm: {:?}", key.algorithm);
+    if key.created != 0 {
+        println!("created: {}", key.created);
+    }
+    if !key.comment.is_empty() {
+        println!("comment: {}", key.comment);
+    }
+
+    let public_key = public_key_of(&key, &args.passphrase()?)?;
+    println!("public key: {}", encode(&public_key));
+    println!("fingerprint: {}", fingerprint(&public_key));
+    Ok(())
+}
+
+fn print_fingerprint(args: &Args) -> Result<(), Failure> {
+    let path = &args.paths(1)?[0];
+    let key = args.load_key(path)?;
+
+    println!("{}", fingerprint(&public_key_of(&key, &args.passphrase()?)?));
+    Ok(())
+}
+
+fn pin(args: &Args) -> Result<(), Failure> {
+    let path = &args.paths(1)?[0];
+    let key = args.load_key(path)?;
+    let public_key = public_key_of(&key, &args.passphrase()?)?;
+
+    print_key(args, &public_key)
+}
+
+fn sealing_key(args: &Args) -> Result<(), Failure> {
+    let path = &args.paths(1)?[0];
+    let key = args.load_key(path)?;
+    let signed = signed_sealing_key(&key, &args.passphrase()?)?;
+
+    print_key(args, &signed)
+}
+
+/// Prints raw key bytes in the encoding chosen with `--format`.
+fn print_key(args: &Args, key: &[u8]) -> Result<(), Failure> {
+    match args.option("format").unwrap_or("hex") {
+        "hex" => {
+            let hex: Vec<String> = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+            println!("{}", hex.concat());
+        },
+        "base64" => println!("{}", encode(key)),
+        "raw" => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stdout.write_all(key)?;
+            stdout.flush()?;
+        },
+        format => return Err(Failure::Usage(format!("unknown key format: {}", format))),
+    }
+
+    Ok(())
+}
+
+fn convert(args: &Args) -> Result<(), Failure> {
+    let paths = args.paths(2)?;
+    let format = match args.option("format") {
+        Some(name) => match KeyFormat::from_name(name) {
+            Some(format) => format,
+            None => return Err(Failure::Usage(format!("unknown key format: {}", name))),
+        },
+        None => return Err(Failure::Usage("convert needs --format".to_string())),
+    };
+
+    let key = args.load_key(&paths[0])?;
+    save_key_file(&key, &paths[1], format)?;
+
+    println!("wrote {} as {:?}", paths[1], format);
+    Ok(())
+}
+
+fn passphrase(args: &Args) -> Result<(), Failure> {
+    let path = &args.paths(1)?[0];
+
+    let new_passphrase = match (args.new_passphrase()?, args.flag("remove")) {
+        (Some(source), false) => Some(source.read()?),
+        (None, true) => None,
+        _ => return Err(Failure::Usage("passphrase needs exactly one of a new passphrase or --remove".to_string())),
+    };
+    set_passphrase(path, &args.passphrase()?, new_passphrase.as_ref().map(|p| &p[..]), args.flag("allow-insecure-permissions"))?;
+
+    if new_passphrase.is_some() {
+        println!("encrypted {}", path);
+    } else {
+        println!("decrypted {}", path);
+    }
+    Ok(())
+}
+
+fn verify(args: &Args) -> Result<(), Failure> {
+    let paths = args.paths(2)?;
+    let private = args.load_key(&paths[0])?;
+    if private.kind == KeyKind::PublicKey {
+        return Err(Failure::Key(Error::UnexpectedKeyKind(KeyKind::PrivateKey, KeyKind::PublicKey)));
+    }
+    let public = args.load_key(&paths[1])?;
+    public.expect_kind(KeyKind::PublicKey)?;
+
+    if public_key_of(&private, &args.passphrase()?)? == &public.data[..] {
+        println!("{} matches {}", paths[0], paths[1]);
+        Ok(())
+    } else {
+        Err(Failure::Key(Error::KeyMismatch(paths[0].clone(), paths[1].clone())))
+    }
+}